@@ -0,0 +1,28 @@
+//
+// Generates the grpc messages from proto/fekv.proto with rust-protobuf, see
+// src/grpc.rs. The generated file starts with inner attributes which can't be
+// include!d into a module, they're dropped and grpc.rs allows the lints on
+// the module instead.
+//
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    protobuf_codegen_pure::Codegen::new()
+        .out_dir(&out_dir)
+        .include("proto")
+        .input("proto/fekv.proto")
+        .run()
+        .expect("protobuf codegen failed");
+    let path = Path::new(&out_dir).join("fekv.rs");
+    let generated = fs::read_to_string(&path).unwrap();
+    let code: Vec<&str> = generated
+        .lines()
+        .filter(|line| !line.starts_with("#![") && !line.starts_with("//!"))
+        .collect();
+    fs::write(&path, code.join("\n")).unwrap();
+    println!("cargo:rerun-if-changed=proto/fekv.proto");
+}
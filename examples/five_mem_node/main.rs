@@ -11,12 +11,13 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{str, thread};
 
-
 //use raft::storage::MemStorage;
-use fekv::raftstore::RaftDiskStorage;
-use raft::{Config, RawNode, StateRole};
-use raft::eraftpb::{ConfChange, ConfChangeType, Entry, EntryType, Message as RaftMessage, MessageType, Snapshot};
-use protobuf::Message; // as PbMessage;
+use fekv::raftstore::{promote_learner, RaftDiskStorage};
+use protobuf::Message;
+use raft::eraftpb::{
+    ConfChange, ConfChangeType, Entry, EntryType, Message as RaftMessage, MessageType, Snapshot,
+};
+use raft::{Config, RawNode, StateRole}; // as PbMessage;
 
 use regex::Regex;
 
@@ -32,8 +33,9 @@ fn main() {
         .fuse();
     let logger = slog::Logger::root(drain, o!());
 
-    const NUM_NODES: u32 = 5;
-    // Create 5 mailboxes to send/receive messages. Every node holds a `Receiver` to receive
+    // 5 voters plus node 6 which joins as a learner and is then promoted
+    const NUM_NODES: u32 = 6;
+    // Create 6 mailboxes to send/receive messages. Every node holds a `Receiver` to receive
     // messages from others, and uses the respective `Sender` to send messages to others.
     let (mut tx_vec, mut rx_vec) = (Vec::new(), Vec::new());
     for _ in 0..NUM_NODES {
@@ -51,8 +53,8 @@ fn main() {
 
     let mut handles = Vec::new();
     for (i, rx) in rx_vec.into_iter().enumerate() {
-        // A map[peer_id -> sender]. In the example we create 5 nodes, with ids in [1, 5].
-        let mailboxes = (1..6u64).zip(tx_vec.iter().cloned()).collect();
+        // A map[peer_id -> sender]. In the example we create 6 nodes, with ids in [1, 6].
+        let mailboxes = (1..=NUM_NODES as u64).zip(tx_vec.iter().cloned()).collect();
         let mut node = match i {
             // Peer 1 is the leader.
             0 => Node::create_raft_leader(1, rx, mailboxes, &logger),
@@ -120,6 +122,10 @@ fn main() {
     // Propose some conf changes so that followers can be initialized.
     add_all_followers(proposals.as_ref());
 
+    // Add node 6 as a non-voting learner, then promote it once it has caught up.
+    add_learner_and_promote(6, proposals.as_ref());
+    info!(logger, "Learner 6 caught up and was promoted to a voter");
+
     // Put 100 key-value pairs.
     info!(
         logger,
@@ -282,6 +288,7 @@ fn on_ready(
         }
     }
 
+    let reg = Regex::new("put ([0-9]+) (.+)").unwrap();
     let mut handle_committed_entries =
         |rn: &mut RawNode<RaftDiskStorage>, committed_entries: Vec<Entry>| {
             for entry in committed_entries {
@@ -294,12 +301,11 @@ fn on_ready(
                     let mut cc = ConfChange::default();
                     cc.merge_from_bytes(&entry.data).unwrap();
                     let cs = rn.apply_conf_change(&cc).unwrap();
-                    store.wl().set_conf_state(cs);
+                    store.wl().set_conf_state(cs).unwrap();
                 } else {
                     // For normal proposals, extract the key-value pair and then
                     // insert them into the kv engine.
                     let data = str::from_utf8(&entry.data).unwrap();
-                    let reg = Regex::new("put ([0-9]+) (.+)").unwrap();
                     if let Some(caps) = reg.captures(data) {
                         kv_pairs.insert(caps[1].parse().unwrap(), caps[2].to_string());
                     }
@@ -327,7 +333,7 @@ fn on_ready(
 
     if let Some(hs) = ready.hs() {
         // Raft HardState changed, and we need to persist it.
-        store.wl().set_hardstate(hs.clone());
+        store.wl().set_hardstate(hs.clone()).unwrap();
     }
 
     if !ready.persisted_messages().is_empty() {
@@ -369,6 +375,7 @@ struct Proposal {
     normal: Option<(u16, String)>, // key is an u16 integer, and value is a string.
     conf_change: Option<ConfChange>, // conf change.
     transfer_leader: Option<u64>,
+    promote_learner: Option<u64>,
     // If it's proposed, it will be set to the index of the entry.
     proposed: u64,
     propose_success: SyncSender<bool>,
@@ -381,6 +388,7 @@ impl Proposal {
             normal: None,
             conf_change: Some(cc.clone()),
             transfer_leader: None,
+            promote_learner: None,
             proposed: 0,
             propose_success: tx,
         };
@@ -393,6 +401,20 @@ impl Proposal {
             normal: Some((key, value)),
             conf_change: None,
             transfer_leader: None,
+            promote_learner: None,
+            proposed: 0,
+            propose_success: tx,
+        };
+        (proposal, rx)
+    }
+
+    fn promote_learner(id: u64) -> (Self, Receiver<bool>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: None,
+            promote_learner: Some(id),
             proposed: 0,
             propose_success: tx,
         };
@@ -410,6 +432,12 @@ fn propose(raft_group: &mut RawNode<RaftDiskStorage>, proposal: &mut Proposal) {
     } else if let Some(_transferee) = proposal.transfer_leader {
         // TODO: implement transfer leader.
         unimplemented!();
+    } else if let Some(id) = proposal.promote_learner {
+        // Leave the proposal queued until the learner is within LEARNER_MAX_LAG
+        // entries of the leader, it's retried every time the leader loops.
+        if !promote_learner(raft_group, id, LEARNER_MAX_LAG, vec![]).unwrap_or(false) {
+            return;
+        }
     }
 
     let last_index2 = raft_group.raft.raft_log.last_index() + 1;
@@ -421,6 +449,28 @@ fn propose(raft_group: &mut RawNode<RaftDiskStorage>, proposal: &mut Proposal) {
     }
 }
 
+// How far behind the leader's log a learner may be when it is promoted.
+const LEARNER_MAX_LAG: u64 = 5;
+
+// Adds peer `id` as a learner, then promotes it to a voter once it has caught up.
+fn add_learner_and_promote(id: u64, proposals: &Mutex<VecDeque<Proposal>>) {
+    let mut conf_change = ConfChange::default();
+    conf_change.node_id = id;
+    conf_change.set_change_type(ConfChangeType::AddLearnerNode);
+    loop {
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        proposals.lock().unwrap().push_back(proposal);
+        if rx.recv().unwrap() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let (proposal, rx) = Proposal::promote_learner(id);
+    proposals.lock().unwrap().push_back(proposal);
+    assert!(rx.recv().unwrap());
+}
+
 // Proposes some conf change for peers [2, 5].
 fn add_all_followers(proposals: &Mutex<VecDeque<Proposal>>) {
     for i in 2..6u64 {
@@ -437,4 +487,3 @@ fn add_all_followers(proposals: &Mutex<VecDeque<Proposal>>) {
         }
     }
 }
-
@@ -0,0 +1,106 @@
+// Simulated store which heartbeats to a fekv-pd placement driver
+//
+// Start a placement driver and a few stores in separate terminals, e.g:
+//   $ cargo run --bin fekv-pd
+//   $ cargo run --example pd_store -- 1 127.0.0.1:2379 1 2 3 4
+//   $ cargo run --example pd_store -- 2 127.0.0.1:2379
+//   $ cargo run --example pd_store -- 3 127.0.0.1:2379
+//   $ curl localhost:2379/pd/stores
+//
+// Store 1 starts out leading every region with a single replica, the placement
+// driver then adds replicas on stores 2 and 3 and spreads leadership across them.
+// Operators are "applied" by updating the local view of the region only.
+
+use slog::Drain;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use fekv::pd::{
+    send_region_heartbeat, send_store_heartbeat, Operator, RegionHeartbeat, StoreHeartbeat,
+};
+use slog::{error, info, o, warn};
+
+#[tokio::main]
+async fn main() {
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain)
+        .overflow_strategy(slog_async::OverflowStrategy::Block)
+        .build()
+        .fuse();
+    let root = slog::Logger::root(drain, o!());
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        error!(root, "usage: pd_store <store_id> <pd_addr> [region_id ...]");
+        // the async drain has to flush before exiting
+        drop(root);
+        std::process::exit(1);
+    }
+    let store_id: u64 = args[1].parse().expect("store_id must be a number");
+    let pd_addr = args[2].clone();
+    let logger = root.new(o!("store" => store_id));
+
+    // regions this store currently leads
+    let mut regions: HashMap<u64, RegionHeartbeat> = args[3..]
+        .iter()
+        .map(|r| {
+            let region_id: u64 = r.parse().expect("region_id must be a number");
+            let hb = RegionHeartbeat {
+                region_id: region_id,
+                term: 1,
+                leader_store_id: store_id,
+                peer_store_ids: vec![store_id],
+                approximate_size: 1024,
+                approximate_keys: 16,
+            };
+            (region_id, hb)
+        })
+        .collect();
+
+    let store = StoreHeartbeat {
+        store_id: store_id,
+        address: format!("store-{}", store_id),
+        capacity: 1_073_741_824,
+        available: 1_073_741_824,
+    };
+
+    loop {
+        if let Err(e) = send_store_heartbeat(&pd_addr, &store).await {
+            warn!(logger, "store heartbeat failed"; "error" => %e);
+        }
+
+        let mut region_ids: Vec<u64> = regions.keys().copied().collect();
+        region_ids.sort();
+        for region_id in region_ids {
+            let hb = regions[&region_id].clone();
+            let op = match send_region_heartbeat(&pd_addr, &hb).await {
+                Ok(op) => op,
+                Err(e) => {
+                    warn!(logger, "region heartbeat failed"; "region" => region_id, "error" => %e);
+                    continue;
+                }
+            };
+            let region = regions.get_mut(&region_id).unwrap();
+            match op {
+                Some(Operator::AddPeer { store_id, .. }) => {
+                    info!(logger, "add peer"; "region" => region_id, "on_store" => store_id);
+                    region.peer_store_ids.push(store_id);
+                }
+                Some(Operator::RemovePeer { store_id, .. }) => {
+                    info!(logger, "remove peer"; "region" => region_id, "on_store" => store_id);
+                    region.peer_store_ids.retain(|id| *id != store_id);
+                }
+                Some(Operator::TransferLeader { to_store_id, .. }) => {
+                    // no raft behind this example so leadership is simply handed off,
+                    // the new leader would carry on heartbeating with a higher term
+                    info!(logger, "transfer leader"; "region" => region_id, "to_store" => to_store_id);
+                    regions.remove(&region_id);
+                }
+                None => (),
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
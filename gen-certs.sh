@@ -0,0 +1,25 @@
+#!/bin/bash
+#
+# Makes a CA and node/client certificates signed by it for trying out TLS
+# locally, e.g.
+#   $ ./gen-certs.sh node-1 node-2 alice
+#   $ cargo run -- --tls-cert certs/node-1.pem --tls-key certs/node-1-key.pem --tls-ca certs/ca.pem
+#
+set -e
+
+dir=certs
+mkdir -p $dir
+
+if [ ! -f $dir/ca.pem ]; then
+    openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=fekv-ca" \
+        -keyout $dir/ca-key.pem -out $dir/ca.pem
+fi
+
+for name in "$@"; do
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=$name" \
+        -keyout $dir/$name-key.pem -out $dir/$name.csr
+    openssl x509 -req -in $dir/$name.csr -CA $dir/ca.pem -CAkey $dir/ca-key.pem \
+        -CAcreateserial -days 365 -out $dir/$name.pem \
+        -extfile <(printf "subjectAltName=DNS:localhost,IP:127.0.0.1\nextendedKeyUsage=serverAuth,clientAuth")
+    rm $dir/$name.csr
+done
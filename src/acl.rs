@@ -0,0 +1,157 @@
+//
+// Role based access control on key prefixes
+//
+// A Role grants read, write or admin access to keys starting with a prefix,
+// each level includes the ones below it. Principals are bound to a list of
+// role names. Roles and bindings are stored in the store's acl table (json,
+// keyed "role/{name}" and "binding/{principal}") and changed through raft with
+// Command::PutRole, DeleteRole and SetBindings so every replica agrees.
+//
+// Admin access on the "" prefix is needed for the server's /admin endpoints.
+//
+
+use std::io::{Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kvstore::KVStorage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permission {
+    pub prefix: String,
+    pub access: Access,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Role {
+    pub permissions: Vec<Permission>,
+}
+
+impl Role {
+    // also answers whether every key under a prefix is covered (for scans and
+    // range operations), as a permission covers a prefix exactly when it
+    // covers the prefix as a key
+    pub fn allows(&self, key: &[u8], access: Access) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p.access >= access && key.starts_with(p.prefix.as_bytes()))
+    }
+}
+
+pub fn allowed(roles: &[Role], key: &[u8], access: Access) -> bool {
+    roles.iter().any(|r| r.allows(key, access))
+}
+
+pub fn role_key(name: &str) -> String {
+    format!("role/{}", name)
+}
+
+pub fn binding_key(principal: &str) -> String {
+    format!("binding/{}", principal)
+}
+
+fn decode_err(err: serde_json::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+pub fn get_role(store: &impl KVStorage, name: &str) -> Result<Option<Role>> {
+    match store.get_acl(&role_key(name))? {
+        Some(buf) => serde_json::from_slice(&buf).map(Some).map_err(decode_err),
+        None => Ok(None),
+    }
+}
+
+pub fn get_bindings(store: &impl KVStorage, principal: &str) -> Result<Vec<String>> {
+    match store.get_acl(&binding_key(principal))? {
+        Some(buf) => serde_json::from_slice(&buf).map_err(decode_err),
+        None => Ok(Vec::new()),
+    }
+}
+
+// roles bound to principal, bindings to roles which don't exist are skipped
+pub fn roles_for(store: &impl KVStorage, principal: &str) -> Result<Vec<Role>> {
+    let mut roles = Vec::new();
+    for name in get_bindings(store, principal)? {
+        if let Some(role) = get_role(store, &name)? {
+            roles.push(role);
+        }
+    }
+    Ok(roles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::kvstore::memstore::MemKVStore;
+
+    fn perm(prefix: &str, access: Access) -> Permission {
+        Permission {
+            prefix: prefix.to_string(),
+            access: access,
+        }
+    }
+
+    #[test]
+    fn test_role_allows() {
+        let role = Role {
+            permissions: vec![perm("app/", Access::Write), perm("", Access::Read)],
+        };
+        assert!(role.allows(b"app/foo", Access::Write));
+        assert!(role.allows(b"app/foo", Access::Read));
+        assert!(!role.allows(b"app/foo", Access::Admin));
+        assert!(role.allows(b"other", Access::Read));
+        assert!(!role.allows(b"other", Access::Write));
+        // prefixes
+        assert!(role.allows(b"app/sub/", Access::Write));
+        assert!(!role.allows(b"ap", Access::Write));
+        assert!(!allowed(&[], b"app/foo", Access::Read));
+    }
+
+    #[test]
+    fn test_roles_for() {
+        let mut ms = MemKVStore::new();
+        let role = Role {
+            permissions: vec![perm("app/", Access::Write)],
+        };
+        Command::PutRole {
+            name: String::from("writer"),
+            role: role.clone(),
+        }
+        .apply(&mut ms);
+        Command::SetBindings {
+            principal: String::from("alice"),
+            roles: vec![String::from("writer"), String::from("missing")],
+        }
+        .apply(&mut ms);
+
+        assert_eq!(get_role(&ms, "writer").unwrap(), Some(role.clone()));
+        assert_eq!(roles_for(&ms, "alice").unwrap(), vec![role]);
+        assert!(roles_for(&ms, "bob").unwrap().is_empty());
+
+        // removing the role removes the access, the binding is left
+        Command::DeleteRole {
+            name: String::from("writer"),
+        }
+        .apply(&mut ms);
+        assert!(roles_for(&ms, "alice").unwrap().is_empty());
+        assert_eq!(get_bindings(&ms, "alice").unwrap().len(), 2);
+
+        // an empty binding list removes the binding
+        Command::SetBindings {
+            principal: String::from("alice"),
+            roles: vec![],
+        }
+        .apply(&mut ms);
+        assert!(get_bindings(&ms, "alice").unwrap().is_empty());
+        assert_eq!(ms.get_acl(&binding_key("alice")).unwrap(), None);
+    }
+}
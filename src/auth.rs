@@ -0,0 +1,319 @@
+//
+// Authentication for the http api
+//
+// Each configured provider is tried in turn:
+//   - static bearer tokens from --auth-token name=token
+//   - http basic auth against the argon2 hashed user table (KVStorage::get_user)
+//   - the identity of a verified TLS client certificate, put in the request's
+//     extensions as a ClientIdentity by the listener
+// router(...) inserts the resulting Principal into the request's extensions
+// for handlers. With no providers configured auth is disabled. The redis
+// listener authenticates connections with authenticate_with(...) instead.
+//
+// Principals named with --auth-admin can do anything, everyone else is
+// limited to the roles bound to them, see fekv::acl.
+//
+
+use std::collections::{HashMap, HashSet};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request};
+
+use crate::config::ServerConfig;
+use fekv::kvstore::asyncstore::AsyncKVStore;
+use fekv::kvstore::KVStorage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    Token,
+    Basic,
+    ClientCert,
+}
+
+// who made a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub method: AuthMethod,
+}
+
+// subject of a verified TLS client certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+pub enum AuthProvider {
+    // token -> principal name
+    Tokens(HashMap<String, String>),
+    Basic,
+    ClientCert,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    // no credentials for any configured provider
+    Missing,
+    // credentials were given but didn't check out
+    Invalid,
+}
+
+pub struct Auth {
+    providers: Vec<AuthProvider>,
+    admins: HashSet<String>,
+}
+
+impl Auth {
+    pub fn new(providers: Vec<AuthProvider>, admins: HashSet<String>) -> Auth {
+        Auth {
+            providers: providers,
+            admins: admins,
+        }
+    }
+
+    pub fn from_config(cfg: &ServerConfig) -> Auth {
+        let mut providers = Vec::new();
+        if !cfg.auth_tokens.is_empty() {
+            let tokens = cfg
+                .auth_tokens
+                .iter()
+                .map(|(name, token)| (token.clone(), name.clone()))
+                .collect();
+            providers.push(AuthProvider::Tokens(tokens));
+        }
+        if cfg.auth_basic {
+            providers.push(AuthProvider::Basic);
+        }
+        if cfg.auth_client_cert {
+            providers.push(AuthProvider::ClientCert);
+        }
+        Auth::new(providers, cfg.auth_admins.iter().cloned().collect())
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.providers.is_empty()
+    }
+
+    // admins aren't subject to roles, e.g. to create the first ones
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.admins.contains(name)
+    }
+
+    // WWW-Authenticate challenge for 401 responses
+    pub fn challenge(&self) -> &'static str {
+        if self
+            .providers
+            .iter()
+            .any(|p| matches!(p, AuthProvider::Basic))
+        {
+            "Basic realm=\"fekv\""
+        } else {
+            "Bearer realm=\"fekv\""
+        }
+    }
+
+    // Ok(None) when auth is disabled
+    pub async fn authenticate<S: KVStorage + Send + Sync + 'static>(
+        &self,
+        req: &Request<Body>,
+        store: &AsyncKVStore<S>,
+    ) -> Result<Option<Principal>, AuthError> {
+        let authorization = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok());
+        let identity = req.extensions().get::<ClientIdentity>();
+        self.authenticate_with(authorization, identity, store).await
+    }
+
+    // authenticate(...) for credentials which didn't come in an http request,
+    // authorization is what the Authorization header would hold
+    pub async fn authenticate_with<S: KVStorage + Send + Sync + 'static>(
+        &self,
+        authorization: Option<&str>,
+        identity: Option<&ClientIdentity>,
+        store: &AsyncKVStore<S>,
+    ) -> Result<Option<Principal>, AuthError> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let mut err = AuthError::Missing;
+        for provider in &self.providers {
+            let res = match provider {
+                AuthProvider::Tokens(tokens) => check_token(tokens, authorization),
+                AuthProvider::Basic => check_basic(store, authorization).await,
+                AuthProvider::ClientCert => match identity {
+                    Some(id) => Ok(Principal {
+                        name: id.0.clone(),
+                        method: AuthMethod::ClientCert,
+                    }),
+                    None => Err(AuthError::Missing),
+                },
+            };
+            match res {
+                Ok(principal) => return Ok(Some(principal)),
+                Err(AuthError::Invalid) => err = AuthError::Invalid,
+                Err(AuthError::Missing) => {}
+            }
+        }
+        Err(err)
+    }
+}
+
+fn check_token(
+    tokens: &HashMap<String, String>,
+    authorization: Option<&str>,
+) -> Result<Principal, AuthError> {
+    let token = match authorization.and_then(|a| a.strip_prefix("Bearer ")) {
+        Some(token) => token.trim(),
+        None => return Err(AuthError::Missing),
+    };
+    // compare against every token so timing doesn't give away a prefix match
+    let mut found = None;
+    for (t, name) in tokens {
+        if constant_time_eq(t.as_bytes(), token.as_bytes()) {
+            found = Some(name);
+        }
+    }
+    match found {
+        Some(name) => Ok(Principal {
+            name: name.clone(),
+            method: AuthMethod::Token,
+        }),
+        None => Err(AuthError::Invalid),
+    }
+}
+
+async fn check_basic<S: KVStorage + Send + Sync + 'static>(
+    store: &AsyncKVStore<S>,
+    authorization: Option<&str>,
+) -> Result<Principal, AuthError> {
+    let (name, password) = match authorization.and_then(|a| a.strip_prefix("Basic ")) {
+        Some(creds) => parse_basic(creds.trim()).ok_or(AuthError::Invalid)?,
+        None => return Err(AuthError::Missing),
+    };
+    let hash = match store.get_user(name.clone()).await {
+        Ok(Some(hash)) => String::from_utf8(hash).map_err(|_| AuthError::Invalid)?,
+        _ => return Err(AuthError::Invalid),
+    };
+    // argon2 is deliberately slow, keep it off the async workers
+    let ok = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    if !ok {
+        return Err(AuthError::Invalid);
+    }
+    Ok(Principal {
+        name: name,
+        method: AuthMethod::Basic,
+    })
+}
+
+// base64 "name:password"
+fn parse_basic(creds: &str) -> Option<(String, String)> {
+    let decoded = STANDARD.decode(creds).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| err.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_err) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fekv::command::Command;
+    use fekv::kvstore::memstore::MemKVStore;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/fekv/foo");
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_auth_disabled() {
+        let store = AsyncKVStore::new(Arc::new(RwLock::new(MemKVStore::new())));
+        let auth = Auth::new(vec![], HashSet::new());
+        assert_eq!(auth.authenticate(&request(None), &store).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_auth_providers() {
+        let store = AsyncKVStore::new(Arc::new(RwLock::new(MemKVStore::new())));
+        let put = Command::PutUser {
+            name: String::from("alice"),
+            password_hash: hash_password("secret").unwrap(),
+        };
+        put.apply(&mut *store.shared().write().await);
+
+        let tokens = HashMap::from([(String::from("t0ken"), String::from("ci"))]);
+        let auth = Auth::new(
+            vec![
+                AuthProvider::Tokens(tokens),
+                AuthProvider::Basic,
+                AuthProvider::ClientCert,
+            ],
+            HashSet::from([String::from("ci")]),
+        );
+        assert!(auth.is_admin("ci"));
+        assert!(!auth.is_admin("alice"));
+        assert_eq!(auth.challenge(), "Basic realm=\"fekv\"");
+
+        let res = auth
+            .authenticate(&request(Some("Bearer t0ken")), &store)
+            .await;
+        assert_eq!(res.unwrap().unwrap().name, "ci");
+        let res = auth
+            .authenticate(&request(Some("Bearer nope")), &store)
+            .await;
+        assert_eq!(res, Err(AuthError::Invalid));
+
+        let creds = format!("Basic {}", STANDARD.encode("alice:secret"));
+        let res = auth.authenticate(&request(Some(&creds)), &store).await;
+        let principal = res.unwrap().unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.method, AuthMethod::Basic);
+        let creds = format!("Basic {}", STANDARD.encode("alice:wrong"));
+        let res = auth.authenticate(&request(Some(&creds)), &store).await;
+        assert_eq!(res, Err(AuthError::Invalid));
+
+        let mut req = request(None);
+        req.extensions_mut()
+            .insert(ClientIdentity(String::from("node-2")));
+        let res = auth.authenticate(&req, &store).await.unwrap().unwrap();
+        assert_eq!(res.method, AuthMethod::ClientCert);
+
+        let res = auth.authenticate(&request(None), &store).await;
+        assert_eq!(res, Err(AuthError::Missing));
+    }
+}
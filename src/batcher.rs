@@ -0,0 +1,183 @@
+//
+// Proposal batching / group commit
+//
+// Client writes are queued and coalesced into a single Command::Batch raft
+// entry, so concurrent writes share one raft proposal, one RaftDB append
+// and one fsync. A batch is proposed once it holds max_batch_size commands
+// or linger has passed since its first command arrived.
+//
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+
+use crate::command::{Command, CommandResult};
+use crate::metrics;
+use crate::raftnode::{ApplyCallback, RaftNodeHandle};
+
+// upper bounds of the batch size histogram buckets, the last bucket is +Inf
+pub const BATCH_SIZE_BUCKETS: [u64; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+
+#[derive(Default)]
+pub struct BatchMetrics {
+    batches: AtomicU64,
+    commands: AtomicU64,
+    max_batch_size: AtomicU64,
+    buckets: [AtomicU64; BATCH_SIZE_BUCKETS.len() + 1],
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchStats {
+    pub batches: u64,
+    pub commands: u64,
+    pub max_batch_size: u64,
+    // non cumulative count of batches per BATCH_SIZE_BUCKETS bucket
+    pub buckets: Vec<u64>,
+}
+
+impl BatchMetrics {
+    fn observe(&self, size: usize) {
+        let size = size as u64;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.commands.fetch_add(size, Ordering::Relaxed);
+        self.max_batch_size.fetch_max(size, Ordering::Relaxed);
+        let bucket = BATCH_SIZE_BUCKETS
+            .iter()
+            .position(|b| size <= *b)
+            .unwrap_or(BATCH_SIZE_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        metrics::RAFT_BATCH_SIZE.observe(size as f64);
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        BatchStats {
+            batches: self.batches.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+            max_batch_size: self.max_batch_size.load(Ordering::Relaxed),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Batcher {
+    sender: mpsc::UnboundedSender<(Command, ApplyCallback)>,
+    metrics: Arc<BatchMetrics>,
+}
+
+impl Batcher {
+    // spawn the batching task on the current tokio runtime
+    pub fn spawn(node: RaftNodeHandle, max_batch_size: usize, linger: Duration) -> Batcher {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let metrics = Arc::new(BatchMetrics::default());
+        tokio::spawn(run_batcher(
+            node,
+            receiver,
+            max_batch_size.max(1),
+            linger,
+            metrics.clone(),
+        ));
+        Batcher {
+            sender: sender,
+            metrics: metrics,
+        }
+    }
+
+    pub async fn propose(&self, cmd: Command) -> CommandResult {
+        let (tx, rx) = oneshot::channel();
+        let cb: ApplyCallback = Box::new(move |res| {
+            let _ = tx.send(res);
+        });
+        if self.sender.send((cmd, cb)).is_err() {
+            return CommandResult::Failed(String::from("batcher stopped"));
+        }
+        rx.await
+            .unwrap_or(CommandResult::Failed(String::from("proposal dropped")))
+    }
+
+    pub fn metrics(&self) -> BatchStats {
+        self.metrics.stats()
+    }
+}
+
+async fn run_batcher(
+    node: RaftNodeHandle,
+    mut receiver: mpsc::UnboundedReceiver<(Command, ApplyCallback)>,
+    max_batch_size: usize,
+    linger: Duration,
+    metrics: Arc<BatchMetrics>,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + linger;
+        while batch.len() < max_batch_size {
+            if linger.is_zero() {
+                // no lingering, just take whatever is already queued
+                match receiver.try_recv() {
+                    Ok(p) => batch.push(p),
+                    Err(_err) => break,
+                }
+                continue;
+            }
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(p)) => batch.push(p),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        metrics.observe(batch.len());
+        propose_batch(&node, batch);
+    }
+}
+
+fn propose_batch(node: &RaftNodeHandle, mut batch: Vec<(Command, ApplyCallback)>) {
+    if batch.len() == 1 {
+        let (cmd, cb) = batch.pop().unwrap();
+        node.propose(cmd, cb);
+        return;
+    }
+    let (cmds, cbs): (Vec<Command>, Vec<ApplyCallback>) = batch.into_iter().unzip();
+    node.propose(
+        Command::Batch(cmds),
+        Box::new(move |res| match res {
+            CommandResult::Batch(results) => {
+                for (cb, res) in cbs.into_iter().zip(results) {
+                    cb(res);
+                }
+            }
+            // the whole batch failed to commit
+            other => {
+                for cb in cbs {
+                    cb(other.clone());
+                }
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_metrics() {
+        let metrics = BatchMetrics::default();
+        metrics.observe(1);
+        metrics.observe(3);
+        metrics.observe(3);
+        metrics.observe(500);
+
+        let stats = metrics.stats();
+        assert_eq!(stats.batches, 4);
+        assert_eq!(stats.commands, 507);
+        assert_eq!(stats.max_batch_size, 500);
+        assert_eq!(stats.buckets, vec![1, 0, 2, 0, 0, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,110 @@
+//
+// Placement driver server - wraps fekv::pd::PlacementDriver in a small json/http api
+//
+// Run with: `cargo run --bin fekv-pd [listen_addr]` (defaults to 127.0.0.1:2379)
+//
+// Endpoints:
+//   POST /pd/store  - StoreHeartbeat json body
+//   POST /pd/region - RegionHeartbeat json body, returns Operator json (or null)
+//   GET  /pd/stores - per store region/leader counts
+//   GET  /pd/regions - latest heartbeat for every region
+//
+
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use slog::{error, info, o, Drain, Logger};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use fekv::pd::{PdConfig, PlacementDriver, RegionHeartbeat, StoreHeartbeat};
+
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:2379";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listen_addr: SocketAddr = std::env::args()
+        .nth(1)
+        .unwrap_or(String::from(DEFAULT_LISTEN_ADDR))
+        .parse()?;
+    let logger = build_logger();
+    let pd = Arc::new(Mutex::new(PlacementDriver::new(PdConfig::default())));
+
+    let svc_logger = logger.clone();
+    let make_svc = make_service_fn(move |_conn: &AddrStream| {
+        let pd = pd.clone();
+        let logger = svc_logger.clone();
+        let service = service_fn(move |req| pd_router(req, pd.to_owned(), logger.clone()));
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    let server = Server::bind(&listen_addr).serve(make_svc);
+    info!(logger, "placement driver listening"; "addr" => format!("http://{}", listen_addr));
+
+    let graceful = server.with_graceful_shutdown(async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install CTRL+C signal handler");
+    });
+    if let Err(e) = graceful.await {
+        error!(logger, "server error"; "error" => %e);
+    }
+    Ok(())
+}
+
+fn build_logger() -> Logger {
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    Logger::root(drain, o!())
+}
+
+async fn pd_router(
+    req: Request<Body>,
+    pd: Arc<Mutex<PlacementDriver>>,
+    logger: Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/pd/store") => {
+            let b = hyper::body::to_bytes(req).await?;
+            let hb: StoreHeartbeat = match serde_json::from_slice(&b) {
+                Ok(hb) => hb,
+                Err(_err) => return Ok(response_status(StatusCode::BAD_REQUEST)),
+            };
+            pd.lock().unwrap().handle_store_heartbeat(hb);
+            Ok(Response::new(Body::from("OK")))
+        }
+        (&Method::POST, "/pd/region") => {
+            let b = hyper::body::to_bytes(req).await?;
+            let hb: RegionHeartbeat = match serde_json::from_slice(&b) {
+                Ok(hb) => hb,
+                Err(_err) => return Ok(response_status(StatusCode::BAD_REQUEST)),
+            };
+            let op = pd.lock().unwrap().handle_region_heartbeat(hb);
+            if let Some(op) = &op {
+                info!(logger, "scheduling operator"; "operator" => ?op);
+            }
+            Ok(response_json(&op))
+        }
+        (&Method::GET, "/pd/stores") => Ok(response_json(&pd.lock().unwrap().store_stats())),
+        (&Method::GET, "/pd/regions") => Ok(response_json(&pd.lock().unwrap().regions())),
+        _ => Ok(response_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+fn response_json<T: serde::Serialize>(val: &T) -> Response<Body> {
+    match serde_json::to_vec(val) {
+        Ok(b) => Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(b))
+            .unwrap(),
+        Err(_err) => response_status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn response_status(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::default();
+    *resp.status_mut() = status;
+    resp
+}
@@ -0,0 +1,134 @@
+//
+// CRC32C checksums for values at rest
+//
+// DiskKVStore values are stored as a 4 byte little endian crc32c followed by
+// the value, raft entries carry their checksum in raftstore::EntryRef. A
+// mismatch is reported as an io::Error of kind InvalidData wrapping a
+// ChecksumError so callers can tell corruption apart from other failures.
+//
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::metrics;
+
+pub const CHECKSUM_LEN: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumError {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch: expected {:08x}, got {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+pub fn checksum(buf: &[u8]) -> u32 {
+    crc32c::crc32c(buf)
+}
+
+// checksum over several buffers as if they were concatenated
+pub fn checksum_parts(parts: &[&[u8]]) -> u32 {
+    parts
+        .iter()
+        .fold(0, |crc, part| crc32c::crc32c_append(crc, part))
+}
+
+pub fn corruption_error(expected: u32, actual: u32) -> Error {
+    metrics::CHECKSUM_FAILURES.inc();
+    Error::new(
+        ErrorKind::InvalidData,
+        ChecksumError {
+            expected: expected,
+            actual: actual,
+        },
+    )
+}
+
+pub fn is_corruption(err: &Error) -> bool {
+    err.kind() == ErrorKind::InvalidData
+        && err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ChecksumError>())
+}
+
+// prefix buf with its checksum
+pub fn seal(buf: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(CHECKSUM_LEN + buf.len());
+    sealed.extend_from_slice(&checksum(buf).to_le_bytes());
+    sealed.extend_from_slice(buf);
+    sealed
+}
+
+// returns the value or the (expected, actual) checksums on mismatch
+fn check(sealed: &[u8]) -> Result<&[u8], (u32, u32)> {
+    if sealed.len() < CHECKSUM_LEN {
+        // too short to even hold a checksum
+        return Err((0, 0));
+    }
+    let (crc, buf) = sealed.split_at(CHECKSUM_LEN);
+    let expected = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    let actual = checksum(buf);
+    if expected != actual {
+        return Err((expected, actual));
+    }
+    Ok(buf)
+}
+
+// verify and strip the checksum added by seal
+pub fn unseal(sealed: &[u8]) -> Result<&[u8], Error> {
+    check(sealed).map_err(|(expected, actual)| corruption_error(expected, actual))
+}
+
+// like unseal but just reports whether sealed is intact, used by the
+// scrubber so a known bad value isn't counted again on every pass
+pub fn verify(sealed: &[u8]) -> bool {
+    check(sealed).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_unseal() {
+        let sealed = seal(b"bar");
+        assert_eq!(sealed.len(), CHECKSUM_LEN + 3);
+        assert_eq!(unseal(&sealed).unwrap(), b"bar");
+
+        // empty values are fine too
+        assert_eq!(unseal(&seal(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_unseal_corrupt() {
+        let mut sealed = seal(b"bar");
+        sealed[CHECKSUM_LEN] ^= 0x01;
+        let err = unseal(&sealed).unwrap_err();
+        assert!(is_corruption(&err));
+        assert!(!verify(&sealed));
+
+        // truncated
+        let err = unseal(&sealed[..2]).unwrap_err();
+        assert!(is_corruption(&err));
+
+        // other errors aren't corruption
+        let err = Error::new(ErrorKind::InvalidData, "something else");
+        assert!(!is_corruption(&err));
+    }
+
+    #[test]
+    fn test_checksum_parts() {
+        assert_eq!(checksum_parts(&[b"foo", b"bar"]), checksum(b"foobar"));
+        assert_eq!(checksum_parts(&[]), 0);
+    }
+}
@@ -0,0 +1,1104 @@
+//
+// Commands replicated through the raft log and applied to a KVStorage
+//
+// Commands are bincode encoded into the data of normal raft entries, behind a
+// BINARY marker byte. Entries from before that are json, which decode() still
+// reads so old raft logs replay.
+//
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::acl::{self, Role};
+use crate::checksum;
+use crate::jsondoc::{self, Patch, PatchError};
+use crate::kvstore::{legacy_key, unexpired, KVStorage, ValueMeta};
+use crate::namespace::{self, Quota, Rejection};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    // ns is the key's namespace, "" (the default) for entries from before
+    // there were namespaces
+    Set {
+        #[serde(default)]
+        ns: String,
+        #[serde(deserialize_with = "deserialize_key")]
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // timestamped by the proposing node so every replica stores the same
+        #[serde(default)]
+        meta: Option<ValueMeta>,
+    },
+    Delete {
+        #[serde(default)]
+        ns: String,
+        #[serde(deserialize_with = "deserialize_key")]
+        key: Vec<u8>,
+    },
+    // delete keys from start up to end (None for no end), see
+    // KVStorage::delete_range
+    DeleteRange {
+        ns: String,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    },
+    // update a json document in place, modified is the proposing node's time
+    // and max_len its largest value, the patched document can't be longer
+    Patch {
+        ns: String,
+        key: Vec<u8>,
+        #[serde(with = "patch_json")]
+        patch: Patch,
+        modified: u64,
+        #[serde(default)]
+        max_len: Option<u64>,
+    },
+    // add by (which may be negative) to a counter, see KVStorage::incr
+    Incr {
+        ns: String,
+        key: Vec<u8>,
+        by: i64,
+        modified: u64,
+    },
+    // several client commands coalesced into one raft entry, applied in order
+    Batch(Vec<Command>),
+    // apply success if every compare holds, otherwise failure, with nothing
+    // else applied in between. If any command of the branch doesn't apply
+    // none of them do, and the Txn's result is that command's
+    Txn {
+        compares: Vec<Compare>,
+        success: Vec<Command>,
+        failure: Vec<Command>,
+        // the proposing node's time, values which had expired by then count
+        // as missing
+        #[serde(default)]
+        now: u64,
+    },
+    // a client request tagged with a session id and sequence number so retries
+    // (e.g. after a leader failover) are applied at most once, see
+    // https://pdos.csail.mit.edu/6.824/labs/lab-kvraft.html
+    Session {
+        client_id: String,
+        seq: u64,
+        cmd: Box<Command>,
+    },
+    // basic auth users, the password is hashed by the proposing node so every
+    // replica stores the same hash
+    PutUser {
+        name: String,
+        password_hash: String,
+    },
+    DeleteUser {
+        name: String,
+    },
+    // authorization roles and which roles a principal has, see acl
+    PutRole {
+        name: String,
+        role: Role,
+    },
+    DeleteRole {
+        name: String,
+    },
+    // an empty list of roles removes the binding
+    SetBindings {
+        principal: String,
+        roles: Vec<String>,
+    },
+    // create a namespace or change its quota, see namespace
+    PutNamespace {
+        name: String,
+        quota: Quota,
+    },
+    // drops the namespace's keys with it
+    DropNamespace {
+        name: String,
+    },
+    // delete keys which had expired by now, the proposing node's time. One
+    // written since it was found expired is left alone, see expirer
+    Expire {
+        ns: String,
+        keys: Vec<Vec<u8>>,
+        now: u64,
+    },
+}
+
+// a condition on a key's current value, see Command::Txn
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Compare {
+    pub ns: String,
+    pub key: Vec<u8>,
+    pub expect: Expect,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Expect {
+    // the key holds exactly this value
+    Value(Vec<u8>),
+    Exists,
+    Missing,
+}
+
+// keys are encoded as bytes, entries from when they were strings are decoded
+// with kvstore::legacy_key so they still apply to the same keys
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyRepr {
+    Bytes(Vec<u8>),
+    Legacy(String),
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+    // only json entries can hold string keys
+    if !d.is_human_readable() {
+        return Vec::deserialize(d);
+    }
+    match KeyRepr::deserialize(d)? {
+        KeyRepr::Bytes(key) => Ok(key),
+        KeyRepr::Legacy(key) => Ok(legacy_key(&key)),
+    }
+}
+
+// Patches hold serde_json Values, which only self describing formats can
+// decode, so bincode carries them as json text
+mod patch_json {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(patch: &Patch, s: S) -> std::result::Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            return patch.serialize(s);
+        }
+        let json = serde_json::to_string(patch).map_err(S::Error::custom)?;
+        json.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Patch, D::Error> {
+        if d.is_human_readable() {
+            return Patch::deserialize(d);
+        }
+        let json = String::deserialize(d)?;
+        serde_json::from_str(&json).map_err(D::Error::custom)
+    }
+}
+
+// first byte of bincode encoded commands, json ones start with {
+const BINARY: u8 = 1;
+
+// Outcome of applying a command, sent back to whoever proposed it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CommandResult {
+    Done(bool),
+    // a counter's new value
+    Value(i64),
+    // how many keys a range delete removed
+    Deleted(u64),
+    Failed(String),
+    // the store refused the command, e.g. it would go over a quota
+    Rejected(Rejection),
+    // the command doesn't apply to the value it's for
+    Refused(CommandError),
+    Batch(Vec<CommandResult>),
+    // which branch of a Txn was applied and its results
+    Txn {
+        succeeded: bool,
+        results: Vec<CommandResult>,
+    },
+}
+
+// Writes refused for what they'd do to a value, rather than to a namespace
+// (see namespace::Rejection). Like those they're wrapped in an io::Error by
+// refused(...) and sent back to the proposer, as CommandResult::Refused
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommandError {
+    NoSuchKey,
+    NotJson,
+    PatchFailed(String),
+    // a patched value would be over the proposer's max value size
+    TooLarge,
+    // counters, see KVStorage::incr
+    NotInteger,
+    Overflow,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::NoSuchKey => write!(f, "no such key"),
+            CommandError::NotJson => write!(f, "value isn't a json document"),
+            CommandError::PatchFailed(reason) => write!(f, "patch failed: {}", reason),
+            CommandError::TooLarge => write!(f, "value would be too large"),
+            CommandError::NotInteger => write!(f, "value isn't an integer"),
+            CommandError::Overflow => write!(f, "counter would overflow"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub fn refused(err: CommandError) -> Error {
+    Error::other(err)
+}
+
+pub fn refusal(err: &Error) -> Option<CommandError> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<CommandError>())
+        .cloned()
+}
+
+// Last request applied for a client, persisted in the store's session table
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ClientSession {
+    seq: u64,
+    result: CommandResult,
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![BINARY];
+        bincode::serialize_into(&mut data, self).unwrap();
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Command> {
+        match data.first() {
+            Some(&BINARY) => bincode::deserialize(&data[1..])
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err)),
+            _ => {
+                serde_json::from_slice(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+            }
+        }
+    }
+
+    // operation name used to label metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::Delete { .. } => "delete",
+            Command::DeleteRange { .. } => "delete_range",
+            Command::Patch { .. } => "patch",
+            Command::Incr { .. } => "incr",
+            Command::Batch(_) => "batch",
+            Command::Txn { .. } => "txn",
+            Command::Session { cmd, .. } => cmd.name(),
+            Command::PutUser { .. } => "put_user",
+            Command::DeleteUser { .. } => "delete_user",
+            Command::PutRole { .. } => "put_role",
+            Command::DeleteRole { .. } => "delete_role",
+            Command::SetBindings { .. } => "set_bindings",
+            Command::PutNamespace { .. } => "put_namespace",
+            Command::DropNamespace { .. } => "drop_namespace",
+            Command::Expire { .. } => "expire",
+        }
+    }
+
+    pub fn apply(&self, store: &mut impl KVStorage) -> CommandResult {
+        let res = match self {
+            Command::Set {
+                ns,
+                key,
+                value,
+                meta,
+            } => resolve(store, ns, key)
+                .and_then(|(ns, key)| store.set_with_meta(&ns, &key, value.to_vec(), meta.clone())),
+            Command::Delete { ns, key } => {
+                resolve(store, ns, key).and_then(|(ns, key)| store.delete(&ns, &key))
+            }
+            Command::DeleteRange { ns, start, end } => {
+                let deleted = resolve(store, ns, start).and_then(|(to, start)| {
+                    let end = resolve_end(&to, end.as_deref());
+                    store.delete_range(&to, &start, end.as_deref())
+                });
+                return match deleted {
+                    Ok(n) => CommandResult::Deleted(n),
+                    Err(err) => failed(err),
+                };
+            }
+            Command::Patch {
+                ns,
+                key,
+                patch,
+                modified,
+                max_len,
+            } => resolve(store, ns, key).and_then(|(ns, key)| {
+                let max_len = max_len.map_or(usize::MAX, |max| max as usize);
+                patch_value(store, &ns, &key, patch, *modified, max_len)
+            }),
+            Command::Incr {
+                ns,
+                key,
+                by,
+                modified,
+            } => {
+                let n = resolve(store, ns, key)
+                    .and_then(|(ns, key)| store.incr(&ns, &key, *by, *modified));
+                return match n {
+                    Ok(n) => CommandResult::Value(n),
+                    Err(err) => failed(err),
+                };
+            }
+            Command::Batch(cmds) => {
+                return CommandResult::Batch(cmds.iter().map(|c| c.apply(&mut *store)).collect());
+            }
+            Command::Txn {
+                compares,
+                success,
+                failure,
+                now,
+            } => {
+                let succeeded = match compares_hold(store, compares, *now) {
+                    Ok(succeeded) => succeeded,
+                    Err(err) => return failed(err),
+                };
+                let cmds = match succeeded {
+                    true => success,
+                    false => failure,
+                };
+                let mut results = Vec::new();
+                let applied = store.atomic(&mut |mut st| {
+                    results = cmds.iter().map(|c| c.apply(&mut st)).collect();
+                    match results.iter().any(is_failure) {
+                        true => Err(Error::other("txn rolled back")),
+                        false => Ok(()),
+                    }
+                });
+                if let Some(res) = results.iter().find(|res| is_failure(res)) {
+                    return res.clone();
+                }
+                return match applied {
+                    Ok(()) => CommandResult::Txn {
+                        succeeded: succeeded,
+                        results: results,
+                    },
+                    Err(err) => failed(err),
+                };
+            }
+            Command::Session {
+                client_id,
+                seq,
+                cmd,
+            } => return apply_session(store, client_id, *seq, cmd),
+            Command::PutUser {
+                name,
+                password_hash,
+            } => store.set_user(name, password_hash.as_bytes().to_vec()),
+            Command::DeleteUser { name } => store.delete_user(name),
+            Command::PutRole { name, role } => {
+                store.set_acl(&acl::role_key(name), serde_json::to_vec(role).unwrap())
+            }
+            Command::DeleteRole { name } => store.delete_acl(&acl::role_key(name)),
+            Command::SetBindings { principal, roles } if roles.is_empty() => {
+                store.delete_acl(&acl::binding_key(principal))
+            }
+            Command::SetBindings { principal, roles } => store.set_acl(
+                &acl::binding_key(principal),
+                serde_json::to_vec(roles).unwrap(),
+            ),
+            Command::PutNamespace { name, quota } => store.put_namespace(name, *quota),
+            Command::DropNamespace { name } => store.drop_namespace(name),
+            Command::Expire { ns, keys, now } => {
+                return match expire(store, ns, keys, *now) {
+                    Ok(n) => CommandResult::Deleted(n),
+                    Err(err) => failed(err),
+                };
+            }
+        };
+        match res {
+            Ok(res) => CommandResult::Done(res),
+            Err(err) => failed(err),
+        }
+    }
+}
+
+// Proposers split paths into a namespace and key against their own view of
+// the namespaces, which can lag, so default namespace keys are split again
+// against the store's as they're applied. A "{name}/..." key written once
+// name exists would be shadowed by it. Named namespaces which are gone by
+// then are rejected by the store
+fn resolve(store: &impl KVStorage, ns: &str, key: &[u8]) -> Result<(String, Vec<u8>)> {
+    if ns == namespace::DEFAULT {
+        if let Some((name, rest)) = namespace::split_key(key) {
+            if store.namespace(name)?.is_some() {
+                return Ok((name.to_string(), rest.to_vec()));
+            }
+        }
+    }
+    Ok((ns.to_string(), key.to_vec()))
+}
+
+// a default namespace range's end in the namespace resolve(...) moved its
+// start to, None past the end of its keys
+fn resolve_end(ns: &str, end: Option<&[u8]>) -> Option<Vec<u8>> {
+    let end = end?;
+    if ns == namespace::DEFAULT {
+        return Some(end.to_vec());
+    }
+    end.strip_prefix(namespace::key_prefix(ns).as_slice())
+        .map(|end| end.to_vec())
+}
+
+// deletes the keys which had expired by now, returning how many there were
+fn expire(store: &mut impl KVStorage, ns: &str, keys: &[Vec<u8>], now: u64) -> Result<u64> {
+    let mut deleted = 0;
+    for key in keys {
+        let expired = store
+            .get_meta(ns, key)?
+            .is_some_and(|meta| meta.expired(now));
+        if expired && store.delete(ns, key)? {
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+// rejections and refusals are expected outcomes, anything else is a failure
+fn failed(err: Error) -> CommandResult {
+    if let Some(rejection) = namespace::rejection(&err) {
+        return CommandResult::Rejected(rejection);
+    }
+    match refusal(&err) {
+        Some(err) => CommandResult::Refused(err),
+        None => CommandResult::Failed(err.to_string()),
+    }
+}
+
+// whether a result, or any part of a Batch, didn't apply
+fn is_failure(res: &CommandResult) -> bool {
+    match res {
+        CommandResult::Failed(_) | CommandResult::Rejected(_) | CommandResult::Refused(_) => true,
+        CommandResult::Batch(results) => results.iter().any(is_failure),
+        _ => false,
+    }
+}
+
+// apply a patch to a json document, values which aren't one or would grow
+// past max_len are refused
+fn patch_value(
+    store: &mut impl KVStorage,
+    ns: &str,
+    key: &[u8],
+    patch: &Patch,
+    modified: u64,
+    max_len: usize,
+) -> Result<bool> {
+    let buf = match store.get(ns, key) {
+        Ok(buf) => buf,
+        Err(err) if checksum::is_corruption(&err) || namespace::rejection(&err).is_some() => {
+            return Err(err)
+        }
+        Err(_err) => return Err(refused(CommandError::NoSuchKey)),
+    };
+    let meta = match store.get_meta(ns, key)? {
+        Some(meta) if jsondoc::is_json(meta.content_type.as_deref()) => meta,
+        _ => return Err(refused(CommandError::NotJson)),
+    };
+    let doc = serde_json::from_slice(&buf).map_err(|_| refused(CommandError::NotJson))?;
+    let doc = jsondoc::apply(&doc, patch, max_len).map_err(|err| match err {
+        PatchError::Failed(reason) => refused(CommandError::PatchFailed(reason)),
+        PatchError::TooLarge => refused(CommandError::TooLarge),
+    })?;
+    let meta = ValueMeta {
+        modified: modified,
+        ..meta
+    };
+    store.set_with_meta(ns, key, serde_json::to_vec(&doc).unwrap(), Some(meta))
+}
+
+// whether every compare holds, a damaged value or unknown namespace fails
+// the whole txn rather than counting as missing
+fn compares_hold(store: &impl KVStorage, compares: &[Compare], now: u64) -> Result<bool> {
+    for cmp in compares {
+        let (ns, key) = resolve(store, &cmp.ns, &cmp.key)?;
+        let value = match store.get(&ns, &key) {
+            Ok(value) => Some(value),
+            Err(err) if checksum::is_corruption(&err) || namespace::rejection(&err).is_some() => {
+                return Err(err)
+            }
+            Err(_err) => None,
+        };
+        let (value, _meta) = unexpired(value, store.get_meta(&ns, &key)?, now);
+        let holds = match (&cmp.expect, value) {
+            (Expect::Value(expected), Some(value)) => *expected == value,
+            (Expect::Exists, Some(_)) | (Expect::Missing, None) => true,
+            _ => false,
+        };
+        if !holds {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// the session record is only as durable as the command's own writes when
+// both land in one KVStorage::atomic call, as the raft node applies entries
+fn apply_session(
+    store: &mut impl KVStorage,
+    client_id: &str,
+    seq: u64,
+    cmd: &Command,
+) -> CommandResult {
+    let session = match store.get_session(client_id) {
+        Ok(Some(b)) => serde_json::from_slice::<ClientSession>(&b).ok(),
+        Ok(None) => None,
+        Err(err) => return CommandResult::Failed(err.to_string()),
+    };
+    if let Some(session) = session {
+        if seq == session.seq {
+            // duplicate of the last request, reply with the cached result
+            return session.result;
+        }
+        if seq < session.seq {
+            return CommandResult::Failed(format!(
+                "stale request seq {} for client {}, last applied {}",
+                seq, client_id, session.seq
+            ));
+        }
+    }
+
+    let result = cmd.apply(store);
+    let session = ClientSession {
+        seq: seq,
+        result: result.clone(),
+    };
+    if let Err(err) = store.set_session(client_id, serde_json::to_vec(&session).unwrap()) {
+        return CommandResult::Failed(err.to_string());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::{self, diskstore::DiskKVStore, memstore::MemKVStore};
+
+    #[test]
+    fn test_command_encode_apply() {
+        let mut ms = MemKVStore::new();
+
+        let set = Command::Set {
+            ns: String::new(),
+            key: b"foo".to_vec(),
+            value: b"bar".to_vec(),
+            meta: None,
+        };
+        let decoded = Command::decode(&set.encode()).unwrap();
+        assert_eq!(decoded, set);
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Done(true));
+        // bytes aren't spelled out as json arrays
+        let big = Command::Set {
+            ns: String::new(),
+            key: b"foo".to_vec(),
+            value: vec![255; 1000],
+            meta: None,
+        };
+        assert!(big.encode().len() < 1100);
+        assert_eq!(Command::decode(&big.encode()).unwrap(), big);
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+
+        let delete = Command::Delete {
+            ns: String::new(),
+            key: b"foo".to_vec(),
+        };
+        assert_eq!(delete.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(delete.apply(&mut ms), CommandResult::Done(false));
+
+        assert!(Command::decode(b"junk").is_err());
+
+        // entries written when keys were percent-encoded strings
+        let legacy = br#"{"Set":{"key":"a%20b","value":[98,97,114]}}"#;
+        let decoded = Command::decode(legacy).unwrap();
+        assert_eq!(
+            decoded,
+            Command::Set {
+                ns: String::new(),
+                key: b"a b".to_vec(),
+                value: b"bar".to_vec(),
+                meta: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_command_batch() {
+        let mut ms = MemKVStore::new();
+        let batch = Command::Batch(vec![
+            Command::Set {
+                ns: String::new(),
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+                meta: None,
+            },
+            Command::Delete {
+                ns: String::new(),
+                key: b"foo".to_vec(),
+            },
+            Command::Delete {
+                ns: String::new(),
+                key: b"foo".to_vec(),
+            },
+        ]);
+        let decoded = Command::decode(&batch.encode()).unwrap();
+        assert_eq!(
+            decoded.apply(&mut ms),
+            CommandResult::Batch(vec![
+                CommandResult::Done(true),
+                CommandResult::Done(true),
+                CommandResult::Done(false),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_command_session_dedup() {
+        let mut ms = MemKVStore::new();
+        let session = |seq: u64, cmd: Command| Command::Session {
+            client_id: String::from("client-1"),
+            seq: seq,
+            cmd: Box::new(cmd),
+        };
+        let delete = Command::Delete {
+            ns: String::new(),
+            key: b"foo".to_vec(),
+        };
+        ms.set("", b"foo", b"bar".to_vec()).unwrap();
+
+        // first delete removes the key
+        assert_eq!(
+            session(1, delete.clone()).apply(&mut ms),
+            CommandResult::Done(true)
+        );
+        // a retry of seq 1 isn't applied again, the cached result is returned
+        ms.set("", b"foo", b"bar".to_vec()).unwrap();
+        assert_eq!(
+            session(1, delete.clone()).apply(&mut ms),
+            CommandResult::Done(true)
+        );
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+
+        // next seq is applied
+        assert_eq!(
+            session(2, delete.clone()).apply(&mut ms),
+            CommandResult::Done(true)
+        );
+        assert!(ms.get("", b"foo").is_err());
+
+        // older requests are rejected
+        let res = session(1, delete.clone()).apply(&mut ms);
+        assert!(matches!(res, CommandResult::Failed(_)));
+    }
+
+    #[test]
+    fn test_command_users() {
+        let mut ms = MemKVStore::new();
+        let put = Command::PutUser {
+            name: String::from("alice"),
+            password_hash: String::from("$argon2id$hash"),
+        };
+        let decoded = Command::decode(&put.encode()).unwrap();
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(
+            ms.get_user("alice").unwrap(),
+            Some(b"$argon2id$hash".to_vec())
+        );
+        // users aren't visible as regular keys
+        assert!(ms.get("", b"alice").is_err());
+
+        let delete = Command::DeleteUser {
+            name: String::from("alice"),
+        };
+        assert_eq!(delete.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(delete.apply(&mut ms), CommandResult::Done(false));
+    }
+
+    #[test]
+    fn test_command_namespaces() {
+        let mut ms = MemKVStore::new();
+        let set = Command::Set {
+            ns: String::from("team"),
+            key: b"foo".to_vec(),
+            value: b"bar".to_vec(),
+            meta: None,
+        };
+        assert_eq!(
+            set.apply(&mut ms),
+            CommandResult::Rejected(Rejection::NoSuchNamespace(String::from("team")))
+        );
+
+        let put = Command::PutNamespace {
+            name: String::from("team"),
+            quota: Quota {
+                max_keys: Some(1),
+                max_bytes: None,
+            },
+        };
+        let decoded = Command::decode(&put.encode()).unwrap();
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(set.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+        let over = Command::Set {
+            ns: String::from("team"),
+            key: b"two".to_vec(),
+            value: b"bar".to_vec(),
+            meta: None,
+        };
+        assert_eq!(
+            over.apply(&mut ms),
+            CommandResult::Rejected(Rejection::QuotaExceeded(String::from("team")))
+        );
+
+        let drop = Command::DropNamespace {
+            name: String::from("team"),
+        };
+        assert_eq!(drop.apply(&mut ms), CommandResult::Done(true));
+        assert!(ms.get("team", b"foo").is_err());
+    }
+
+    #[test]
+    fn test_command_resolve_namespace() {
+        let mut ms = MemKVStore::new();
+        ms.put_namespace("team", Quota::default()).unwrap();
+
+        // proposed before the proposer saw team created
+        let set = Command::Set {
+            ns: String::new(),
+            key: b"team/foo".to_vec(),
+            value: b"bar".to_vec(),
+            meta: None,
+        };
+        assert_eq!(set.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+        assert!(ms.get("", b"team/foo").is_err());
+        let other = Command::Set {
+            ns: String::new(),
+            key: b"other/foo".to_vec(),
+            value: b"bar".to_vec(),
+            meta: None,
+        };
+        assert_eq!(other.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(ms.get("", b"other/foo").unwrap(), b"bar");
+
+        // a default namespace prefix range lands in the namespace
+        ms.set("team", b"fop", b"x".to_vec()).unwrap();
+        ms.set("team", b"zzz", b"x".to_vec()).unwrap();
+        let range = Command::DeleteRange {
+            ns: String::new(),
+            start: b"team/fo".to_vec(),
+            end: Some(b"team/fp".to_vec()),
+        };
+        assert_eq!(range.apply(&mut ms), CommandResult::Deleted(2));
+        assert_eq!(ms.get("team", b"zzz").unwrap(), b"x");
+        let all = Command::DeleteRange {
+            ns: String::new(),
+            start: b"team/".to_vec(),
+            end: Some(b"team0".to_vec()),
+        };
+        assert_eq!(all.apply(&mut ms), CommandResult::Deleted(1));
+        assert_eq!(ms.get("", b"other/foo").unwrap(), b"bar");
+    }
+
+    #[test]
+    fn test_command_patch() {
+        let mut ms = MemKVStore::new();
+        let patch = |p: &str| Command::Patch {
+            ns: String::new(),
+            key: b"doc".to_vec(),
+            patch: Patch::Merge(serde_json::from_str(p).unwrap()),
+            modified: 2,
+            max_len: Some(32),
+        };
+        assert_eq!(
+            patch("{}").apply(&mut ms),
+            CommandResult::Refused(CommandError::NoSuchKey)
+        );
+        ms.set("", b"doc", br#"{"a":1}"#.to_vec()).unwrap();
+        assert_eq!(
+            patch("{}").apply(&mut ms),
+            CommandResult::Refused(CommandError::NotJson)
+        );
+
+        let meta = ValueMeta {
+            content_type: Some(String::from("application/json")),
+            created: 1,
+            modified: 1,
+            ..Default::default()
+        };
+        ms.set_with_meta("", b"doc", br#"{"a":1}"#.to_vec(), Some(meta))
+            .unwrap();
+        let decoded = Command::decode(&patch(r#"{"b":2}"#).encode()).unwrap();
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Done(true));
+        assert_eq!(ms.get("", b"doc").unwrap(), br#"{"a":1,"b":2}"#);
+        let meta = ms.get_meta("", b"doc").unwrap().unwrap();
+        assert_eq!((meta.created, meta.modified), (1, 2));
+        assert_eq!(
+            patch(r#"{"c":"0123456789abcdef"}"#).apply(&mut ms),
+            CommandResult::Refused(CommandError::TooLarge)
+        );
+        assert_eq!(ms.get("", b"doc").unwrap(), br#"{"a":1,"b":2}"#);
+
+        let test = Command::Patch {
+            ns: String::new(),
+            key: b"doc".to_vec(),
+            patch: Patch::Json(vec![jsondoc::PatchOp::Test {
+                path: String::from("/a"),
+                value: serde_json::json!(2),
+            }]),
+            modified: 3,
+            max_len: None,
+        };
+        assert!(matches!(
+            test.apply(&mut ms),
+            CommandResult::Refused(CommandError::PatchFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_delete_range() {
+        let mut ms = MemKVStore::new();
+        for key in [&b"app/a"[..], b"app/b", b"other"] {
+            ms.set("", key, b"x".to_vec()).unwrap();
+        }
+        let delete = Command::DeleteRange {
+            ns: String::new(),
+            start: b"app/".to_vec(),
+            end: kvstore::prefix_end(b"app/"),
+        };
+        let decoded = Command::decode(&delete.encode()).unwrap();
+        assert_eq!(decoded.name(), "delete_range");
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Deleted(2));
+        assert_eq!(delete.apply(&mut ms), CommandResult::Deleted(0));
+        assert_eq!(ms.get("", b"other").unwrap(), b"x");
+
+        let missing = Command::DeleteRange {
+            ns: String::from("team"),
+            start: Vec::new(),
+            end: None,
+        };
+        assert_eq!(
+            missing.apply(&mut ms),
+            CommandResult::Rejected(Rejection::NoSuchNamespace(String::from("team")))
+        );
+    }
+
+    #[test]
+    fn test_command_txn() {
+        let mut ms = MemKVStore::new();
+        let set = |key: &[u8], value: &[u8]| Command::Set {
+            ns: String::new(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+            meta: None,
+        };
+        let cmp = |key: &[u8], expect: Expect| Compare {
+            ns: String::new(),
+            key: key.to_vec(),
+            expect: expect,
+        };
+        // create lock only if it's missing
+        let txn = Command::Txn {
+            compares: vec![cmp(b"lock", Expect::Missing)],
+            success: vec![set(b"lock", b"a"), set(b"owner", b"a")],
+            failure: vec![],
+            now: 0,
+        };
+        let decoded = Command::decode(&txn.encode()).unwrap();
+        assert_eq!(decoded.name(), "txn");
+        assert_eq!(
+            decoded.apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: true,
+                results: vec![CommandResult::Done(true), CommandResult::Done(true)],
+            }
+        );
+        assert_eq!(
+            txn.apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: false,
+                results: vec![],
+            }
+        );
+
+        // compare and swap
+        let swap = Command::Txn {
+            compares: vec![
+                cmp(b"lock", Expect::Value(b"a".to_vec())),
+                cmp(b"owner", Expect::Exists),
+            ],
+            success: vec![set(b"lock", b"b")],
+            failure: vec![set(b"lost", b"1")],
+            now: 0,
+        };
+        assert!(matches!(
+            swap.apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: true,
+                ..
+            }
+        ));
+        assert_eq!(ms.get("", b"lock").unwrap(), b"b");
+        assert!(matches!(
+            swap.apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: false,
+                ..
+            }
+        ));
+        assert_eq!(ms.get("", b"lost").unwrap(), b"1");
+
+        let unknown = Command::Txn {
+            compares: vec![Compare {
+                ns: String::from("team"),
+                key: b"k".to_vec(),
+                expect: Expect::Missing,
+            }],
+            success: vec![],
+            failure: vec![],
+            now: 0,
+        };
+        assert_eq!(
+            unknown.apply(&mut ms),
+            CommandResult::Rejected(Rejection::NoSuchNamespace(String::from("team")))
+        );
+
+        // expired values count as missing
+        let expiring = ValueMeta {
+            expires: Some(5),
+            ..Default::default()
+        };
+        ms.set_with_meta("", b"lease", b"a".to_vec(), Some(expiring))
+            .unwrap();
+        let acquire = |now| Command::Txn {
+            compares: vec![cmp(b"lease", Expect::Missing)],
+            success: vec![set(b"lease", b"b")],
+            failure: vec![],
+            now: now,
+        };
+        assert!(matches!(
+            acquire(4).apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: false,
+                ..
+            }
+        ));
+        assert!(matches!(
+            acquire(5).apply(&mut ms),
+            CommandResult::Txn {
+                succeeded: true,
+                ..
+            }
+        ));
+    }
+
+    // the last put of a Txn goes over the namespace's quota, the ones before
+    // it are undone with it
+    fn check_txn_rollback(store: &mut impl KVStorage) {
+        store
+            .put_namespace(
+                "team",
+                Quota {
+                    max_keys: Some(3),
+                    max_bytes: None,
+                },
+            )
+            .unwrap();
+        store.set("team", b"a", b"0".to_vec()).unwrap();
+        let set = |key: &[u8]| Command::Set {
+            ns: String::from("team"),
+            key: key.to_vec(),
+            value: b"1".to_vec(),
+            meta: None,
+        };
+        let txn = Command::Txn {
+            compares: vec![],
+            success: vec![set(b"a"), set(b"b"), set(b"c"), set(b"d")],
+            failure: vec![],
+            now: 0,
+        };
+        let mut res = None;
+        // as the raft node applies it, inside an atomic of its own
+        store
+            .atomic(&mut |mut st| {
+                res = Some(txn.apply(&mut st));
+                st.set_applied_index(3).map(|_| ())
+            })
+            .unwrap();
+        assert_eq!(
+            res.unwrap(),
+            CommandResult::Rejected(Rejection::QuotaExceeded(String::from("team")))
+        );
+        assert_eq!(store.get("team", b"a").unwrap(), b"0");
+        assert!(store.get("team", b"b").is_err());
+        assert!(store.get("team", b"c").is_err());
+        assert_eq!(store.applied_index().unwrap(), 3);
+
+        assert_eq!(
+            txn.apply(&mut *store),
+            CommandResult::Rejected(Rejection::QuotaExceeded(String::from("team")))
+        );
+        assert_eq!(store.get("team", b"a").unwrap(), b"0");
+        assert!(store.get("team", b"b").is_err());
+    }
+
+    #[test]
+    fn test_command_txn_rollback() {
+        check_txn_rollback(&mut MemKVStore::new());
+        let tmp = tempfile::tempdir().unwrap();
+        check_txn_rollback(&mut DiskKVStore::new_with_db_path(tmp.path()).unwrap());
+    }
+
+    #[test]
+    fn test_command_incr() {
+        let mut ms = MemKVStore::new();
+        let incr = |by: i64| Command::Incr {
+            ns: String::new(),
+            key: b"count".to_vec(),
+            by: by,
+            modified: 1,
+        };
+        let decoded = Command::decode(&incr(5).encode()).unwrap();
+        assert_eq!(decoded.name(), "incr");
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Value(5));
+        assert_eq!(incr(-7).apply(&mut ms), CommandResult::Value(-2));
+
+        // retries get the value the first attempt returned
+        let session = Command::Session {
+            client_id: String::from("client"),
+            seq: 1,
+            cmd: Box::new(incr(1)),
+        };
+        assert_eq!(session.apply(&mut ms), CommandResult::Value(-1));
+        assert_eq!(session.apply(&mut ms), CommandResult::Value(-1));
+        assert_eq!(ms.get("", b"count").unwrap(), b"-1");
+
+        ms.set("", b"count", b"text".to_vec()).unwrap();
+        assert_eq!(
+            incr(1).apply(&mut ms),
+            CommandResult::Refused(CommandError::NotInteger)
+        );
+    }
+
+    #[test]
+    fn test_command_expire() {
+        let mut ms = MemKVStore::new();
+        let expiring = |expires| ValueMeta {
+            expires: Some(expires),
+            ..Default::default()
+        };
+        for (key, expires) in [(&b"a"[..], 5), (b"b", 10)] {
+            ms.set_with_meta("", key, b"x".to_vec(), Some(expiring(expires)))
+                .unwrap();
+        }
+        ms.set("", b"c", b"x".to_vec()).unwrap();
+        let expire = Command::Expire {
+            ns: String::new(),
+            keys: vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()],
+            now: 5,
+        };
+        let decoded = Command::decode(&expire.encode()).unwrap();
+        assert_eq!(decoded.name(), "expire");
+        // only a had expired by then, c never does and d is already gone
+        assert_eq!(decoded.apply(&mut ms), CommandResult::Deleted(1));
+        assert_eq!(ms.count_range("", b"", None).unwrap(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+//
+// Server configuration from command line flags, see `cargo run -- --help`
+//
+
+use clap::Parser;
+use slog::Level;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::logging::{parse_level, LogFormat};
+use fekv::kvstore;
+use fekv::tls::TlsFiles;
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "fekv", about = "A toy key value store")]
+pub struct ServerConfig {
+    /// Raft id of this node
+    #[arg(long, default_value_t = 1)]
+    pub id: u64,
+
+    /// Address the client listener binds to
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+
+    /// Port the client listener binds to
+    #[arg(long, default_value_t = 3000)]
+    pub port: u16,
+
+    /// Tokio worker threads, defaults to one per cpu
+    #[arg(long)]
+    pub workers: Option<usize>,
+
+    /// Max threads for blocking work, store reads and password checks
+    #[arg(long, default_value_t = 512)]
+    pub blocking_threads: usize,
+
+    /// Seconds to wait for in flight requests to finish on shutdown
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_timeout_secs: u64,
+
+    /// Other raft members as id=host:port, may be repeated
+    #[arg(long = "peer", value_parser = parse_peer)]
+    pub peers: Vec<(u64, String)>,
+
+    /// Id of a member which starts out as a non-voting learner, may be
+    /// repeated. Every node needs the same list, promote learners with
+    /// POST /admin/learners/{id}
+    #[arg(long = "learner")]
+    pub learners: Vec<u64>,
+
+    /// Committed entries a node may not have applied yet and still serve
+    /// reads, more (or not knowing a leader) get a 503
+    #[arg(long, default_value_t = 1000)]
+    pub stale_read_max_lag: u64,
+
+    /// Directory for the raft log and the lmdb store, every node on a host
+    /// needs its own
+    #[arg(long, default_value = "./data")]
+    pub data_dir: PathBuf,
+
+    /// Storage backend for the key value store
+    #[arg(long, default_value = "lmdb", value_parser = parse_store)]
+    pub store: String,
+
+    /// Max client writes coalesced into a single raft proposal
+    #[arg(long, default_value_t = 64)]
+    pub max_batch_size: usize,
+
+    /// How long to wait for more writes before proposing a batch
+    #[arg(long, default_value_t = 2)]
+    pub batch_linger_ms: u64,
+
+    /// Largest key accepted in bytes, lmdb limits this to 491
+    #[arg(long, default_value_t = 256)]
+    pub max_key_size: usize,
+
+    /// Largest value accepted in bytes, bigger requests get a 413
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub max_value_size: usize,
+
+    /// Values larger than this are stored and streamed back in chunks
+    #[arg(long, default_value_t = 256 * 1024)]
+    pub chunk_size: usize,
+
+    /// Static bearer token as name=token, may be repeated, enables auth
+    #[arg(long = "auth-token", value_parser = parse_token)]
+    pub auth_tokens: Vec<(String, String)>,
+
+    /// Accept http basic auth against users added with PUT /admin/users/{name}
+    #[arg(long)]
+    pub auth_basic: bool,
+
+    /// Accept the subject of a verified TLS client certificate as the user
+    #[arg(long)]
+    pub auth_client_cert: bool,
+
+    /// Principal with full access regardless of roles, may be repeated
+    #[arg(long = "auth-admin")]
+    pub auth_admins: Vec<String>,
+
+    /// Bearer token sent with raft messages to peers with auth on, an
+    /// --auth-token of an --auth-admin there
+    #[arg(long)]
+    pub peer_token: Option<String>,
+
+    /// PEM certificate, serves https and calls peers over https when set
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA certificate(s) to verify client and peer certificates with,
+    /// without it peers must share --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Separate listener for raft traffic from peers, which must present a
+    /// certificate signed by --tls-ca when TLS is on. Peers' --peer addresses
+    /// should point here
+    #[arg(long)]
+    pub peer_listen: Option<SocketAddr>,
+
+    /// Listener for the gRPC api in proto/fekv.proto, off unless set
+    #[arg(long)]
+    pub grpc_listen: Option<SocketAddr>,
+
+    /// Listener for redis clients (RESP), off unless set
+    #[arg(long)]
+    pub resp_listen: Option<SocketAddr>,
+
+    /// Seconds between background checksum scrubs, 0 disables them
+    #[arg(long, default_value_t = 3600)]
+    pub scrub_interval_secs: u64,
+
+    /// Seconds between sweeps deleting expired keys, 0 disables them
+    #[arg(long, default_value_t = 60)]
+    pub expire_interval_secs: u64,
+
+    /// Log level: critical, error, warning, info, debug or trace
+    #[arg(long, default_value = "info", value_parser = parse_level)]
+    pub log_level: Level,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Term)]
+    pub log_format: LogFormat,
+
+    /// Append a json access log line for every request to this file,
+    /// without it requests are only logged at debug level
+    #[arg(long)]
+    pub access_log: Option<String>,
+}
+
+fn parse_peer(s: &str) -> Result<(u64, String), String> {
+    let (id, addr) = s
+        .split_once('=')
+        .ok_or(format!("expected id=host:port, got {}", s))?;
+    let id = id.parse().map_err(|_| format!("invalid peer id {}", id))?;
+    Ok((id, addr.to_string()))
+}
+
+fn parse_store(s: &str) -> Result<String, String> {
+    let backends = kvstore::backends();
+    match backends.contains(&s) {
+        true => Ok(s.to_string()),
+        false => Err(format!("expected one of {}", backends.join(", "))),
+    }
+}
+
+fn parse_token(s: &str) -> Result<(String, String), String> {
+    let (name, token) = s
+        .split_once('=')
+        .ok_or(format!("expected name=token, got {}", s))?;
+    if name.is_empty() || token.is_empty() {
+        return Err(String::from("token name and value can't be empty"));
+    }
+    Ok((name.to_string(), token.to_string()))
+}
+
+impl ServerConfig {
+    pub fn tls_files(&self) -> Option<TlsFiles> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+                ca: self.tls_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//
+// Expired key reclamation
+//
+// A value past its expiry reads as missing but stays in the store, counting
+// towards its namespace's quota, until something deletes it. The leader
+// periodically walks every namespace for such keys and proposes a
+// Command::Expire for each batch through raft, so every replica deletes the
+// same keys. Each key is checked again as the command is applied, one written
+// since it was found is kept. Followers leave sweeping to the leader.
+//
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use slog::{debug, error, Logger};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::command::{Command, CommandResult};
+use crate::kvstore::KVStorage;
+use crate::metrics;
+use crate::namespace;
+use crate::raftnode::RaftNodeHandle;
+
+// keys read at a time while sweeping, and the most one Expire deletes
+pub const SWEEP_BATCH: usize = 256;
+
+// a batch's expired keys and where the next batch starts
+type Batch = (Vec<Vec<u8>>, Option<Vec<u8>>);
+
+pub struct Expirer<S: KVStorage> {
+    store: Arc<RwLock<S>>,
+    node: RaftNodeHandle,
+    // only one sweep at a time
+    running: Mutex<()>,
+    logger: Logger,
+}
+
+impl<S: KVStorage + Send + Sync + 'static> Expirer<S> {
+    pub fn new(store: Arc<RwLock<S>>, node: RaftNodeHandle, logger: &Logger) -> Expirer<S> {
+        Expirer {
+            store: store,
+            node: node,
+            running: Mutex::new(()),
+            logger: logger.clone(),
+        }
+    }
+
+    // sweep every interval on the current tokio runtime, a zero interval
+    // leaves expired keys until they're overwritten or deleted
+    pub fn spawn(self: &Arc<Self>, interval: Duration) {
+        if interval.is_zero() {
+            return;
+        }
+        let expirer = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match expirer.sweep().await {
+                    Ok(0) => {}
+                    Ok(n) => debug!(expirer.logger, "deleted expired keys"; "keys" => n),
+                    Err(err) => error!(expirer.logger, "expiry sweep failed"; "error" => err),
+                }
+            }
+        });
+    }
+
+    // delete the keys which have expired, if this node is the leader, and
+    // return how many there were
+    pub async fn sweep(&self) -> Result<u64, String> {
+        let _running = self.running.lock().await;
+        let status = self.node.status();
+        if status.leader_id != status.id {
+            return Ok(0);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut names = vec![namespace::DEFAULT.to_string()];
+        let namespaces = self.store.read().await.namespaces();
+        names.extend(
+            namespaces
+                .map_err(|err| err.to_string())?
+                .into_iter()
+                .map(|ns| ns.name),
+        );
+        let mut deleted = 0;
+        for ns in names {
+            let mut start = Some(Vec::new());
+            while let Some(from) = start {
+                let store = self.store.clone();
+                let scan = ns.clone();
+                let (keys, next) = tokio::task::spawn_blocking(move || {
+                    expired_keys(&*store.blocking_read(), &scan, &from, now)
+                })
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| err.to_string())?;
+                start = next;
+                if keys.is_empty() {
+                    continue;
+                }
+                let cmd = Command::Expire {
+                    ns: ns.clone(),
+                    keys: keys,
+                    now: now,
+                };
+                match self.node.propose_wait(cmd).await {
+                    CommandResult::Deleted(n) => deleted += n,
+                    // dropped while it was being swept
+                    CommandResult::Rejected(_) => break,
+                    res => return Err(format!("{:?}", res)),
+                }
+            }
+        }
+        metrics::EXPIRED_KEYS.inc_by(deleted);
+        Ok(deleted)
+    }
+}
+
+// the keys among the SWEEP_BATCH from start which had expired by now, and
+// the key to carry on from, None once the namespace's keys run out
+fn expired_keys(store: &impl KVStorage, ns: &str, start: &[u8], now: u64) -> io::Result<Batch> {
+    let keys = store.range_keys(ns, start, None, SWEEP_BATCH)?;
+    // nothing sorts between key and key\0
+    let next = match keys.len() == SWEEP_BATCH {
+        true => keys.last().map(|key| [key.as_slice(), &[0]].concat()),
+        false => None,
+    };
+    let mut expired = Vec::new();
+    for key in keys {
+        if store
+            .get_meta(ns, &key)?
+            .is_some_and(|meta| meta.expired(now))
+        {
+            expired.push(key);
+        }
+    }
+    Ok((expired, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::memstore::MemKVStore;
+    use crate::kvstore::ValueMeta;
+
+    #[test]
+    fn test_expired_keys() {
+        let mut ms = MemKVStore::new();
+        for i in 0..SWEEP_BATCH + 10 {
+            let meta = ValueMeta {
+                expires: Some(i as u64 % 2),
+                ..Default::default()
+            };
+            let key = format!("{:04}", i);
+            ms.set_with_meta("", key.as_bytes(), b"x".to_vec(), Some(meta))
+                .unwrap();
+        }
+        let (expired, next) = expired_keys(&ms, "", b"", 0).unwrap();
+        assert_eq!(expired.len(), SWEEP_BATCH / 2);
+        assert_eq!(expired[0], b"0000");
+        let next = next.unwrap();
+        assert_eq!(next, format!("{:04}\0", SWEEP_BATCH - 1).into_bytes());
+
+        let (expired, next) = expired_keys(&ms, "", &next, 0).unwrap();
+        assert_eq!(expired.len(), 5);
+        assert_eq!(next, None);
+    }
+}
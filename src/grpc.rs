@@ -0,0 +1,871 @@
+//
+// gRPC api, the KV, Watch and Cluster services in proto/fekv.proto
+//
+// Served by the same hyper server as the REST api on its own listener
+// (--grpc-listen, http/2 only). router(...) authenticates requests as usual
+// then hands them to dispatch(...) here, they're authorized against the same
+// acls and writes go through the batcher and raft like fekv_handler's.
+// The messages are generated from the .proto by build.rs, see the proto
+// module at the bottom. Each message is framed with a compressed flag
+// (compression isn't supported) and its length, and the call's status is sent
+// in grpc-status/grpc-message trailers, or in the headers for errors.
+//
+// Watch streams changes as this node applies them, see kvstore::watch, from
+// when the watch starts. A watcher which hangs up is noticed at its next
+// event, or when it falls behind and gets ABORTED. Dropping the watched
+// namespace or installing a raft snapshot ends the watch with ABORTED too.
+//
+// MemberAdd's address is handed to the transport through the conf change but
+// isn't persisted, restarted nodes need it as a --peer.
+//
+
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use protobuf::{Message, SingularPtrField};
+use raft::prelude::{ConfChange, ConfChangeType};
+use slog::{debug, error, warn, Logger};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::Principal;
+use crate::handlers::{
+    authorize_principal, now_ms, read_body, response_404, response_415, ServerState, MAX_META_SIZE,
+};
+use fekv::acl::Access;
+use fekv::checksum;
+use fekv::command::{Command, CommandError, CommandResult, Compare as CommandCompare, Expect};
+use fekv::jsondoc;
+use fekv::kvstore::watch::{Event, EventKind};
+use fekv::kvstore::{display_key, in_range, prefix_end, unexpired, KVStorage, ValueMeta};
+use fekv::namespace::{self, Rejection};
+
+use self::proto::*;
+
+// room in a request for everything besides values, which are limited by
+// --max-value-size as over http
+const MAX_REQUEST_OVERHEAD: usize = 64 * 1024;
+
+// grpc-message is percent-encoded, see
+// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md
+const GRPC_MESSAGE: &AsciiSet = &CONTROLS.add(b'%');
+
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    Ok = 0,
+    InvalidArgument = 3,
+    NotFound = 5,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: Code, message: &str) -> Status {
+        Status {
+            code: code,
+            message: message.to_string(),
+        }
+    }
+
+    fn ok() -> Status {
+        Status::new(Code::Ok, "")
+    }
+}
+
+type Reply<T> = Result<T, Status>;
+
+// calls a method, the route is "/fekv.{service}" and rest the method name
+pub async fn dispatch(
+    req: Request<Body>,
+    route: &str,
+    rest: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.method() != Method::POST {
+        return response_404().await;
+    }
+    let grpc = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/grpc"));
+    if !grpc {
+        return response_415().await;
+    }
+    let principal = req.extensions().get::<Principal>().cloned();
+    let body = match read_body(req, state.max_value_size + MAX_REQUEST_OVERHEAD).await? {
+        Some(body) => body,
+        None => {
+            let status = Status::new(Code::ResourceExhausted, "request too large");
+            return Ok(status_response(&status));
+        }
+    };
+    let method = format!("{}/{}", route, rest);
+    let res = call(&method, &body, principal.as_ref(), state, log).await;
+    Ok(match res {
+        Ok(resp) => resp,
+        Err(status) => status_response(&status),
+    })
+}
+
+async fn call(
+    method: &str,
+    body: &[u8],
+    p: Option<&Principal>,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Reply<Response<Body>> {
+    match method {
+        "/fekv.KV/Get" => get(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.KV/Put" => put(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.KV/Delete" => delete(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.KV/Range" => range(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.KV/Txn" => txn(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.Watch/Watch" => watch(decode(body)?, p, state, log).await,
+        "/fekv.Cluster/MemberList" => member_list(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.Cluster/MemberAdd" => member_add(decode(body)?, p, &state, log).await.map(unary),
+        "/fekv.Cluster/MemberRemove" => member_remove(decode(body)?, p, &state, log)
+            .await
+            .map(unary),
+        _ => {
+            debug!(log, "unknown grpc method"; "method" => method);
+            Err(Status::new(Code::Unimplemented, "unknown method"))
+        }
+    }
+}
+
+// the request message, which is the body's only frame
+fn decode<M: Message>(body: &[u8]) -> Reply<M> {
+    let invalid = |message: &str| Status::new(Code::InvalidArgument, message);
+    if body.len() < 5 {
+        return Err(invalid("truncated message"));
+    }
+    if body[0] != 0 {
+        return Err(Status::new(
+            Code::Unimplemented,
+            "compressed messages aren't supported",
+        ));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if body.len() - 5 != len {
+        return Err(invalid("message length doesn't match the body"));
+    }
+    M::parse_from_bytes(&body[5..]).map_err(|err| invalid(&err.to_string()))
+}
+
+fn frame(msg: &impl Message) -> Vec<u8> {
+    // proto3 has no required fields, the only thing writing checks
+    let msg = msg.write_to_bytes().unwrap();
+    let mut buf = Vec::with_capacity(5 + msg.len());
+    buf.push(0);
+    buf.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&msg);
+    buf
+}
+
+fn trailers(status: &Status) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("grpc-status", HeaderValue::from(status.code as u32));
+    let message = utf8_percent_encode(&status.message, GRPC_MESSAGE).to_string();
+    if let Ok(v) = HeaderValue::from_str(&message) {
+        headers.insert("grpc-message", v);
+    }
+    headers
+}
+
+fn grpc_response(body: Body) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/grpc")
+        .body(body)
+        .unwrap()
+}
+
+// errors are sent as a trailers-only response, the status in the headers
+pub fn status_response(status: &Status) -> Response<Body> {
+    let mut resp = grpc_response(Body::empty());
+    resp.headers_mut().extend(trailers(status));
+    resp
+}
+
+// one message followed by an OK status
+fn unary(msg: impl Message) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let frame = frame(&msg);
+    tokio::spawn(async move {
+        if sender.send_data(frame.into()).await.is_ok() {
+            let _ = sender.send_trailers(trailers(&Status::ok())).await;
+        }
+    });
+    grpc_response(body)
+}
+
+fn rejected(rejection: Rejection) -> Status {
+    let code = match rejection {
+        Rejection::NoSuchNamespace(_) => Code::NotFound,
+        Rejection::InvalidNamespace(_) => Code::InvalidArgument,
+        Rejection::QuotaExceeded(_) => Code::ResourceExhausted,
+        Rejection::TooManyNamespaces | Rejection::NamespaceInUse(_) => Code::FailedPrecondition,
+    };
+    Status::new(code, &rejection.to_string())
+}
+
+fn refused(err: CommandError) -> Status {
+    let code = match err {
+        CommandError::NoSuchKey => Code::NotFound,
+        CommandError::TooLarge => Code::ResourceExhausted,
+        CommandError::NotJson
+        | CommandError::PatchFailed(_)
+        | CommandError::NotInteger
+        | CommandError::Overflow => Code::FailedPrecondition,
+    };
+    Status::new(code, &err.to_string())
+}
+
+// a write's result unless it failed or was rejected
+fn applied(res: CommandResult, log: &Logger) -> Reply<CommandResult> {
+    match res {
+        CommandResult::Failed(err) => {
+            warn!(log, "proposal failed"; "error" => &err);
+            Err(Status::new(Code::Unavailable, &err))
+        }
+        CommandResult::Rejected(rejection) => {
+            debug!(log, "proposal rejected"; "reason" => %rejection);
+            Err(rejected(rejection))
+        }
+        CommandResult::Refused(err) => {
+            debug!(log, "proposal refused"; "reason" => %err);
+            Err(refused(err))
+        }
+        res => Ok(res),
+    }
+}
+
+// a failed read, missing keys are only NOT_FOUND where a single key was asked
+// for, see get(...)
+fn read_failed(err: std::io::Error, log: &Logger) -> Status {
+    if checksum::is_corruption(&err) {
+        error!(log, "stored value is corrupt"; "error" => %err);
+        return Status::new(Code::DataLoss, "stored value is corrupt");
+    }
+    match namespace::rejection(&err) {
+        Some(rejection) => rejected(rejection),
+        None => {
+            warn!(log, "read failed"; "error" => %err);
+            Status::new(Code::Internal, &err.to_string())
+        }
+    }
+}
+
+async fn authorize(
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    ns: &str,
+    key: &[u8],
+    access: Access,
+    log: &Logger,
+) -> Reply<()> {
+    let qualified = namespace::qualified_key(ns, key);
+    match authorize_principal(principal, state, &qualified, access, log).await {
+        true => Ok(()),
+        false => Err(Status::new(Code::PermissionDenied, "access denied")),
+    }
+}
+
+// the keys a delete, range or watch covers, and the prefix all of them start
+// with which acls are checked against
+#[derive(Debug, PartialEq)]
+struct KeyRange {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    prefix: Vec<u8>,
+    // just the one key
+    single: bool,
+}
+
+impl KeyRange {
+    fn new(key: &[u8], range_end: &[u8], prefix: bool) -> KeyRange {
+        let single = !prefix && range_end.is_empty();
+        let end = match (prefix, range_end.is_empty()) {
+            (true, _) => prefix_end(key),
+            (false, false) => Some(range_end.to_vec()),
+            // a range of one, nothing sorts between key and key\0
+            (false, true) => {
+                let mut end = key.to_vec();
+                end.push(0);
+                Some(end)
+            }
+        };
+        let prefix = match (prefix, &end) {
+            (true, _) => key.to_vec(),
+            // keys from start to end share whatever prefix those two do
+            (false, Some(end)) => key
+                .iter()
+                .zip(end)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| *a)
+                .collect(),
+            (false, None) => Vec::new(),
+        };
+        KeyRange {
+            start: key.to_vec(),
+            end: end,
+            prefix: prefix,
+            single: single,
+        }
+    }
+}
+
+fn key_value(key: Vec<u8>, value: Vec<u8>, meta: Option<ValueMeta>) -> KeyValue {
+    let meta = meta.unwrap_or_default();
+    KeyValue {
+        key: key,
+        value: value,
+        content_type: meta.content_type.unwrap_or_default(),
+        created: meta.created,
+        modified: meta.modified,
+        metadata: meta.user.into_iter().collect(),
+        ..Default::default()
+    }
+}
+
+fn check_key(state: &ServerState<impl KVStorage + Send + Sync + 'static>, key: &[u8]) -> Reply<()> {
+    match key.len() > state.max_key_size {
+        true => Err(Status::new(Code::InvalidArgument, "key too large")),
+        false => Ok(()),
+    }
+}
+
+// reads are refused while the store lags too far behind, see
+// ServerState::readable
+fn readable(state: &ServerState<impl KVStorage + Send + Sync + 'static>) -> Reply<()> {
+    match state.readable() {
+        true => Ok(()),
+        false => Err(Status::new(
+            Code::Unavailable,
+            "store too stale to read from",
+        )),
+    }
+}
+
+async fn get(
+    req: GetRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<GetResponse> {
+    authorize(
+        principal,
+        state,
+        &req.namespace,
+        &req.key,
+        Access::Read,
+        log,
+    )
+    .await?;
+    readable(state)?;
+    let (ns, key, now) = (req.namespace, req.key, now_ms());
+    let found = state
+        .store
+        .read(move |st| {
+            let value = st.get(&ns, &key)?;
+            match unexpired(Some(value), st.get_meta(&ns, &key)?, now) {
+                (Some(value), meta) => Ok(Some(key_value(key, value, meta))),
+                _ => Ok(None),
+            }
+        })
+        .await;
+    match found {
+        Ok(Some(kv)) => Ok(GetResponse {
+            kv: SingularPtrField::some(kv),
+            ..Default::default()
+        }),
+        Ok(None) => Err(Status::new(Code::NotFound, "no such key")),
+        Err(err) if checksum::is_corruption(&err) || namespace::rejection(&err).is_some() => {
+            Err(read_failed(err, log))
+        }
+        Err(_err) => Err(Status::new(Code::NotFound, "no such key")),
+    }
+}
+
+// a put as a command, checking it the same way fekv_handler does
+fn put_command(
+    req: PutRequest,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+) -> Reply<Command> {
+    check_key(state, &req.key)?;
+    if req.value.len() > state.max_value_size {
+        return Err(Status::new(Code::ResourceExhausted, "value too large"));
+    }
+    let size = req.content_type.len()
+        + req
+            .metadata
+            .iter()
+            .map(|(k, v)| k.len() + v.len())
+            .sum::<usize>();
+    if size > MAX_META_SIZE {
+        return Err(Status::new(Code::ResourceExhausted, "metadata too large"));
+    }
+    let content_type = match req.content_type.is_empty() {
+        true => None,
+        false => Some(req.content_type),
+    };
+    let json = jsondoc::is_json(content_type.as_deref());
+    if json && serde_json::from_slice::<serde_json::Value>(&req.value).is_err() {
+        return Err(Status::new(Code::InvalidArgument, "invalid json document"));
+    }
+    let now = now_ms();
+    let meta = ValueMeta {
+        content_type: content_type,
+        user: req.metadata.into_iter().collect(),
+        created: now,
+        modified: now,
+        ..Default::default()
+    };
+    Ok(Command::Set {
+        ns: req.namespace,
+        key: req.key,
+        value: req.value,
+        meta: Some(meta),
+    })
+}
+
+fn delete_command(
+    req: DeleteRequest,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+) -> Reply<Command> {
+    check_key(state, &req.key)?;
+    let range = KeyRange::new(&req.key, &req.range_end, req.prefix);
+    if range.single {
+        return Ok(Command::Delete {
+            ns: req.namespace,
+            key: req.key,
+        });
+    }
+    Ok(Command::DeleteRange {
+        ns: req.namespace,
+        start: range.start,
+        end: range.end,
+    })
+}
+
+// a delete removes one key (Done) or a range (Deleted)
+fn deleted(res: CommandResult) -> DeleteResponse {
+    let deleted = match res {
+        CommandResult::Done(deleted) => deleted as u64,
+        CommandResult::Deleted(n) => n,
+        _ => 0,
+    };
+    DeleteResponse {
+        deleted: deleted,
+        ..Default::default()
+    }
+}
+
+async fn put(
+    req: PutRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<PutResponse> {
+    authorize(
+        principal,
+        state,
+        &req.namespace,
+        &req.key,
+        Access::Write,
+        log,
+    )
+    .await?;
+    let cmd = put_command(req, state)?;
+    applied(state.batcher.propose(cmd).await, log)?;
+    Ok(PutResponse::new())
+}
+
+async fn delete(
+    req: DeleteRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<DeleteResponse> {
+    let range = KeyRange::new(&req.key, &req.range_end, req.prefix);
+    authorize(
+        principal,
+        state,
+        &req.namespace,
+        &range.prefix,
+        Access::Write,
+        log,
+    )
+    .await?;
+    let cmd = delete_command(req, state)?;
+    Ok(deleted(applied(state.batcher.propose(cmd).await, log)?))
+}
+
+async fn range(
+    req: RangeRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<RangeResponse> {
+    let range = KeyRange::new(&req.key, &req.range_end, req.prefix);
+    authorize(
+        principal,
+        state,
+        &req.namespace,
+        &range.prefix,
+        Access::Read,
+        log,
+    )
+    .await?;
+    readable(state)?;
+    let limit = match req.limit {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+    let (ns, keys_only, now) = (req.namespace, req.keys_only, now_ms());
+    let found = state
+        .store
+        .read(move |st| {
+            let mut keys = st.range_keys(
+                &ns,
+                &range.start,
+                range.end.as_deref(),
+                limit.saturating_add(1),
+            )?;
+            let more = keys.len() > limit;
+            keys.truncate(limit);
+            let mut kvs = Vec::with_capacity(keys.len());
+            for key in keys {
+                let meta = st.get_meta(&ns, &key)?;
+                if meta.as_ref().is_some_and(|meta| meta.expired(now)) {
+                    continue;
+                }
+                let value = match keys_only {
+                    true => Vec::new(),
+                    false => st.get(&ns, &key)?,
+                };
+                kvs.push(key_value(key, value, meta));
+            }
+            Ok(RangeResponse {
+                kvs: kvs.into(),
+                more: more,
+                ..Default::default()
+            })
+        })
+        .await;
+    found.map_err(|err| read_failed(err, log))
+}
+
+async fn txn(
+    req: TxnRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<TxnResponse> {
+    let mut compares = Vec::with_capacity(req.compare.len());
+    for cmp in req.compare {
+        authorize(
+            principal,
+            state,
+            &cmp.namespace,
+            &cmp.key,
+            Access::Read,
+            log,
+        )
+        .await?;
+        // targets this doesn't know parse as VALUE, the number kept with
+        // the unknown fields
+        if cmp.unknown_fields.get(3).is_some() {
+            return Err(Status::new(Code::InvalidArgument, "unknown compare target"));
+        }
+        let expect = match cmp.target {
+            CompareTarget::VALUE => Expect::Value(cmp.value),
+            CompareTarget::EXISTS => Expect::Exists,
+            CompareTarget::MISSING => Expect::Missing,
+        };
+        compares.push(CommandCompare {
+            ns: cmp.namespace,
+            key: cmp.key,
+            expect: expect,
+        });
+    }
+    // both branches are checked up front, which one applies isn't known
+    // until the txn is
+    let (success, success_puts) = txn_ops(req.success.into_vec(), principal, state, log).await?;
+    let (failure, failure_puts) = txn_ops(req.failure.into_vec(), principal, state, log).await?;
+    let cmd = Command::Txn {
+        compares: compares,
+        success: success,
+        failure: failure,
+        now: now_ms(),
+    };
+    let (succeeded, results) = match applied(state.batcher.propose(cmd).await, log)? {
+        CommandResult::Txn { succeeded, results } => (succeeded, results),
+        res => {
+            return Err(Status::new(
+                Code::Internal,
+                &format!("unexpected {:?}", res),
+            ))
+        }
+    };
+    let puts = match succeeded {
+        true => success_puts,
+        false => failure_puts,
+    };
+    let mut responses = Vec::with_capacity(results.len());
+    for (res, put) in results.into_iter().zip(puts) {
+        let res = applied(res, log)?;
+        let response = match put {
+            true => ResponseOp_oneof_response::put(PutResponse::new()),
+            false => ResponseOp_oneof_response::delete(deleted(res)),
+        };
+        responses.push(ResponseOp {
+            response: Some(response),
+            ..Default::default()
+        });
+    }
+    Ok(TxnResponse {
+        succeeded: succeeded,
+        responses: responses.into(),
+        ..Default::default()
+    })
+}
+
+// a txn branch's commands, and which of them are puts
+async fn txn_ops(
+    ops: Vec<RequestOp>,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<(Vec<Command>, Vec<bool>)> {
+    let mut cmds = Vec::with_capacity(ops.len());
+    let mut puts = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op.request {
+            Some(RequestOp_oneof_request::put(req)) => {
+                authorize(
+                    principal,
+                    state,
+                    &req.namespace,
+                    &req.key,
+                    Access::Write,
+                    log,
+                )
+                .await?;
+                cmds.push(put_command(req, state)?);
+                puts.push(true);
+            }
+            Some(RequestOp_oneof_request::delete(req)) => {
+                let range = KeyRange::new(&req.key, &req.range_end, req.prefix);
+                authorize(
+                    principal,
+                    state,
+                    &req.namespace,
+                    &range.prefix,
+                    Access::Write,
+                    log,
+                )
+                .await?;
+                cmds.push(delete_command(req, state)?);
+                puts.push(false);
+            }
+            None => return Err(Status::new(Code::InvalidArgument, "empty txn op")),
+        }
+    }
+    Ok((cmds, puts))
+}
+
+fn watch_response(event: Event) -> WatchResponse {
+    let kind = match event.kind {
+        EventKind::Put => EventType::PUT,
+        // Dropped and Restored end the watch before getting here
+        _ => EventType::DELETE,
+    };
+    WatchResponse {
+        field_type: kind,
+        kv: SingularPtrField::some(key_value(event.key, event.value, event.meta)),
+        ..Default::default()
+    }
+}
+
+async fn watch(
+    req: WatchRequest,
+    principal: Option<&Principal>,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Reply<Response<Body>> {
+    let range = KeyRange::new(&req.key, &req.range_end, req.prefix);
+    authorize(
+        principal,
+        &state,
+        &req.namespace,
+        &range.prefix,
+        Access::Read,
+        log,
+    )
+    .await?;
+    // subscribed before responding so no change after the call is missed
+    let mut events = state.events.subscribe();
+    let (mut sender, body) = Body::channel();
+    let (ns, log) = (req.namespace, log.clone());
+    tokio::spawn(async move {
+        let status = loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    debug!(log, "watcher fell behind, ending watch"; "key" => display_key(&range.start), "missed" => n);
+                    break Status::new(Code::Aborted, "watcher fell behind");
+                }
+                Err(RecvError::Closed) => break Status::ok(),
+            };
+            // changes which don't come key by key end the watch, the watcher
+            // has to read again and start over
+            match event.kind {
+                EventKind::Restored => {
+                    break Status::new(Code::Aborted, "store restored from a snapshot")
+                }
+                EventKind::Dropped if event.ns == ns => {
+                    break Status::new(Code::Aborted, "namespace dropped")
+                }
+                _ => {}
+            }
+            if event.ns != ns || !in_range(&event.key, &range.start, range.end.as_deref()) {
+                continue;
+            }
+            let frame = frame(&watch_response(event));
+            if sender.send_data(frame.into()).await.is_err() {
+                // watcher went away
+                return;
+            }
+        };
+        let _ = sender.send_trailers(trailers(&status)).await;
+    });
+    Ok(grpc_response(body))
+}
+
+async fn member_list(
+    _req: MemberListRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<MemberListResponse> {
+    authorize(principal, state, "", b"", Access::Admin, log).await?;
+    let status = state.node.status();
+    let member = |id: &u64, learner: bool| Member {
+        id: *id,
+        learner: learner,
+        leader: *id == status.leader_id,
+        ..Default::default()
+    };
+    let voters = status.voters.iter().map(|id| member(id, false));
+    let learners = status.learners.iter().map(|id| member(id, true));
+    Ok(MemberListResponse {
+        members: voters.chain(learners).collect(),
+        ..Default::default()
+    })
+}
+
+async fn member_add(
+    req: MemberAddRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<MemberAddResponse> {
+    authorize(principal, state, "", b"", Access::Admin, log).await?;
+    if req.id == 0 {
+        return Err(Status::new(Code::InvalidArgument, "member id can't be 0"));
+    }
+    let mut cc = ConfChange::default();
+    cc.set_change_type(match req.learner {
+        true => ConfChangeType::AddLearnerNode,
+        false => ConfChangeType::AddNode,
+    });
+    cc.node_id = req.id;
+    cc.context = req.addr.into_bytes().into();
+    applied(state.node.change_members(cc).await, log)?;
+    Ok(MemberAddResponse::new())
+}
+
+async fn member_remove(
+    req: MemberRemoveRequest,
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    log: &Logger,
+) -> Reply<MemberRemoveResponse> {
+    authorize(principal, state, "", b"", Access::Admin, log).await?;
+    let mut cc = ConfChange::default();
+    cc.set_change_type(ConfChangeType::RemoveNode);
+    cc.node_id = req.id;
+    applied(state.node.change_members(cc).await, log)?;
+    Ok(MemberRemoveResponse::new())
+}
+
+// Messages from proto/fekv.proto, generated by build.rs
+#[rustfmt::skip]
+#[allow(clippy::all, warnings)]
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/fekv.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        let req = GetRequest {
+            namespace: String::from("team"),
+            key: b"foo".to_vec(),
+            ..Default::default()
+        };
+        let body = frame(&req);
+        assert_eq!(&body[..5], &[0, 0, 0, 0, 11]);
+        assert_eq!(decode::<GetRequest>(&body).unwrap(), req);
+
+        let err = decode::<GetRequest>(&body[..body.len() - 1]).unwrap_err();
+        assert_eq!(err.code, Code::InvalidArgument);
+        let mut compressed = body.clone();
+        compressed[0] = 1;
+        let err = decode::<GetRequest>(&compressed).unwrap_err();
+        assert_eq!(err.code, Code::Unimplemented);
+
+        let headers = trailers(&Status::new(Code::NotFound, "no such key 100%"));
+        assert_eq!(headers["grpc-status"], "5");
+        assert_eq!(headers["grpc-message"], "no such key 100%25");
+    }
+
+    #[test]
+    fn test_key_range() {
+        let one = KeyRange::new(b"foo", b"", false);
+        assert_eq!(one.end, Some(b"foo\0".to_vec()));
+        assert_eq!(one.prefix, b"foo");
+        assert!(one.single);
+
+        let prefix = KeyRange::new(b"app/", b"", true);
+        assert_eq!(prefix.end, Some(b"app0".to_vec()));
+        assert_eq!(prefix.prefix, b"app/");
+        assert!(!prefix.single);
+
+        let range = KeyRange::new(b"log/2023", b"log/2024", false);
+        assert_eq!(range.end, Some(b"log/2024".to_vec()));
+        assert_eq!(range.prefix, b"log/202");
+
+        let all = KeyRange::new(b"", b"", true);
+        assert_eq!((all.end, all.prefix), (None, Vec::new()));
+    }
+}
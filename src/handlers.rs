@@ -3,18 +3,93 @@
 //
 // Key functions are:
 //   - route_root(...) - helper to return the "route" from a uri
-//   - router(...) - http entrypoint which routes to other handlers as appropriate
+//   - router(...) - http entrypoint, authenticates and logs requests then
+//     dispatch(...) routes to other handlers as appropriate
 //   - hello(...) - hello world!
-//   - kv(...) - REST interface to store backend
+//   - fekv_handler(...) - REST interface to store backend, writes go through raft
+//   - raft_handler(...) - receives raft messages from peers
+//   - stats_handler(...) - json info on the backing store and raft node
+//   - metrics_handler(...) - prometheus metrics
+//   - admin_handler(...) - operator endpoints, the checksum scrubber, users,
+//     roles, role bindings and namespaces
+//
+// Requests on the gRPC listener go to grpc::dispatch(...) instead, after
+// authentication here.
 //
 
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
 use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use slog::{debug, error, info, o, warn, Logger};
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use url::Url;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use url::form_urlencoded;
+
+use crate::auth::{hash_password, Auth, Principal};
+use crate::grpc::{self, Code, Status};
+use fekv::acl::{self, Access, Role};
+use fekv::batcher::{BatchStats, Batcher};
+use fekv::checksum;
+use fekv::command::{Command, CommandError, CommandResult};
+use fekv::jsondoc;
+use fekv::kvstore::asyncstore::AsyncKVStore;
+use fekv::kvstore::watch::Event;
+use fekv::kvstore::{display_key, prefix_end, KVStorage, StoreStats, ValueInfo, ValueMeta};
+use fekv::metrics;
+use fekv::namespace::{self, Quota, Rejection};
+use fekv::raftnode::{RaftNodeHandle, RaftStatus};
+use fekv::scrubber::Scrubber;
+use fekv::transport;
+
+// which listener a request came in on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Listener {
+    Client,
+    Peer,
+    Grpc,
+}
 
-use crate::kvstore::KVStorage;
+// state shared by all requests
+pub struct ServerState<S: KVStorage> {
+    pub store: AsyncKVStore<S>,
+    pub node: RaftNodeHandle,
+    pub batcher: Batcher,
+    pub scrubber: Arc<Scrubber<S>>,
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    // reads get a 503 once the store is this many entries behind the commit
+    // index, see RaftStatus::can_serve_stale_read
+    pub stale_read_max_lag: u64,
+    pub auth: Auth,
+    // raft traffic goes to a separate (mutual TLS) peer listener
+    pub peer_listener: bool,
+    // the peer listener only accepts nodes with a certificate signed by the
+    // CA, so raft messages there need no other credentials
+    pub peer_verified: bool,
+    pub logger: Logger,
+    pub access_log: Option<Logger>,
+    pub next_request_id: AtomicU64,
+    // changes applied to the store, for grpc watches
+    pub events: broadcast::Sender<Event>,
+}
+
+impl<S: KVStorage> ServerState<S> {
+    // whether this node's store is fresh enough to read from
+    pub fn readable(&self) -> bool {
+        self.node
+            .status()
+            .can_serve_stale_read(self.stale_read_max_lag)
+    }
+}
 
 static INDEX: &[u8] =
     b"<html><head><title>fekv</title></head><body><h1>fekv</h1>A Toy Key Value store! <br /><br /> \
@@ -28,50 +103,227 @@ $ curl --fail -X 'GET' localhost:3000/fekv/foo \
 
 static OK: &[u8] = b"OK";
 
+static CLIENT_ID_HEADER: &str = "x-fekv-client-id";
+static SEQ_HEADER: &str = "x-fekv-seq";
+static REQUEST_ID_HEADER: &str = "x-request-id";
+// value metadata, see request_meta(...)
+static META_HEADER_PREFIX: &str = "x-fekv-meta-";
+static CREATED_HEADER: &str = "x-fekv-created";
+static MODIFIED_HEADER: &str = "x-fekv-modified";
+
+// for keys given with ?key_encoding=base64
+const BASE64_KEY: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+// admin request bodies are passwords or small json documents, not values
+const MAX_ADMIN_BODY_SIZE: usize = 64 * 1024;
+
+// content type and X-Fekv-Meta-* headers stored with a value
+pub const MAX_META_SIZE: usize = 8 * 1024;
+
 // route_root returns first segment of uri path
 //   for example "/foo/bar" -> "/foo", "/" -> "/", "/blah/blah?blah" -> "/blah" etc
-// also returns remainder of path and query parameters (if any)
-// TODO tests and error handling (too many unwraps!)
+// also returns remainder of path and query parameters (if any), both as sent
+// (still percent-encoded and not normalized) so keys can hold any bytes, see
+// request_key(...)
+// TODO tests
 fn route_root(req_uri: &Uri) -> (String, Option<String>, Option<String>) {
-    // url crate only parses absolute urls so use example.com as fake base for url
-    let root_url = Url::parse("http://example.com").unwrap();
-    let request_url = root_url.join(req_uri.to_string().as_str()).unwrap();
-    let path_segments: Vec<&str> = request_url.path_segments().unwrap().collect();
-    let query: Option<String> = request_url.query().map(str::to_string);
-    match path_segments.len() {
-        0 => return (String::from("/"), None, query),
-        1 => return (format!("/{}", path_segments[0]), None, query),
-        _ => {
-            let rest = path_segments.split_at(1).1;
-            return (
-                format!("/{}", path_segments[0]),
-                Some(rest.join("/")),
-                query,
-            );
+    let path = req_uri.path();
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let query: Option<String> = req_uri.query().map(str::to_string);
+    match path.split_once('/') {
+        None if path.is_empty() => (String::from("/"), None, query),
+        None => (format!("/{}", path), None, query),
+        Some((root, rest)) => (format!("/{}", root), Some(rest.to_string()), query),
+    }
+}
+
+// the key a /fekv request is for, the percent-decoded path or with
+// ?key_encoding=base64 the path as url safe base64 (padding optional), None
+// if it doesn't decode
+fn request_key(req: &Request<Body>, path: &str) -> Option<Vec<u8>> {
+    match query_param(req, "key_encoding").as_deref() {
+        None | Some("percent") => Some(percent_decode_str(path).collect()),
+        Some("base64") => BASE64_KEY.decode(path).ok(),
+        Some(_) => None,
+    }
+}
+
+// first value of a query parameter, decoded
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    let query = req.uri().query().unwrap_or("");
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// first value of a query parameter as sent, for keys which are decoded with
+// request_key(...) like paths
+fn raw_query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (n, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match n == name {
+            true => Some(value),
+            false => None,
         }
+    })
+}
+
+// metric label for a route, unknown routes are lumped together so random
+// paths don't blow up label cardinality
+fn route_label(route: &str) -> &'static str {
+    match route {
+        "/" | "/index.html" => "/",
+        "/hello" => "/hello",
+        "/fekv" => "/fekv",
+        "/raft" => "/raft",
+        "/stats" => "/stats",
+        "/metrics" => "/metrics",
+        "/admin" => "/admin",
+        "/fekv.KV" => "/fekv.KV",
+        "/fekv.Watch" => "/fekv.Watch",
+        "/fekv.Cluster" => "/fekv.Cluster",
+        _ => "other",
     }
 }
 
-// base router which calls our other handlers or returns 404
+// routes which don't need authentication
+fn public_route(route: &str) -> bool {
+    matches!(route, "/" | "/index.html" | "/hello" | "/favicon.ico")
+}
+
+// base router which authenticates, then calls dispatch(...) and records the
+// request in metrics and the access log
 pub async fn router(
-    req: Request<Body>,
+    mut req: Request<Body>,
     addr: SocketAddr,
-    store: Arc<Mutex<impl KVStorage>>,
+    listener: Listener,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
 ) -> Result<Response<Body>, hyper::Error> {
     let (route, rest, _query) = route_root(req.uri());
     let route = route.as_str();
     let rest = rest.unwrap_or(String::from(""));
 
-    if route != "/favicon.ico" {
-        println!(
-            "request: {} {} from client: {} with route: {}",
-            req.method(),
-            req.uri(),
-            addr,
-            route
-        );
+    // use the caller's request id if they sent one
+    let request_id = match req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(id) => id.to_string(),
+        None => format!(
+            "{}-{}",
+            state.node.id(),
+            state.next_request_id.fetch_add(1, Ordering::Relaxed)
+        ),
+    };
+    let mut log = state.logger.new(o!("request_id" => request_id.clone()));
+
+    let method = req.method().to_string();
+    let uri = req.uri().to_string();
+    let start = Instant::now();
+
+    // with a peer listener raft messages are only taken there, and it serves
+    // nothing else. The grpc listener only serves grpc, which always needs
+    // credentials when auth is on
+    let misrouted = match listener {
+        Listener::Peer => route != "/raft",
+        Listener::Client => state.peer_listener && route == "/raft",
+        Listener::Grpc => false,
+    };
+    // elsewhere raft messages need an admin's credentials, e.g. --peer-token
+    let public = match listener {
+        Listener::Peer => state.peer_verified,
+        Listener::Client => public_route(route),
+        Listener::Grpc => false,
+    };
+    let auth = match misrouted || public {
+        true => Ok(None),
+        false => state.auth.authenticate(&req, &state.store).await,
+    };
+    let mut principal = String::from("-");
+    let mut res = match auth {
+        _ if misrouted => {
+            debug!(log, "route not served on this listener, returning 404"; "listener" => ?listener);
+            response_404().await
+        }
+        Ok(Some(p)) if route == "/raft" && !state.auth.is_admin(&p.name) => {
+            debug!(log, "raft messages need an admin, returning 403"; "principal" => &p.name);
+            principal = p.name;
+            response_403().await
+        }
+        Ok(p) => {
+            if let Some(p) = p {
+                principal = p.name.clone();
+                log = log.new(o!("principal" => p.name.clone()));
+                req.extensions_mut().insert::<Principal>(p);
+            }
+            match listener {
+                Listener::Grpc => grpc::dispatch(req, route, rest, state.clone(), &log).await,
+                _ => dispatch(req, route, rest, state.clone(), &log).await,
+            }
+        }
+        Err(err) if listener == Listener::Grpc => {
+            debug!(log, "authentication failed, returning unauthenticated"; "error" => ?err);
+            let status = Status::new(Code::Unauthenticated, "authentication failed");
+            Ok(grpc::status_response(&status))
+        }
+        Err(err) => {
+            debug!(log, "authentication failed, returning 401"; "error" => ?err);
+            response_401(state.auth.challenge()).await
+        }
+    };
+
+    let elapsed = start.elapsed();
+    let status = match &mut res {
+        Ok(resp) => {
+            if let Ok(v) = HeaderValue::from_str(&request_id) {
+                resp.headers_mut().insert(REQUEST_ID_HEADER, v);
+            }
+            resp.status().as_u16().to_string()
+        }
+        Err(_err) => String::from("error"),
+    };
+    let labels = [route_label(route), method.as_str(), status.as_str()];
+    metrics::HTTP_REQUESTS.with_label_values(&labels).inc();
+    metrics::HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+
+    let duration_ms = elapsed.as_secs_f64() * 1000.0;
+    match &state.access_log {
+        Some(access_log) => info!(access_log, "access";
+            "request_id" => &request_id,
+            "client" => %addr,
+            "principal" => &principal,
+            "method" => &method,
+            "uri" => &uri,
+            "status" => &status,
+            "duration_ms" => duration_ms,
+        ),
+        None => debug!(log, "access";
+            "client" => %addr,
+            "method" => &method,
+            "uri" => &uri,
+            "status" => &status,
+            "duration_ms" => duration_ms,
+        ),
     }
+    res
+}
 
+// calls our other handlers or returns 404
+async fn dispatch(
+    req: Request<Body>,
+    route: &str,
+    rest: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
     match (req.method(), route) {
         (&Method::GET, "/") | (&Method::GET, "/index.html") => Ok(Response::new(INDEX.into())),
 
@@ -79,57 +331,880 @@ pub async fn router(
 
         (&Method::DELETE, "/fekv")
         | (&Method::GET, "/fekv")
+        | (&Method::HEAD, "/fekv")
+        | (&Method::PATCH, "/fekv")
         | (&Method::POST, "/fekv")
-        | (&Method::PUT, "/fekv") => fekv_handler(req, rest, store).await,
+        | (&Method::PUT, "/fekv") => fekv_handler(req, rest, state, log).await,
+
+        (&Method::POST, "/raft") => raft_handler(req, state).await,
+
+        (&Method::GET, "/stats") => stats_handler(state, log).await,
+
+        (&Method::GET, "/metrics") => metrics_handler(state).await,
+
+        (&Method::GET, "/admin")
+        | (&Method::POST, "/admin")
+        | (&Method::PUT, "/admin")
+        | (&Method::DELETE, "/admin") => admin_handler(req, rest, state, log).await,
 
         (&Method::GET, "/favicon.ico") => response_404().await,
         _ => {
-            println!("unknown route: {}, returning 404 ...", route);
+            debug!(log, "unknown route, returning 404"; "route" => route);
             response_404().await
         }
     }
 }
 
+// a value read whole, the layout of a chunked one to stream, or for HEAD
+// just its size
+enum Found {
+    Whole(Vec<u8>),
+    Chunked(ValueInfo),
+    Head(ValueInfo),
+}
+
+// the namespace a /fekv path is in and the rest of it: the first segment
+// names a namespace if one by that name exists, otherwise the whole path is a
+// key in the default namespace. Writes are split again as they're applied,
+// in case a namespace was created meanwhile
+async fn split_namespace(
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    path: String,
+) -> std::io::Result<(String, String)> {
+    if let Some((ns, rest)) = path.split_once('/') {
+        if namespace::valid_name(ns) && state.store.namespace(ns.to_string()).await?.is_some() {
+            return Ok((ns.to_string(), rest.to_string()));
+        }
+    }
+    Ok((String::from(namespace::DEFAULT), path))
+}
+
 pub async fn fekv_handler(
     req: Request<Body>,
-    key: String,
-    store: Arc<Mutex<impl KVStorage>>,
+    path: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
 ) -> Result<Response<Body>, hyper::Error> {
+    if req.method() == Method::DELETE && path.is_empty() {
+        if let Some(prefix) = raw_query_param(&req, "prefix") {
+            let prefix = prefix.to_string();
+            return delete_prefix(req, prefix, state, log).await;
+        }
+    }
+    let (ns, path) = match split_namespace(&state, path).await {
+        Ok(split) => split,
+        Err(err) => {
+            warn!(log, "reading namespaces failed, returning 503"; "error" => %err);
+            return response_503().await;
+        }
+    };
+    let key = match request_key(&req, &path) {
+        Some(key) => key,
+        None => {
+            debug!(log, "undecodable key, returning 400"; "path" => &path);
+            return response_400().await;
+        }
+    };
+    if key.len() > state.max_key_size {
+        return response_413().await;
+    }
+    let access = match req.method() {
+        &Method::GET | &Method::HEAD => Access::Read,
+        _ => Access::Write,
+    };
+    let qualified = namespace::qualified_key(&ns, &key);
+    if !authorize(&req, &state, &qualified, access, log).await {
+        return response_403().await;
+    }
+    if access == Access::Read && !state.readable() {
+        debug!(log, "store too stale to read from, returning 503");
+        return response_503().await;
+    }
     match req.method() {
-        &Method::GET => {
-            let st = store.lock().await;
-            let val = st.get(key.to_string());
-            match val {
-                Ok(val) => return Ok(Response::new(val.into())),
+        &Method::GET if query_param(&req, "path").is_some() => {
+            let path = query_param(&req, "path").unwrap_or_default();
+            project_value(state, ns, key, path, log).await
+        }
+        &Method::GET | &Method::HEAD => {
+            let timer = metrics::STORE_OP_DURATION
+                .with_label_values(&["get"])
+                .start_timer();
+            let head = req.method() == Method::HEAD;
+            let (n, k) = (ns.clone(), key.clone());
+            let now = now_ms();
+            let found = state
+                .store
+                .read(move |st| {
+                    let meta = st.get_meta(&n, &k)?;
+                    if meta.as_ref().is_some_and(|meta| meta.expired(now)) {
+                        return Err(std::io::Error::new(ErrorKind::NotFound, "value expired"));
+                    }
+                    let found = match st.value_info(&n, &k) {
+                        Ok(info) if head => Found::Head(info),
+                        // large values are streamed a chunk at a time
+                        Ok(info) if info.chunks > 1 => Found::Chunked(info),
+                        _ => Found::Whole(st.get(&n, &k)?),
+                    };
+                    Ok((found, meta))
+                })
+                .await;
+            timer.observe_duration();
+            let (mut resp, meta) = match found {
+                Ok((Found::Whole(val), meta)) => (Response::new(val.into()), meta),
+                Ok((Found::Chunked(info), meta)) => (
+                    stream_value(state.clone(), ns, key, info, log.clone()),
+                    meta,
+                ),
+                Ok((Found::Head(info), meta)) => (
+                    Response::builder()
+                        .header(CONTENT_LENGTH, info.len)
+                        .body(Body::empty())
+                        .unwrap(),
+                    meta,
+                ),
+                Err(err) if checksum::is_corruption(&err) => {
+                    error!(log, "stored value is corrupt, returning 500"; "key" => display_key(&qualified), "error" => %err);
+                    return response_500().await;
+                }
                 Err(_err) => return response_404().await,
+            };
+            if let Some(meta) = meta {
+                meta_headers(&mut resp, meta);
             }
+            Ok(resp)
+        }
+        &Method::POST if query_param(&req, "op").is_some() => {
+            let session = match client_session(&req) {
+                Ok(session) => session,
+                Err(_err) => return response_400().await,
+            };
+            let by = match query_param(&req, "by").as_deref().map(str::parse::<i64>) {
+                Some(Ok(by)) => by,
+                Some(Err(_err)) => return response_400().await,
+                None => 1,
+            };
+            let by = match query_param(&req, "op").as_deref() {
+                Some("incr") => by,
+                Some("decr") => match by.checked_neg() {
+                    Some(by) => by,
+                    None => return response_400().await,
+                },
+                _ => {
+                    debug!(log, "unknown op, returning 400"; "op" => query_param(&req, "op"));
+                    return response_400().await;
+                }
+            };
+            let cmd = Command::Incr {
+                ns: ns,
+                key: key,
+                by: by,
+                modified: now_ms(),
+            };
+            let cmd = with_session(cmd, session);
+            propose_response(state.batcher.propose(cmd).await, log).await
         }
         &Method::POST | &Method::PUT => {
-            let b = hyper::body::to_bytes(req).await.unwrap();
-            let mut st = store.try_lock_owned().unwrap();
-            let res = st.set(key.to_string(), b.to_vec());
-            match res {
-                Ok(_res) => return Ok(Response::new(OK.into())),
-                Err(_err) => return response_404().await,
+            let session = match client_session(&req) {
+                Ok(session) => session,
+                Err(_err) => return response_400().await,
+            };
+            let meta = match request_meta(&req) {
+                Some(meta) => meta,
+                None => {
+                    debug!(log, "metadata headers too large, returning 413");
+                    return response_413().await;
+                }
+            };
+            let b = match read_body(req, state.max_value_size).await? {
+                Some(b) => b,
+                None => return response_413().await,
+            };
+            // json documents have to parse so they can be patched later
+            let json = jsondoc::is_json(meta.content_type.as_deref());
+            if json && serde_json::from_slice::<serde_json::Value>(&b).is_err() {
+                debug!(log, "invalid json document, returning 400");
+                return response_400().await;
             }
+            let cmd = Command::Set {
+                ns: ns,
+                key: key,
+                value: b,
+                meta: Some(meta),
+            };
+            let cmd = with_session(cmd, session);
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        &Method::PATCH => {
+            let session = match client_session(&req) {
+                Ok(session) => session,
+                Err(_err) => return response_400().await,
+            };
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string();
+            let b = match read_body(req, state.max_value_size).await? {
+                Some(b) => b,
+                None => return response_413().await,
+            };
+            let patch = match jsondoc::parse_patch(&content_type, &b) {
+                Some(patch) => patch,
+                None if content_type.contains("patch+json") => return response_400().await,
+                None => return response_415().await,
+            };
+            let cmd = Command::Patch {
+                ns: ns,
+                key: key,
+                patch: patch,
+                modified: now_ms(),
+                max_len: Some(state.max_value_size as u64),
+            };
+            let cmd = with_session(cmd, session);
+            propose_response(state.batcher.propose(cmd).await, log).await
         }
         &Method::DELETE => {
-            let mut st = store.try_lock_owned().unwrap();
-            let res = st.delete(key.to_string());
-            match res {
-                Ok(_res) => return Ok(Response::new(OK.into())),
-                Err(_err) => return response_404().await,
-            }
+            let session = match client_session(&req) {
+                Ok(session) => session,
+                Err(_err) => return response_400().await,
+            };
+            let cmd = Command::Delete { ns: ns, key: key };
+            let cmd = with_session(cmd, session);
+            propose_response(state.batcher.propose(cmd).await, log).await
         }
         _ => {
-            println!("invalid request method: {} returning 404 ...", req.method());
-            return response_404().await;
+            warn!(log, "invalid request method, returning 404"; "method" => %req.method());
+            response_404().await
+        }
+    }
+}
+
+// DELETE /fekv?prefix=... removes every key starting with prefix, which is
+// split into a namespace and decoded (including ?key_encoding) the same way
+// a path is, in one raft command. ?dry_run=true only counts them. Either way
+// the response is the count
+async fn delete_prefix(
+    req: Request<Body>,
+    prefix: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    let (ns, prefix) = match split_namespace(&state, prefix).await {
+        Ok(split) => split,
+        Err(err) => {
+            warn!(log, "reading namespaces failed, returning 503"; "error" => %err);
+            return response_503().await;
+        }
+    };
+    let prefix = match request_key(&req, &prefix) {
+        Some(prefix) => prefix,
+        None => {
+            debug!(log, "undecodable prefix, returning 400"; "prefix" => &prefix);
+            return response_400().await;
+        }
+    };
+    if prefix.len() > state.max_key_size {
+        return response_413().await;
+    }
+    let qualified = namespace::qualified_key(&ns, &prefix);
+    if !authorize(&req, &state, &qualified, Access::Write, log).await {
+        return response_403().await;
+    }
+    let end = prefix_end(&prefix);
+    if matches!(query_param(&req, "dry_run").as_deref(), Some("true" | "1")) {
+        let count = state
+            .store
+            .read(move |st| st.count_range(&ns, &prefix, end.as_deref()))
+            .await;
+        return match count {
+            Ok(n) => Ok(Response::new(n.to_string().into())),
+            Err(err) => {
+                warn!(log, "counting keys failed, returning 503"; "error" => %err);
+                response_503().await
+            }
+        };
+    }
+    let session = match client_session(&req) {
+        Ok(session) => session,
+        Err(_err) => return response_400().await,
+    };
+    let cmd = Command::DeleteRange {
+        ns: ns,
+        start: prefix,
+        end: end,
+    };
+    let cmd = with_session(cmd, session);
+    propose_response(state.batcher.propose(cmd).await, log).await
+}
+
+// metadata to store with a write's value, its content type and X-Fekv-Meta-*
+// headers (which must be text) timestamped now, None if they're too large
+fn request_meta(req: &Request<Body>) -> Option<ValueMeta> {
+    let now = now_ms();
+    let mut meta = ValueMeta {
+        created: now,
+        modified: now,
+        ..Default::default()
+    };
+    let headers = req.headers();
+    meta.content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let mut size = meta.content_type.as_ref().map_or(0, |ct| ct.len());
+    for (name, value) in headers {
+        let name = match name.as_str().strip_prefix(META_HEADER_PREFIX) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        if let Ok(value) = value.to_str() {
+            size += name.len() + value.len();
+            meta.user.insert(name.to_string(), value.to_string());
+        }
+    }
+    match size > MAX_META_SIZE {
+        true => None,
+        false => Some(meta),
+    }
+}
+
+// timestamps for writes are taken by the proposing node
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// the part of a json document at path (a json pointer), for GET ?path=
+async fn project_value(
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    ns: String,
+    key: Vec<u8>,
+    path: String,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    let (n, k) = (ns.clone(), key.clone());
+    let found = state
+        .store
+        .read(move |st| Ok((st.get(&n, &k)?, st.get_meta(&n, &k)?)))
+        .await;
+    let (buf, meta) = match found {
+        Ok(found) => found,
+        Err(err) if checksum::is_corruption(&err) => {
+            error!(log, "stored value is corrupt, returning 500";
+                "key" => display_key(&namespace::qualified_key(&ns, &key)), "error" => %err);
+            return response_500().await;
+        }
+        Err(_err) => return response_404().await,
+    };
+    if meta.as_ref().is_some_and(|m| m.expired(now_ms())) {
+        return response_404().await;
+    }
+    let json = meta.is_some_and(|m| jsondoc::is_json(m.content_type.as_deref()));
+    let doc = match serde_json::from_slice::<serde_json::Value>(&buf) {
+        Ok(doc) if json => doc,
+        _ => return response_409().await,
+    };
+    match doc.pointer(&path) {
+        Some(part) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(part).unwrap()))
+            .unwrap()),
+        None => response_404().await,
+    }
+}
+
+// add a value's metadata to the response for it
+fn meta_headers(resp: &mut Response<Body>, meta: ValueMeta) {
+    let headers = resp.headers_mut();
+    if let Some(v) = meta
+        .content_type
+        .and_then(|ct| HeaderValue::from_str(&ct).ok())
+    {
+        headers.insert(CONTENT_TYPE, v);
+    }
+    for (name, value) in meta.user {
+        let name = HeaderName::from_bytes(format!("{}{}", META_HEADER_PREFIX, name).as_bytes());
+        if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(&value)) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(CREATED_HEADER, HeaderValue::from(meta.created));
+    headers.insert(MODIFIED_HEADER, HeaderValue::from(meta.modified));
+}
+
+// check the request's principal has access to key (or every key under a
+// prefix), there's only no principal when auth is disabled
+async fn authorize(
+    req: &Request<Body>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    key: &[u8],
+    access: Access,
+    log: &Logger,
+) -> bool {
+    let principal = req.extensions().get::<Principal>();
+    authorize_principal(principal, state, key, access, log).await
+}
+
+// authorize(...) for a principal already taken from its request
+pub async fn authorize_principal(
+    principal: Option<&Principal>,
+    state: &ServerState<impl KVStorage + Send + Sync + 'static>,
+    key: &[u8],
+    access: Access,
+    log: &Logger,
+) -> bool {
+    let principal = match principal {
+        Some(principal) => principal,
+        None => return true,
+    };
+    if state.auth.is_admin(&principal.name) {
+        return true;
+    }
+    let name = principal.name.clone();
+    let roles = state.store.read(move |st| acl::roles_for(st, &name)).await;
+    let allowed = match roles {
+        Ok(roles) => acl::allowed(&roles, key, access),
+        Err(err) => {
+            warn!(log, "loading roles failed"; "error" => %err);
+            false
+        }
+    };
+    if !allowed {
+        debug!(log, "access denied, returning 403"; "key" => display_key(key), "access" => ?access);
+    }
+    allowed
+}
+
+// buffer the request body, giving up as soon as it's known to be larger than
+// limit rather than reading it all first
+pub async fn read_body(req: Request<Body>, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > limit as u64) {
+        return Ok(None);
+    }
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+// send a chunked value, the store is only locked while reading each chunk
+fn stream_value(
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    ns: String,
+    key: Vec<u8>,
+    info: ValueInfo,
+    log: Logger,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let len = info.len;
+    tokio::spawn(async move {
+        for n in 0..info.chunks {
+            let chunk = state
+                .store
+                .get_chunk(ns.clone(), key.clone(), info.clone(), n)
+                .await;
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk.into()).await.is_err() {
+                        // client went away
+                        return;
+                    }
+                }
+                Err(err) => {
+                    warn!(log, "streaming value failed, aborting response";
+                        "key" => display_key(&namespace::qualified_key(&ns, &key)), "chunk" => n, "error" => %err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    Response::builder()
+        .header(CONTENT_LENGTH, len)
+        .body(body)
+        .unwrap()
+}
+
+// Writes may carry a client id and sequence number so retries are applied at
+// most once, both headers must be set for the request to be deduplicated
+fn client_session(req: &Request<Body>) -> Result<Option<(String, u64)>, ()> {
+    let client_id = req.headers().get(CLIENT_ID_HEADER);
+    let seq = req.headers().get(SEQ_HEADER);
+    match (client_id, seq) {
+        (None, None) => Ok(None),
+        (Some(client_id), Some(seq)) => {
+            let client_id = client_id.to_str().map_err(|_| ())?;
+            let seq = seq.to_str().map_err(|_| ())?.parse().map_err(|_| ())?;
+            Ok(Some((client_id.to_string(), seq)))
+        }
+        _ => Err(()),
+    }
+}
+
+fn with_session(cmd: Command, session: Option<(String, u64)>) -> Command {
+    match session {
+        Some((client_id, seq)) => Command::Session {
+            client_id: client_id,
+            seq: seq,
+            cmd: Box::new(cmd),
+        },
+        None => cmd,
+    }
+}
+
+async fn propose_response(
+    res: CommandResult,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    match res {
+        CommandResult::Done(_res) => Ok(Response::new(OK.into())),
+        CommandResult::Value(n) => Ok(Response::new(n.to_string().into())),
+        CommandResult::Deleted(n) => Ok(Response::new(n.to_string().into())),
+        CommandResult::Batch(_res) => Ok(Response::new(OK.into())),
+        CommandResult::Txn { .. } => Ok(Response::new(OK.into())),
+        CommandResult::Failed(err) => {
+            warn!(log, "proposal failed, returning 503"; "error" => err);
+            response_503().await
+        }
+        CommandResult::Rejected(rejection) => {
+            debug!(log, "proposal rejected"; "reason" => %rejection);
+            match rejection {
+                Rejection::NoSuchNamespace(_) => response_404().await,
+                Rejection::InvalidNamespace(_) => response_400().await,
+                Rejection::TooManyNamespaces | Rejection::NamespaceInUse(_) => response_409().await,
+                Rejection::QuotaExceeded(_) => response_507().await,
+            }
+        }
+        CommandResult::Refused(err) => {
+            debug!(log, "proposal refused"; "reason" => %err);
+            match err {
+                CommandError::NoSuchKey => response_404().await,
+                CommandError::NotJson | CommandError::PatchFailed(_) => response_409().await,
+                CommandError::TooLarge => response_413().await,
+                CommandError::NotInteger | CommandError::Overflow => response_409().await,
+            }
+        }
+    }
+}
+
+// raft messages from other nodes, protobuf encoded by transport::HttpTransport
+pub async fn raft_handler(
+    req: Request<Body>,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+) -> Result<Response<Body>, hyper::Error> {
+    let b = hyper::body::to_bytes(req).await?;
+    match transport::decode(&b) {
+        Ok(msg) => {
+            state.node.step(msg);
+            Ok(Response::new(OK.into()))
+        }
+        Err(_err) => response_400().await,
+    }
+}
+
+#[derive(Serialize)]
+struct Stats {
+    store: StoreStats,
+    raft: RaftStatus,
+    batches: BatchStats,
+}
+
+pub async fn stats_handler(
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    let store = state.store.stats().await;
+    let store = match store {
+        Ok(store) => store,
+        Err(err) => {
+            warn!(log, "store stats failed, returning 503"; "error" => %err);
+            return response_503().await;
+        }
+    };
+    let stats = Stats {
+        store: store,
+        raft: state.node.status(),
+        batches: state.batcher.metrics(),
+    };
+    let body = serde_json::to_vec_pretty(&stats).unwrap();
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+pub async fn metrics_handler(
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+) -> Result<Response<Body>, hyper::Error> {
+    // store size gauges are only refreshed when scraped
+    let stats = state.store.stats().await;
+    if let Ok(stats) = stats {
+        metrics::STORE_KEYS.set(stats.keys as i64);
+        if let Some(lmdb) = stats.lmdb {
+            metrics::LMDB_MAP_SIZE.set(lmdb.map_size as i64);
+            metrics::LMDB_FILE_SIZE.set(lmdb.file_size as i64);
+        }
+    }
+    Ok(Response::builder()
+        .header("content-type", metrics::content_type())
+        .body(Body::from(metrics::encode()))
+        .unwrap())
+}
+
+// Needs admin access on every key:
+//   GET /admin/scrub returns the last scrubber report, POST runs a pass now
+//   PUT /admin/users/{name} with the password as the body adds or updates a
+//   basic auth user, DELETE removes one
+//   GET/PUT/DELETE /admin/roles/{name} with a json acl::Role body
+//   GET/PUT/DELETE /admin/bindings/{principal} with a json list of role names
+//   GET /admin/namespaces lists namespaces with their quotas and usage,
+//   GET/PUT/DELETE /admin/namespaces/{name} with an optional json
+//   namespace::Quota body, deleting a namespace deletes its keys
+//   POST /admin/learners/{id} promotes a learner to a voter, from the leader
+//   once the learner has caught up, otherwise 409
+pub async fn admin_handler(
+    req: Request<Body>,
+    action: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    if !authorize(&req, &state, b"", Access::Admin, log).await {
+        return response_403().await;
+    }
+    let (action, name) = match action.split_once('/') {
+        Some((action, name)) => (action.to_string(), name.to_string()),
+        None => (action, String::new()),
+    };
+    match (req.method(), action.as_str()) {
+        (&Method::GET, "scrub") => json_response(&state.scrubber.report()),
+        (&Method::POST, "scrub") => json_response(&state.scrubber.scrub().await),
+        (_, "users") if !name.is_empty() => users_handler(req, name, state, log).await,
+        (_, "roles") if !name.is_empty() => roles_handler(req, name, state, log).await,
+        (_, "bindings") if !name.is_empty() => bindings_handler(req, name, state, log).await,
+        (&Method::GET, "namespaces") if name.is_empty() => {
+            match state.store.read(|st| st.namespaces()).await {
+                Ok(namespaces) => json_response(&namespaces),
+                Err(err) => {
+                    warn!(log, "reading namespaces failed, returning 500"; "error" => %err);
+                    response_500().await
+                }
+            }
+        }
+        (_, "namespaces") if !name.is_empty() => namespaces_handler(req, name, state, log).await,
+        (&Method::POST, "learners") if !name.is_empty() => {
+            let id = match name.parse::<u64>() {
+                Ok(id) => id,
+                Err(_err) => return response_400().await,
+            };
+            match state.node.promote_learner(id).await {
+                CommandResult::Done(false) => {
+                    debug!(log, "learner not promoted, returning 409"; "id" => id);
+                    response_409().await
+                }
+                res => propose_response(res, log).await,
+            }
+        }
+        _ => response_404().await,
+    }
+}
+
+async fn users_handler(
+    req: Request<Body>,
+    name: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    match req.method() {
+        &Method::PUT | &Method::POST => {
+            let password = match read_body(req, MAX_ADMIN_BODY_SIZE).await? {
+                Some(b) => b,
+                None => return response_413().await,
+            };
+            let password = match String::from_utf8(password) {
+                Ok(password) if !password.is_empty() => password,
+                _ => return response_400().await,
+            };
+            // argon2 is slow on purpose, keep it off the async workers
+            let hash = tokio::task::spawn_blocking(move || hash_password(&password)).await;
+            let hash = match hash {
+                Ok(Ok(hash)) => hash,
+                Ok(Err(err)) => {
+                    error!(log, "hashing password failed, returning 500"; "error" => err);
+                    return response_500().await;
+                }
+                Err(err) => {
+                    error!(log, "hashing password failed, returning 500"; "error" => %err);
+                    return response_500().await;
+                }
+            };
+            let cmd = Command::PutUser {
+                name: name,
+                password_hash: hash,
+            };
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        &Method::DELETE => {
+            let cmd = Command::DeleteUser { name: name };
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        _ => response_404().await,
+    }
+}
+
+async fn roles_handler(
+    req: Request<Body>,
+    name: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    match req.method() {
+        &Method::GET => {
+            let role = state.store.read(move |st| acl::get_role(st, &name)).await;
+            match role {
+                Ok(Some(role)) => json_response(&role),
+                Ok(None) => response_404().await,
+                Err(err) => {
+                    warn!(log, "reading role failed, returning 500"; "error" => %err);
+                    response_500().await
+                }
+            }
+        }
+        &Method::PUT | &Method::POST => {
+            let role = match read_json::<Role>(req).await? {
+                Ok(role) => role,
+                Err(res) => return res,
+            };
+            let cmd = Command::PutRole {
+                name: name,
+                role: role,
+            };
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        &Method::DELETE => {
+            let cmd = Command::DeleteRole { name: name };
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        _ => response_404().await,
+    }
+}
+
+async fn bindings_handler(
+    req: Request<Body>,
+    principal: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    let roles = match req.method() {
+        &Method::GET => {
+            let bindings = state
+                .store
+                .read(move |st| acl::get_bindings(st, &principal))
+                .await;
+            return match bindings {
+                Ok(roles) => json_response(&roles),
+                Err(err) => {
+                    warn!(log, "reading bindings failed, returning 500"; "error" => %err);
+                    response_500().await
+                }
+            };
+        }
+        &Method::PUT | &Method::POST => match read_json::<Vec<String>>(req).await? {
+            Ok(roles) => roles,
+            Err(res) => return res,
+        },
+        &Method::DELETE => Vec::new(),
+        _ => return response_404().await,
+    };
+    let cmd = Command::SetBindings {
+        principal: principal,
+        roles: roles,
+    };
+    propose_response(state.batcher.propose(cmd).await, log).await
+}
+
+async fn namespaces_handler(
+    req: Request<Body>,
+    name: String,
+    state: Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+    log: &Logger,
+) -> Result<Response<Body>, hyper::Error> {
+    if !namespace::valid_name(&name) {
+        return response_400().await;
+    }
+    match req.method() {
+        &Method::GET => match state.store.namespace(name).await {
+            Ok(Some(ns)) => json_response(&ns),
+            Ok(None) => response_404().await,
+            Err(err) => {
+                warn!(log, "reading namespace failed, returning 500"; "error" => %err);
+                response_500().await
+            }
+        },
+        &Method::PUT | &Method::POST => {
+            let body = match read_body(req, MAX_ADMIN_BODY_SIZE).await? {
+                Some(body) => body,
+                None => return response_413().await,
+            };
+            // no body for a namespace without quotas
+            let quota = match body.is_empty() {
+                true => Ok(Quota::default()),
+                false => serde_json::from_slice::<Quota>(&body),
+            };
+            let quota = match quota {
+                Ok(quota) => quota,
+                Err(_err) => return response_400().await,
+            };
+            let cmd = Command::PutNamespace {
+                name: name,
+                quota: quota,
+            };
+            propose_response(state.batcher.propose(cmd).await, log).await
+        }
+        &Method::DELETE => {
+            let cmd = Command::DropNamespace { name: name };
+            propose_response(state.batcher.propose(cmd).await, log).await
         }
+        _ => response_404().await,
     }
 }
 
+// parse a small json admin request body, or the error response to send
+async fn read_json<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<Result<T, Result<Response<Body>, hyper::Error>>, hyper::Error> {
+    let body = match read_body(req, MAX_ADMIN_BODY_SIZE).await? {
+        Some(body) => body,
+        None => return Ok(Err(response_413().await)),
+    };
+    match serde_json::from_slice(&body) {
+        Ok(value) => Ok(Ok(value)),
+        Err(_err) => Ok(Err(response_400().await)),
+    }
+}
+
+fn json_response(value: &impl Serialize) -> Result<Response<Body>, hyper::Error> {
+    let body = serde_json::to_vec_pretty(value).unwrap();
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap())
+}
+
 pub async fn hello(_req: Request<Body>, name: String) -> Result<Response<Body>, hyper::Error> {
-    if name != "" {
+    if !name.is_empty() {
         return Ok(Response::new(Body::from(format!("Hello {}!", name))));
     }
     Ok(Response::new(Body::from("Hello World!")))
@@ -140,3 +1215,300 @@ pub async fn response_404() -> Result<Response<Body>, hyper::Error> {
     *not_found.status_mut() = StatusCode::NOT_FOUND;
     Ok(not_found)
 }
+
+pub async fn response_400() -> Result<Response<Body>, hyper::Error> {
+    let mut bad_request = Response::default();
+    *bad_request.status_mut() = StatusCode::BAD_REQUEST;
+    Ok(bad_request)
+}
+
+pub async fn response_401(challenge: &str) -> Result<Response<Body>, hyper::Error> {
+    Ok(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, challenge)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn response_403() -> Result<Response<Body>, hyper::Error> {
+    let mut forbidden = Response::default();
+    *forbidden.status_mut() = StatusCode::FORBIDDEN;
+    Ok(forbidden)
+}
+
+pub async fn response_409() -> Result<Response<Body>, hyper::Error> {
+    let mut conflict = Response::default();
+    *conflict.status_mut() = StatusCode::CONFLICT;
+    Ok(conflict)
+}
+
+pub async fn response_413() -> Result<Response<Body>, hyper::Error> {
+    let mut too_large = Response::default();
+    *too_large.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    Ok(too_large)
+}
+
+pub async fn response_415() -> Result<Response<Body>, hyper::Error> {
+    let mut unsupported = Response::default();
+    *unsupported.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    Ok(unsupported)
+}
+
+pub async fn response_500() -> Result<Response<Body>, hyper::Error> {
+    let mut internal_error = Response::default();
+    *internal_error.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    Ok(internal_error)
+}
+
+pub async fn response_503() -> Result<Response<Body>, hyper::Error> {
+    let mut unavailable = Response::default();
+    *unavailable.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    Ok(unavailable)
+}
+
+pub async fn response_507() -> Result<Response<Body>, hyper::Error> {
+    let mut insufficient_storage = Response::default();
+    *insufficient_storage.status_mut() = StatusCode::INSUFFICIENT_STORAGE;
+    Ok(insufficient_storage)
+}
+
+// a lone node's state over an in memory store, its raft log in dir, for
+// tests driving the listeners. The node's threads run until the test exits
+#[cfg(test)]
+pub fn test_state(
+    auth: Auth,
+    dir: &std::path::Path,
+) -> Arc<ServerState<fekv::kvstore::memstore::MemKVStore>> {
+    use fekv::kvstore::memstore::MemKVStore;
+    use fekv::raftnode::RaftNode;
+    use fekv::raftstore::RaftDiskStorage;
+    use std::time::Duration;
+
+    let logger = Logger::root(slog::Discard, o!());
+    let store = Arc::new(tokio::sync::RwLock::new(MemKVStore::new()));
+    let storage = RaftDiskStorage::new_with_db_path(dir);
+    storage.initialize_with_conf_state((vec![1], vec![]));
+    let cfg = raft::Config {
+        id: 1,
+        election_tick: 10,
+        heartbeat_tick: 3,
+        ..Default::default()
+    };
+    let scrubber = Arc::new(Scrubber::new(store.clone(), storage.clone(), &logger));
+    let handle = RaftNode::spawn(&cfg, storage, store.clone(), |_| {}, &logger)
+        .unwrap()
+        .handle();
+    Arc::new(ServerState {
+        store: AsyncKVStore::new(store),
+        node: handle.clone(),
+        batcher: Batcher::spawn(handle, 16, Duration::from_millis(1)),
+        scrubber: scrubber,
+        max_key_size: 64,
+        max_value_size: 64,
+        stale_read_max_lag: 100,
+        auth: auth,
+        peer_listener: false,
+        peer_verified: false,
+        logger: logger,
+        access_log: None,
+        next_request_id: AtomicU64::new(1),
+        events: broadcast::channel(16).0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthProvider;
+    use std::collections::{HashMap, HashSet};
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    // root is an admin, alice only has the roles bound to her
+    fn test_auth() -> Auth {
+        let tokens = HashMap::from([
+            (String::from("root-token"), String::from("root")),
+            (String::from("alice-token"), String::from("alice")),
+        ]);
+        Auth::new(
+            vec![AuthProvider::Tokens(tokens)],
+            HashSet::from([String::from("root")]),
+        )
+    }
+
+    // send a request through router(...) as who, returning its status and body
+    async fn call(
+        state: &Arc<ServerState<impl KVStorage + Send + Sync + 'static>>,
+        method: Method,
+        uri: &str,
+        who: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(who) = who {
+            req = req.header("authorization", format!("Bearer {}-token", who));
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let res = router(req, addr, Listener::Client, state.clone())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_router_acl() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = test_state(test_auth(), tmp.path());
+
+        // public routes need no credentials, everything else does
+        let (status, _) = call(&state, Method::GET, "/hello", None, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::GET, "/fekv/pub/x", None, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&state, Method::GET, "/fekv/pub/x", Some("bob"), "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // alice can read pub/ once she's bound to a role which allows it
+        let (status, _) = call(&state, Method::GET, "/fekv/pub/x", Some("alice"), "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let role = r#"{"permissions": [{"prefix": "pub/", "access": "read"}]}"#;
+        let (status, _) = call(
+            &state,
+            Method::PUT,
+            "/admin/roles/reader",
+            Some("root"),
+            role,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(
+            &state,
+            Method::PUT,
+            "/admin/bindings/alice",
+            Some("root"),
+            r#"["reader"]"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::PUT, "/fekv/pub/x", Some("root"), "hi").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&state, Method::GET, "/fekv/pub/x", Some("alice"), "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hi"));
+
+        // but not write there, read elsewhere or use the admin endpoints
+        let (status, _) = call(&state, Method::PUT, "/fekv/pub/x", Some("alice"), "no").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, Method::GET, "/fekv/private", Some("alice"), "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(
+            &state,
+            Method::GET,
+            "/admin/roles/reader",
+            Some("alice"),
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_router_limits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = test_state(test_auth(), tmp.path());
+        let root = Some("root");
+
+        let value = "x".repeat(state.max_value_size);
+        let (status, _) = call(&state, Method::PUT, "/fekv/big", root, &value).await;
+        assert_eq!(status, StatusCode::OK);
+        let value = "x".repeat(state.max_value_size + 1);
+        let (status, _) = call(&state, Method::PUT, "/fekv/big", root, &value).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let key = format!("/fekv/{}", "k".repeat(state.max_key_size + 1));
+        let (status, _) = call(&state, Method::PUT, &key, root, "v").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (_status, body) = call(&state, Method::GET, "/fekv/big", root, "").await;
+        assert_eq!(body.len(), state.max_value_size);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_router_admin() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = test_state(test_auth(), tmp.path());
+        let root = Some("root");
+
+        // namespaces
+        let (status, _) = call(&state, Method::GET, "/admin/namespaces/team", root, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&state, Method::PUT, "/admin/namespaces/Bad!", root, "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let quota = r#"{"max_keys": 1, "max_bytes": null}"#;
+        let (status, _) = call(&state, Method::PUT, "/admin/namespaces/team", root, quota).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::PUT, "/fekv/team/a", root, "1").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::PUT, "/fekv/team/b", root, "1").await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        let (status, body) = call(&state, Method::GET, "/admin/namespaces", root, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let namespaces: Vec<namespace::Namespace> = serde_json::from_str(&body).unwrap();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(
+            (namespaces[0].name.as_str(), namespaces[0].keys),
+            ("team", 1)
+        );
+        let (status, _) = call(&state, Method::DELETE, "/admin/namespaces/team", root, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::GET, "/admin/namespaces/team", root, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // users, roles and bindings
+        let (status, _) = call(&state, Method::PUT, "/admin/users/carol", root, "pw").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::GET, "/admin/roles/missing", root, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&state, Method::PUT, "/admin/roles/bad", root, "{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = call(&state, Method::GET, "/admin/bindings/carol", root, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "[]"));
+
+        // the scrubber, and learners, of which there are none
+        let (status, _) = call(&state, Method::POST, "/admin/scrub", root, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&state, Method::GET, "/admin/scrub", root, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with('{'));
+        let (status, _) = call(&state, Method::POST, "/admin/learners/2", root, "").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = call(&state, Method::POST, "/admin/learners/x", root, "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&state, Method::GET, "/admin/nothing", root, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_prefix_param() {
+        // bytes which aren't utf-8 survive, + isn't a space in keys
+        let req = request("/fekv?dry_run=true&prefix=team/a%FF+b");
+        let prefix = raw_query_param(&req, "prefix").unwrap();
+        assert_eq!(prefix, "team/a%FF+b");
+        assert_eq!(request_key(&req, prefix), Some(b"team/a\xff+b".to_vec()));
+        assert_eq!(raw_query_param(&req, "dry_run"), Some("true"));
+        assert_eq!(raw_query_param(&req, "missing"), None);
+
+        let req = request("/fekv?prefix=_w&key_encoding=base64");
+        let prefix = raw_query_param(&req, "prefix").unwrap();
+        assert_eq!(request_key(&req, prefix), Some(b"\xff".to_vec()));
+        let req = request("/fekv?prefix&key_encoding=base64");
+        assert_eq!(raw_query_param(&req, "prefix"), Some(""));
+        let req = request("/fekv?prefix=!&key_encoding=base64");
+        assert_eq!(
+            request_key(&req, raw_query_param(&req, "prefix").unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//
+// JSON document values
+//
+// Values written with a json content type (see kvstore::ValueMeta) are json
+// documents, which can be updated in place with Command::Patch rather than
+// read, modified and written back. Patches are RFC 7396 merge patches or RFC
+// 6902 JSON Patches, applied as a whole or not at all. Paths are RFC 6901
+// JSON pointers.
+//
+// Copy ops can double a document each, so apply(...) stops as soon as the
+// document could be over the size limit, and JSON Patches have at most
+// MAX_PATCH_OPS ops.
+//
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub const MERGE_PATCH_TYPE: &str = "application/merge-patch+json";
+pub const JSON_PATCH_TYPE: &str = "application/json-patch+json";
+pub const MAX_PATCH_OPS: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Patch {
+    Merge(Value),
+    Json(Vec<PatchOp>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    // an op didn't apply, and why
+    Failed(String),
+    // the patched document would be over the size limit
+    TooLarge,
+}
+
+// application/json or any +json type, ignoring parameters such as charset
+pub fn is_json(content_type: Option<&str>) -> bool {
+    let essence = match content_type {
+        Some(ct) => ct
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase(),
+        None => return false,
+    };
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+// parse a PATCH request body by its content type, None for types which
+// aren't patches or bodies which don't parse
+pub fn parse_patch(content_type: &str, body: &[u8]) -> Option<Patch> {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    if essence.eq_ignore_ascii_case(MERGE_PATCH_TYPE) {
+        return serde_json::from_slice(body).ok().map(Patch::Merge);
+    }
+    if essence.eq_ignore_ascii_case(JSON_PATCH_TYPE) {
+        let ops: Vec<PatchOp> = serde_json::from_slice(body).ok()?;
+        return match ops.len() <= MAX_PATCH_OPS {
+            true => Some(Patch::Json(ops)),
+            false => None,
+        };
+    }
+    None
+}
+
+// the patched document, doc is left alone if any part of the patch fails or
+// it would serialize to more than max_len bytes
+pub fn apply(doc: &Value, patch: &Patch, max_len: usize) -> Result<Value, PatchError> {
+    let mut doc = doc.clone();
+    match patch {
+        Patch::Merge(patch) => merge_patch(&mut doc, patch),
+        Patch::Json(ops) if ops.len() > MAX_PATCH_OPS => {
+            return Err(PatchError::Failed(format!(
+                "more than {} ops",
+                MAX_PATCH_OPS
+            )))
+        }
+        Patch::Json(ops) => {
+            // removals aren't counted, so this only overestimates
+            let mut len = json_len(&doc);
+            for op in ops {
+                len = len.saturating_add(apply_op(&mut doc, op).map_err(PatchError::Failed)?);
+                if len > max_len {
+                    return Err(PatchError::TooLarge);
+                }
+            }
+        }
+    }
+    match json_len(&doc) > max_len {
+        true => Err(PatchError::TooLarge),
+        false => Ok(doc),
+    }
+}
+
+// serialized length of a value, without keeping the bytes
+fn json_len(value: &Value) -> usize {
+    struct Count(usize);
+    impl std::io::Write for Count {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut count = Count(0);
+    let _ = serde_json::to_writer(&mut count, value);
+    count.0
+}
+
+// RFC 7396 section 2
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (name, value) in patch {
+            if value.is_null() {
+                target.remove(name);
+            } else {
+                merge_patch(target.entry(name.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+// applies op, returning at most how many bytes it grew the document by
+fn apply_op(doc: &mut Value, op: &PatchOp) -> Result<usize, String> {
+    match op {
+        PatchOp::Add { path, value } => {
+            add(doc, &tokens(path)?, value.clone())?;
+            Ok(json_len(value) + path.len())
+        }
+        PatchOp::Remove { path } => remove(doc, &tokens(path)?).map(|_| 0),
+        PatchOp::Replace { path, value } => {
+            *walk(doc, &tokens(path)?)? = value.clone();
+            Ok(json_len(value))
+        }
+        PatchOp::Move { from, path } => {
+            let (from, to) = (tokens(from)?, tokens(path)?);
+            if to.len() > from.len() && to.starts_with(&from) {
+                return Err(format!("can't move {} into itself", path));
+            }
+            let value = remove(doc, &from)?;
+            add(doc, &to, value)?;
+            Ok(path.len())
+        }
+        PatchOp::Copy { from, path } => {
+            let value = walk(doc, &tokens(from)?)?;
+            let len = json_len(value);
+            let value = value.clone();
+            add(doc, &tokens(path)?, value)?;
+            Ok(len + path.len())
+        }
+        PatchOp::Test { path, value } => match *walk(doc, &tokens(path)?)? == *value {
+            true => Ok(0),
+            false => Err(format!("test failed at {}", path)),
+        },
+    }
+}
+
+// RFC 6901 reference tokens of a pointer, "" is the whole document
+fn tokens(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    match pointer.strip_prefix('/') {
+        Some(rest) => Ok(rest
+            .split('/')
+            .map(|t| t.replace("~1", "/").replace("~0", "~"))
+            .collect()),
+        None => Err(format!("invalid path {}", pointer)),
+    }
+}
+
+// array indexes are digits without leading zeros
+fn index(token: &str) -> Option<usize> {
+    let digits = !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit());
+    match digits && (token == "0" || !token.starts_with('0')) {
+        true => token.parse().ok(),
+        false => None,
+    }
+}
+
+fn walk<'a>(doc: &'a mut Value, tokens: &[String]) -> Result<&'a mut Value, String> {
+    let mut cur = doc;
+    for token in tokens {
+        let next = match cur {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(arr) => index(token).and_then(move |i| arr.get_mut(i)),
+            _ => None,
+        };
+        cur = next.ok_or_else(|| format!("no value at /{}", tokens.join("/")))?;
+    }
+    Ok(cur)
+}
+
+fn add(doc: &mut Value, tokens: &[String], value: Value) -> Result<(), String> {
+    let (last, parent) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *doc = value;
+            return Ok(());
+        }
+    };
+    match walk(doc, parent)? {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+            Ok(())
+        }
+        Value::Array(arr) if last == "-" => {
+            arr.push(value);
+            Ok(())
+        }
+        Value::Array(arr) => match index(last) {
+            Some(i) if i <= arr.len() => {
+                arr.insert(i, value);
+                Ok(())
+            }
+            _ => Err(format!("index {} out of bounds", last)),
+        },
+        _ => Err(format!("can't add to /{}", parent.join("/"))),
+    }
+}
+
+fn remove(doc: &mut Value, tokens: &[String]) -> Result<Value, String> {
+    let (last, parent) = match tokens.split_last() {
+        Some(split) => split,
+        None => return Err(String::from("can't remove the whole document")),
+    };
+    let removed = match walk(doc, parent)? {
+        Value::Object(map) => map.remove(last),
+        Value::Array(arr) => match index(last) {
+            Some(i) if i < arr.len() => Some(arr.remove(i)),
+            _ => None,
+        },
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("no value at /{}", tokens.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_patch() {
+        // from RFC 7396 section 3
+        let doc = json!({
+            "title": "Goodbye!",
+            "author": {"givenName": "John", "familyName": "Doe"},
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged"
+        });
+        let patch = json!({
+            "title": "Hello!",
+            "phoneNumber": "+01-123-456-7890",
+            "author": {"familyName": null},
+            "tags": ["example"]
+        });
+        let patched = apply(&doc, &Patch::Merge(patch), usize::MAX).unwrap();
+        assert_eq!(
+            patched,
+            json!({
+                "title": "Hello!",
+                "author": {"givenName": "John"},
+                "tags": ["example"],
+                "content": "This will be unchanged",
+                "phoneNumber": "+01-123-456-7890"
+            })
+        );
+
+        let mut doc = json!([1]);
+        merge_patch(&mut doc, &json!({"a": {"b": "c"}}));
+        assert_eq!(doc, json!({"a": {"b": "c"}}));
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let doc = json!({"foo": ["bar", "baz"], "a/b": 1});
+        let ops: Vec<PatchOp> = serde_json::from_value(json!([
+            {"op": "test", "path": "/a~1b", "value": 1},
+            {"op": "add", "path": "/foo/1", "value": "qux"},
+            {"op": "add", "path": "/foo/-", "value": "end"},
+            {"op": "remove", "path": "/foo/0"},
+            {"op": "replace", "path": "/a~1b", "value": 2},
+            {"op": "copy", "from": "/foo", "path": "/copied"},
+            {"op": "move", "from": "/copied/0", "path": "/moved"}
+        ]))
+        .unwrap();
+        let patched = apply(&doc, &Patch::Json(ops), usize::MAX).unwrap();
+        assert_eq!(
+            patched,
+            json!({
+                "foo": ["qux", "baz", "end"],
+                "a/b": 2,
+                "copied": ["baz", "end"],
+                "moved": "qux"
+            })
+        );
+
+        // a failing op leaves the document as it was
+        let ops: Vec<PatchOp> = serde_json::from_value(json!([
+            {"op": "add", "path": "/new", "value": true},
+            {"op": "test", "path": "/a~1b", "value": 3}
+        ]))
+        .unwrap();
+        assert!(apply(&doc, &Patch::Json(ops), usize::MAX).is_err());
+
+        for op in [
+            json!({"op": "remove", "path": "/missing"}),
+            json!({"op": "replace", "path": "/foo/2", "value": 0}),
+            json!({"op": "add", "path": "/foo/01", "value": 0}),
+            json!({"op": "add", "path": "no-slash", "value": 0}),
+            json!({"op": "move", "from": "/foo", "path": "/foo/0"}),
+        ] {
+            let op: PatchOp = serde_json::from_value(op).unwrap();
+            assert!(apply(&doc, &Patch::Json(vec![op]), usize::MAX).is_err());
+        }
+    }
+
+    #[test]
+    fn test_patch_limits() {
+        // each copy doubles the document
+        let doc = json!({"a": ["0123456789"]});
+        let ops: Vec<PatchOp> = (0..64)
+            .map(|_| PatchOp::Copy {
+                from: String::from("/a"),
+                path: String::from("/a/0"),
+            })
+            .collect();
+        let patch = Patch::Json(ops);
+        assert_eq!(apply(&doc, &patch, 4096), Err(PatchError::TooLarge));
+
+        let merge = Patch::Merge(json!({"b": "0123456789"}));
+        assert_eq!(apply(&doc, &merge, 20), Err(PatchError::TooLarge));
+        assert!(apply(&doc, &merge, 64).is_ok());
+
+        let ops = vec![
+            PatchOp::Test {
+                path: String::from("/a/0"),
+                value: json!("0123456789"),
+            };
+            MAX_PATCH_OPS + 1
+        ];
+        assert!(matches!(
+            apply(&doc, &Patch::Json(ops.clone()), usize::MAX),
+            Err(PatchError::Failed(_))
+        ));
+        let body = serde_json::to_vec(&ops).unwrap();
+        assert_eq!(parse_patch(JSON_PATCH_TYPE, &body), None);
+    }
+
+    #[test]
+    fn test_content_types() {
+        assert!(is_json(Some("application/json")));
+        assert!(is_json(Some("Application/JSON; charset=utf-8")));
+        assert!(is_json(Some("application/vnd.api+json")));
+        assert!(!is_json(Some("text/plain")));
+        assert!(!is_json(None));
+
+        let merge = parse_patch(MERGE_PATCH_TYPE, br#"{"a": null}"#);
+        assert_eq!(merge, Some(Patch::Merge(json!({"a": null}))));
+        let ops = parse_patch(JSON_PATCH_TYPE, br#"[{"op": "remove", "path": "/a"}]"#);
+        assert_eq!(
+            ops,
+            Some(Patch::Json(vec![PatchOp::Remove {
+                path: String::from("/a")
+            }]))
+        );
+        assert_eq!(parse_patch("application/json", b"{}"), None);
+        assert_eq!(parse_patch(JSON_PATCH_TYPE, b"{}"), None);
+    }
+}
@@ -0,0 +1,121 @@
+//
+// Async adapter for a shared KVStorage
+//
+// KVStorage is synchronous and lmdb calls can block on disk, so calling it
+// from a tokio worker stalls every other connection on that worker. Reads
+// through AsyncKVStore run on tokio's blocking pool instead, under a read lock
+// of the same RwLock the raft node applies writes through.
+//
+
+use std::io::{Error, Result};
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use super::{KVStorage, StoreStats, ValueInfo};
+use crate::namespace::Namespace;
+
+pub struct AsyncKVStore<S> {
+    store: Arc<RwLock<S>>,
+}
+
+impl<S> Clone for AsyncKVStore<S> {
+    fn clone(&self) -> Self {
+        AsyncKVStore {
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<S: KVStorage + Send + Sync + 'static> AsyncKVStore<S> {
+    pub fn new(store: Arc<RwLock<S>>) -> AsyncKVStore<S> {
+        AsyncKVStore { store: store }
+    }
+
+    // the underlying store, for the raft node and scrubber threads
+    pub fn shared(&self) -> Arc<RwLock<S>> {
+        self.store.clone()
+    }
+
+    // run f against the store on the blocking pool, for reads needing more
+    // than one call to agree
+    pub async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&*store.blocking_read()))
+            .await
+            .unwrap_or_else(|err| Err(Error::other(err.to_string())))
+    }
+
+    pub async fn get(&self, ns: String, key: Vec<u8>) -> Result<Vec<u8>> {
+        self.read(move |st| st.get(&ns, &key)).await
+    }
+
+    pub async fn get_user(&self, name: String) -> Result<Option<Vec<u8>>> {
+        self.read(move |st| st.get_user(&name)).await
+    }
+
+    pub async fn stats(&self) -> Result<StoreStats> {
+        self.read(|st| st.stats()).await
+    }
+
+    pub async fn namespace(&self, name: String) -> Result<Option<Namespace>> {
+        self.read(move |st| st.namespace(&name)).await
+    }
+
+    pub async fn value_info(&self, ns: String, key: Vec<u8>) -> Result<ValueInfo> {
+        self.read(move |st| st.value_info(&ns, &key)).await
+    }
+
+    pub async fn get_chunk(
+        &self,
+        ns: String,
+        key: Vec<u8>,
+        info: ValueInfo,
+        n: u32,
+    ) -> Result<Vec<u8>> {
+        self.read(move |st| st.get_chunk(&ns, &key, &info, n)).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.read(|st| st.sync()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::memstore::MemKVStore;
+
+    #[tokio::test]
+    async fn test_async_kvstore() {
+        let store = AsyncKVStore::new(Arc::new(RwLock::new(MemKVStore::new())));
+        store
+            .shared()
+            .write()
+            .await
+            .set("", b"foo", b"bar".to_vec())
+            .unwrap();
+
+        let ns = String::new();
+        assert_eq!(
+            store.get(ns.clone(), b"foo".to_vec()).await.unwrap(),
+            b"bar"
+        );
+        assert!(store.get(ns.clone(), b"missing".to_vec()).await.is_err());
+        let info = store.value_info(ns.clone(), b"foo".to_vec()).await.unwrap();
+        assert_eq!(info.len, 3);
+        let chunk = store.get_chunk(ns.clone(), b"foo".to_vec(), info, 0).await;
+        assert_eq!(chunk.unwrap(), b"bar");
+        assert_eq!(store.stats().await.unwrap().keys, 1);
+        assert_eq!(store.namespace(String::from("team")).await.unwrap(), None);
+
+        let both = store
+            .read(|st| Ok((st.get("", b"foo")?, st.applied_index()?)))
+            .await;
+        assert_eq!(both.unwrap(), (b"bar".to_vec(), 0));
+    }
+}
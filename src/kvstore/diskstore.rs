@@ -1,90 +1,1236 @@
 //
 // Disk backed KVStorage implementation using heed
 //
+// Values and sessions are stored with a crc32c prefix (see checksum::seal)
+// which is verified on every read, databases from before that are sealed on
+// open.
+//
+// Keys are arbitrary bytes, databases from before that are migrated on open.
+//
+// Values larger than chunk_size are split across records in the chunks db,
+// keyed by "{key}/{checksum}/{n}", with a ValueInfo manifest for the key in
+// the chunked db. Including the value's checksum in the chunk keys means a
+// reader streaming chunks never mixes two versions of a value.
+//
+// Values' metadata (see ValueMeta) is kept in the value_meta db under the same
+// key, values stored without any have no record there.
+//
+// Range deletes walk each database with a cursor over the range, deleting as
+// they go, all in one write transaction.
+//
+// Each write is a transaction of its own, or with KVStorage::atomic one nested
+// in a TxnStore's transaction, which commits all of them together.
+//
+// Named namespaces each get their own db, chunked, chunks and value_meta
+// databases, with their usage and quota in the namespaces db. Those are a
+// slot's databases, named "slot/{n}" and "slot/{n}/{db}", with the slot in
+// the meta db under "slot/{name}". A dropped namespace's databases are
+// emptied and its slot goes to the next namespace created, lmdb can't close
+// them before restart so there are only ever MAX_NAMESPACES slots open.
+//
 
-use std::fs::create_dir_all;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, metadata};
 use std::io::{Error, ErrorKind, Result};
+use std::ops::Bound;
 use std::path::Path;
 use std::vec::Vec;
 
-use heed::types::{ByteSlice, Str};
-use heed::{Database, Env, EnvOpenOptions};
+use heed::types::{ByteSlice, OwnedType, Str};
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
 
-use super::KVStorage;
+use super::{
+    counter_meta, display_key, incremented, legacy_key, unexpired, KVStorage, LmdbStats,
+    StoreStats, ValueInfo, ValueMeta,
+};
+use crate::checksum;
+use crate::namespace::{self, Namespace, Quota, Rejection, MAX_NAMESPACES};
 
 const DB_PATH: &str = "./data";
 const DB_NAME: &str = "fekv.mdb";
+const DB_META: &str = "meta";
+const META_APPLIED_INDEX: &str = "applied_index";
+// keys are raw bytes, see DiskKVStore::migrate_keys
+const META_KEY_FORMAT: &str = "key_format";
+const KEY_FORMAT_BYTES: u64 = 1;
+// values and sessions are sealed, see DiskKVStore::seal_values
+const META_VALUE_FORMAT: &str = "value_format";
+const VALUE_FORMAT_SEALED: u64 = 1;
+// namespace name -> its slot, see Keyspace::create
+const META_SLOT: &str = "slot/";
+const DB_SESSIONS: &str = "sessions";
+const DB_CHUNKS: &str = "chunks";
+const DB_CHUNKED: &str = "chunked";
+const DB_VALUE_META: &str = "value_meta";
+const DB_USERS: &str = "users";
+const DB_ACL: &str = "acl";
+const DB_NAMESPACES: &str = "namespaces";
+// the databases above, and four for each namespace
+const MAX_DBS: u32 = 9 + 4 * MAX_NAMESPACES as u32;
 const DB_STORE_SIZE: usize = 1_073_741_824;
+const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+// lmdb's default max key size is 511 bytes, less the "/{checksum}/{n}"
+// suffix of chunk keys
+pub const MAX_KEY_SIZE: usize = 511 - 20;
 
 pub struct DiskKVStore {
     env: Env,
-    db: Database<Str, ByteSlice>,
+    db: Database<ByteSlice, ByteSlice>,
+    meta: Database<Str, OwnedType<u64>>,
+    sessions: Database<Str, ByteSlice>,
+    chunks: Database<ByteSlice, ByteSlice>,
+    chunked: Database<ByteSlice, ByteSlice>,
+    value_meta: Database<ByteSlice, ByteSlice>,
+    users: Database<Str, ByteSlice>,
+    acl: Database<Str, ByteSlice>,
+    // name -> namespace::Namespace
+    namespaces: Database<Str, ByteSlice>,
+    keyspaces: HashMap<String, Keyspace>,
+    chunk_size: usize,
+}
+
+// the databases holding one namespace's keys
+#[derive(Clone, Copy)]
+struct Keyspace {
+    db: Database<ByteSlice, ByteSlice>,
+    chunked: Database<ByteSlice, ByteSlice>,
+    chunks: Database<ByteSlice, ByteSlice>,
+    value_meta: Database<ByteSlice, ByteSlice>,
+}
+
+fn heed_err(err: heed::Error) -> Error {
+    Error::other(err.to_string())
+}
+
+type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+fn key_range<'a>(start: &'a [u8], end: Option<&'a [u8]>) -> KeyRange<'a> {
+    (
+        Bound::Included(start),
+        end.map_or(Bound::Unbounded, Bound::Excluded),
+    )
+}
+
+fn slot_key(name: &str) -> String {
+    format!("{}{}", META_SLOT, name)
+}
+
+fn chunk_key(key: &[u8], info: &ValueInfo, n: u32) -> Vec<u8> {
+    let mut ck = key.to_vec();
+    ck.extend(format!("/{:08x}/{:010}", info.checksum, n).into_bytes());
+    ck
 }
 
 impl DiskKVStore {
-    pub fn new() -> DiskKVStore {
-        let db_path = Path::join(Path::new(&DB_PATH), &DB_NAME);
-        _ = create_dir_all(&db_path);
+    pub fn new() -> Result<DiskKVStore> {
+        DiskKVStore::new_with_db_path(Path::new(&DB_PATH))
+    }
+
+    // fails if the environment can't be opened or the one-time upgrades of
+    // its contents (seal_values, migrate_keys) don't go through
+    pub fn new_with_db_path(db_path: &Path) -> Result<DiskKVStore> {
+        let db_path = Path::join(db_path, DB_NAME);
+        create_dir_all(&db_path)?;
         let env = EnvOpenOptions::new()
             .map_size(DB_STORE_SIZE) // 10MB
-            .max_dbs(3)
+            .max_dbs(MAX_DBS)
             .open(db_path)
-            .unwrap();
-        let db = env.create_database(Some(&DB_NAME)).unwrap();
-        DiskKVStore { env: env, db: db }
+            .map_err(heed_err)?;
+        let db = env.create_database(Some(DB_NAME)).map_err(heed_err)?;
+        let meta = env.create_database(Some(DB_META)).map_err(heed_err)?;
+        let sessions = env.create_database(Some(DB_SESSIONS)).map_err(heed_err)?;
+        let chunks = env.create_database(Some(DB_CHUNKS)).map_err(heed_err)?;
+        let chunked = env.create_database(Some(DB_CHUNKED)).map_err(heed_err)?;
+        let value_meta = env.create_database(Some(DB_VALUE_META)).map_err(heed_err)?;
+        let users = env.create_database(Some(DB_USERS)).map_err(heed_err)?;
+        let acl = env.create_database(Some(DB_ACL)).map_err(heed_err)?;
+        let namespaces = env.create_database(Some(DB_NAMESPACES)).map_err(heed_err)?;
+        let mut store = DiskKVStore {
+            env: env,
+            db: db,
+            meta: meta,
+            sessions: sessions,
+            chunks: chunks,
+            chunked: chunked,
+            value_meta: value_meta,
+            users: users,
+            acl: acl,
+            namespaces: namespaces,
+            keyspaces: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        };
+        store.seal_values()?;
+        store.migrate_keys()?;
+        store.open_keyspaces()?;
+        Ok(store)
+    }
+
+    fn open_keyspaces(&mut self) -> Result<()> {
+        let env = self.env.clone();
+        let mut wtxn = env.write_txn().map_err(heed_err)?;
+        self.load_keyspaces(&mut wtxn)?;
+        wtxn.commit().map_err(heed_err)
+    }
+
+    fn load_keyspaces(&mut self, wtxn: &mut RwTxn) -> Result<()> {
+        let mut names = Vec::new();
+        for item in self.namespaces.iter(wtxn).map_err(heed_err)? {
+            let (name, _val) = item.map_err(heed_err)?;
+            names.push(name.to_string());
+        }
+        for name in names {
+            let slot = match self.meta.get(wtxn, &slot_key(&name)).map_err(heed_err)? {
+                Some(slot) => slot,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("namespace {} has no slot", name),
+                    ))
+                }
+            };
+            let ks = Keyspace::create(&self.env, wtxn, slot)?;
+            self.keyspaces.insert(name, ks);
+        }
+        Ok(())
+    }
+
+    // after a transaction which created or dropped namespaces didn't commit
+    fn reopen_keyspaces(&mut self) {
+        self.keyspaces.clear();
+        let _ = self.open_keyspaces();
+    }
+
+    fn keyspace(&self, ns: &str) -> Result<Keyspace> {
+        if ns == namespace::DEFAULT {
+            return Ok(Keyspace {
+                db: self.db,
+                chunked: self.chunked,
+                chunks: self.chunks,
+                value_meta: self.value_meta,
+            });
+        }
+        match self.keyspaces.get(ns) {
+            Some(ks) => Ok(*ks),
+            None => Err(namespace::rejected(Rejection::NoSuchNamespace(
+                ns.to_string(),
+            ))),
+        }
+    }
+
+    fn namespace_info(&self, rtxn: &RoTxn, name: &str) -> Result<Option<Namespace>> {
+        match self.namespaces.get(rtxn, name).map_err(heed_err)? {
+            Some(buf) => {
+                let buf = checksum::unseal(buf)?;
+                let info = serde_json::from_slice(buf)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+                Ok(Some(info))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put_namespace_info(&self, wtxn: &mut RwTxn, info: &Namespace) -> Result<()> {
+        let buf = serde_json::to_vec(info).unwrap();
+        self.namespaces
+            .put(wtxn, &info.name, &checksum::seal(&buf))
+            .map_err(heed_err)
+    }
+
+    // count key's value becoming new bytes long (None for deleted) against
+    // its namespace, the default namespace isn't tracked
+    fn charge(
+        &self,
+        wtxn: &mut RwTxn,
+        ns: &str,
+        ks: &Keyspace,
+        key: &[u8],
+        new: Option<u64>,
+    ) -> Result<()> {
+        if ns == namespace::DEFAULT {
+            return Ok(());
+        }
+        let mut info = match self.namespace_info(wtxn, ns)? {
+            Some(info) => info,
+            None => {
+                return Err(namespace::rejected(Rejection::NoSuchNamespace(
+                    ns.to_string(),
+                )))
+            }
+        };
+        let old = ks.stored_len(wtxn, key)?;
+        info.charge(key, old, new)?;
+        self.put_namespace_info(wtxn, &info)
+    }
+
+    // store buf as key's value in wtxn, replacing any metadata with meta
+    fn write_value(
+        &self,
+        wtxn: &mut RwTxn,
+        ns: &str,
+        ks: &Keyspace,
+        key: &[u8],
+        buf: &[u8],
+        meta: Option<ValueMeta>,
+    ) -> Result<()> {
+        self.charge(wtxn, ns, ks, key, Some(buf.len() as u64))?;
+        match meta {
+            Some(meta) => {
+                // damaged metadata is replaced, losing the created time
+                let old = ks.get_meta(wtxn, key).unwrap_or(None);
+                let meta = meta.replacing(old, buf.len() as u64);
+                let meta = serde_json::to_vec(&meta).unwrap();
+                ks.value_meta
+                    .put(wtxn, key, &checksum::seal(&meta))
+                    .map_err(heed_err)?;
+            }
+            None => {
+                ks.value_meta.delete(wtxn, key).map_err(heed_err)?;
+            }
+        }
+        ks.remove_chunks(wtxn, key)?;
+        if buf.len() > self.chunk_size {
+            let info = ValueInfo {
+                len: buf.len() as u64,
+                chunks: buf.len().div_ceil(self.chunk_size) as u32,
+                checksum: checksum::checksum(buf),
+            };
+            for (n, chunk) in buf.chunks(self.chunk_size).enumerate() {
+                let ck = chunk_key(key, &info, n as u32);
+                ks.chunks
+                    .put(wtxn, &ck, &checksum::seal(chunk))
+                    .map_err(heed_err)?;
+            }
+            let manifest = serde_json::to_vec(&info).unwrap();
+            ks.chunked
+                .put(wtxn, key, &checksum::seal(&manifest))
+                .map_err(heed_err)?;
+            ks.db.delete(wtxn, key).map_err(heed_err)?;
+        } else {
+            ks.db
+                .put(wtxn, key, &checksum::seal(buf))
+                .map_err(heed_err)?;
+        }
+        Ok(())
+    }
+
+    // Values and sessions used to be stored bare, seal them once per database
+    // so reads can verify them. Has to run before migrate_keys, whose marker
+    // is only ever written alongside sealed values. Databases from between
+    // checksums and that marker are sealed already, values there which
+    // verify are left alone (a bare value passes for a sealed one with odds
+    // of 1 in 2^32). Returns how many values were sealed
+    pub fn seal_values(&mut self) -> Result<u64> {
+        let mut wtxn = self.env.write_txn().map_err(heed_err)?;
+        let format = self.meta.get(&wtxn, META_VALUE_FORMAT).map_err(heed_err)?;
+        if format.unwrap_or(0) >= VALUE_FORMAT_SEALED {
+            return Ok(0);
+        }
+        let mut sealed = 0;
+        let keys_migrated = self.meta.get(&wtxn, META_KEY_FORMAT).map_err(heed_err)?;
+        if keys_migrated.is_none() {
+            let sessions = self.sessions.remap_key_type::<ByteSlice>();
+            for db in [self.db, sessions] {
+                let mut bare = Vec::new();
+                for item in db.iter(&wtxn).map_err(heed_err)? {
+                    let (key, val) = item.map_err(heed_err)?;
+                    if !checksum::verify(val) {
+                        bare.push((key.to_vec(), checksum::seal(val)));
+                    }
+                }
+                for (key, val) in bare {
+                    db.put(&mut wtxn, &key, &val).map_err(heed_err)?;
+                    sealed += 1;
+                }
+            }
+        }
+        self.meta
+            .put(&mut wtxn, META_VALUE_FORMAT, &VALUE_FORMAT_SEALED)
+            .map_err(heed_err)?;
+        wtxn.commit().map_err(heed_err)?;
+        Ok(sealed)
+    }
+
+    // Keys used to be strings holding the percent-encoded request path, rewrite
+    // them to the bytes they stand for, once per database. Where two old keys
+    // decode to the same bytes the one already in decoded form (or else the
+    // first moved) is kept. Every replica migrates the same way so they still
+    // agree. Returns how many keys were moved
+    pub fn migrate_keys(&mut self) -> Result<u64> {
+        let mut wtxn = self.env.write_txn().map_err(heed_err)?;
+        let format = self.meta.get(&wtxn, META_KEY_FORMAT).map_err(heed_err)?;
+        if format.unwrap_or(0) >= KEY_FORMAT_BYTES {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        let ks = self.keyspace(namespace::DEFAULT)?;
+        for db in [self.db, self.chunked] {
+            let mut legacy = Vec::new();
+            for item in db.iter(&wtxn).map_err(heed_err)? {
+                let (key, _val) = item.map_err(heed_err)?;
+                if let Ok(old) = std::str::from_utf8(key) {
+                    let new = legacy_key(old);
+                    if new != key {
+                        legacy.push((key.to_vec(), new));
+                    }
+                }
+            }
+            for (old, new) in legacy {
+                let taken = self.db.get(&wtxn, &new).map_err(heed_err)?.is_some()
+                    || self.chunked.get(&wtxn, &new).map_err(heed_err)?.is_some();
+                if taken {
+                    ks.remove_chunks(&mut wtxn, &old)?;
+                    db.delete(&mut wtxn, &old).map_err(heed_err)?;
+                    self.chunked.delete(&mut wtxn, &old).map_err(heed_err)?;
+                    continue;
+                }
+                if let Ok(Some(info)) = ks.chunked_info(&wtxn, &old) {
+                    for n in 0..info.chunks {
+                        let (from, to) = (chunk_key(&old, &info, n), chunk_key(&new, &info, n));
+                        if let Some(chunk) = self.chunks.get(&wtxn, &from).map_err(heed_err)? {
+                            let chunk = chunk.to_vec();
+                            self.chunks.put(&mut wtxn, &to, &chunk).map_err(heed_err)?;
+                            self.chunks.delete(&mut wtxn, &from).map_err(heed_err)?;
+                        }
+                    }
+                }
+                if let Some(val) = db.get(&wtxn, &old).map_err(heed_err)? {
+                    let val = val.to_vec();
+                    db.put(&mut wtxn, &new, &val).map_err(heed_err)?;
+                    db.delete(&mut wtxn, &old).map_err(heed_err)?;
+                    moved += 1;
+                }
+            }
+        }
+        self.meta
+            .put(&mut wtxn, META_KEY_FORMAT, &KEY_FORMAT_BYTES)
+            .map_err(heed_err)?;
+        wtxn.commit().map_err(heed_err)?;
+        Ok(moved)
+    }
+
+    // values larger than chunk_size are stored as several records
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
     }
 }
 
-impl KVStorage for DiskKVStore {
-    fn get(&self, key: String) -> Result<Vec<u8>> {
-        let rtxn = self.env.read_txn().unwrap();
-        let r = self.db.get(&rtxn, &key);
+impl Keyspace {
+    // slots are below MAX_NAMESPACES, so MAX_DBS is never reached
+    fn create(env: &Env, wtxn: &mut RwTxn, slot: u64) -> Result<Keyspace> {
+        let db_name = format!("slot/{}", slot);
+        let chunked_name = format!("slot/{}/{}", slot, DB_CHUNKED);
+        let chunks_name = format!("slot/{}/{}", slot, DB_CHUNKS);
+        let value_meta_name = format!("slot/{}/{}", slot, DB_VALUE_META);
+        Ok(Keyspace {
+            db: env
+                .create_database_with_txn(Some(&db_name), wtxn)
+                .map_err(heed_err)?,
+            chunked: env
+                .create_database_with_txn(Some(&chunked_name), wtxn)
+                .map_err(heed_err)?,
+            chunks: env
+                .create_database_with_txn(Some(&chunks_name), wtxn)
+                .map_err(heed_err)?,
+            value_meta: env
+                .create_database_with_txn(Some(&value_meta_name), wtxn)
+                .map_err(heed_err)?,
+        })
+    }
+
+    fn get_meta(&self, rtxn: &RoTxn, key: &[u8]) -> Result<Option<ValueMeta>> {
+        match self.value_meta.get(rtxn, key).map_err(heed_err)? {
+            Some(buf) => {
+                let buf = checksum::unseal(buf)?;
+                let meta = serde_json::from_slice(buf)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+                Ok(Some(meta))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // length of key's value, if it has one, without reading every chunk
+    fn stored_len(&self, rtxn: &RoTxn, key: &[u8]) -> Result<Option<u64>> {
+        if let Ok(Some(info)) = self.chunked_info(rtxn, key) {
+            return Ok(Some(info.len));
+        }
+        let sealed = self.db.get(rtxn, key).map_err(heed_err)?;
+        Ok(sealed.map(|buf| buf.len().saturating_sub(checksum::CHECKSUM_LEN) as u64))
+    }
+
+    // key's whole value, None if it has none
+    fn read_value(&self, rtxn: &RoTxn, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(info) = self.chunked_info(rtxn, key)? {
+            let mut buf = Vec::with_capacity(info.len as usize);
+            for n in 0..info.chunks {
+                buf.extend(self.read_chunk(rtxn, key, &info, n)?);
+            }
+            return Ok(Some(buf));
+        }
+        match self.db.get(rtxn, key).map_err(heed_err)? {
+            Some(sealed) => checksum::unseal(sealed).map(|v| Some(v.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    fn chunked_info(&self, rtxn: &RoTxn, key: &[u8]) -> Result<Option<ValueInfo>> {
+        match self.chunked.get(rtxn, key).map_err(heed_err)? {
+            Some(buf) => {
+                let buf = checksum::unseal(buf)?;
+                let info = serde_json::from_slice(buf)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+                Ok(Some(info))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_chunk(&self, rtxn: &RoTxn, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        match self.chunks.get(rtxn, &chunk_key(key, info, n)) {
+            Ok(Some(buf)) => checksum::unseal(buf).map(|c| c.to_owned()),
+            // overwritten or deleted since info was read
+            Ok(None) => Err(Error::new(ErrorKind::NotFound, "value changed")),
+            Err(err) => Err(heed_err(err)),
+        }
+    }
+
+    // drop a chunked value's manifest and chunks, returns false if key
+    // wasn't chunked
+    fn remove_chunks(&self, wtxn: &mut RwTxn, key: &[u8]) -> Result<bool> {
+        let info = match self.chunked_info(wtxn, key) {
+            Ok(Some(info)) => info,
+            Ok(None) => return Ok(false),
+            // a damaged manifest is still removed, its chunks are orphaned
+            Err(_err) => {
+                self.chunked.delete(wtxn, key).map_err(heed_err)?;
+                return Ok(true);
+            }
+        };
+        for n in 0..info.chunks {
+            self.chunks
+                .delete(wtxn, &chunk_key(key, &info, n))
+                .map_err(heed_err)?;
+        }
+        self.chunked.delete(wtxn, key).map_err(heed_err)?;
+        Ok(true)
+    }
+}
+
+// Reads take the transaction to read in, so the store's own read transactions
+// and an open TxnStore's write transaction share them
+impl DiskKVStore {
+    fn get_in(&self, rtxn: &RoTxn, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let ks = self.keyspace(ns)?;
+        if let Some(info) = ks.chunked_info(rtxn, key)? {
+            let mut buf = Vec::with_capacity(info.len as usize);
+            for n in 0..info.chunks {
+                buf.extend(ks.read_chunk(rtxn, key, &info, n)?);
+            }
+            return Ok(buf);
+        }
+        let r = ks.db.get(rtxn, key);
         match r {
             Ok(ro) => match ro {
-                Some(ro) => Ok(ro.to_owned()),
+                Some(ro) => checksum::unseal(ro).map(|v| v.to_owned()),
                 None => {
-                    let err = Error::new(ErrorKind::Other, "no key");
-                    return Err(err);
+                    let err = Error::other("no key");
+                    Err(err)
                 }
             },
             Err(err) => {
-                let err = Error::new(ErrorKind::Other, err.to_string());
-                return Err(err);
+                let err = Error::other(err.to_string());
+                Err(err)
             }
         }
     }
 
-    fn set(&mut self, key: String, buf: Vec<u8>) -> Result<bool> {
-        let mut wtxn = self.env.write_txn().unwrap();
-        let r = self.db.put(&mut wtxn, &key, &buf);
-        if r.is_err() {
-            let err = r.unwrap_err();
-            return Err(Error::new(ErrorKind::Other, err.to_string()));
+    fn get_meta_in(&self, rtxn: &RoTxn, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        let ks = self.keyspace(ns)?;
+        ks.get_meta(rtxn, key)
+    }
+
+    fn count_range_in(
+        &self,
+        rtxn: &RoTxn,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<u64> {
+        let ks = self.keyspace(ns)?;
+        let range = key_range(start, end);
+        let mut count = 0;
+        for db in [ks.db, ks.chunked] {
+            for item in db.range(rtxn, &range).map_err(heed_err)? {
+                item.map_err(heed_err)?;
+                count += 1;
+            }
         }
-        let r = wtxn.commit();
-        match r {
-            Ok(_r) => return Ok(true),
+        Ok(count)
+    }
+
+    // chunked and whole values are in different databases, both in key order
+    fn range_keys_in(
+        &self,
+        rtxn: &RoTxn,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let ks = self.keyspace(ns)?;
+        let range = key_range(start, end);
+        let mut found = Vec::new();
+        for db in [ks.db, ks.chunked] {
+            for item in db.range(rtxn, &range).map_err(heed_err)?.take(limit) {
+                let (key, _val) = item.map_err(heed_err)?;
+                found.push(key.to_vec());
+            }
+        }
+        found.sort();
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    fn namespaces_in(&self, rtxn: &RoTxn) -> Result<Vec<Namespace>> {
+        let mut namespaces = Vec::new();
+        for name in self.keyspaces.keys() {
+            if let Some(info) = self.namespace_info(rtxn, name)? {
+                namespaces.push(info);
+            }
+        }
+        namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(namespaces)
+    }
+
+    fn applied_index_in(&self, rtxn: &RoTxn) -> Result<u64> {
+        match self.meta.get(rtxn, META_APPLIED_INDEX) {
+            Ok(idx) => Ok(idx.unwrap_or(0)),
+            Err(err) => Err(Error::other(err.to_string())),
+        }
+    }
+
+    fn get_session_in(&self, rtxn: &RoTxn, client_id: &str) -> Result<Option<Vec<u8>>> {
+        match self.sessions.get(rtxn, client_id) {
+            Ok(Some(session)) => checksum::unseal(session).map(|s| Some(s.to_owned())),
+            Ok(None) => Ok(None),
+            Err(err) => Err(Error::other(err.to_string())),
+        }
+    }
+
+    fn get_user_in(&self, rtxn: &RoTxn, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.users.get(rtxn, name).map_err(heed_err)? {
+            Some(user) => checksum::unseal(user).map(|u| Some(u.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    fn get_acl_in(&self, rtxn: &RoTxn, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.acl.get(rtxn, key).map_err(heed_err)? {
+            Some(buf) => checksum::unseal(buf).map(|b| Some(b.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    fn value_info_in(&self, rtxn: &RoTxn, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        let ks = self.keyspace(ns)?;
+        if let Some(info) = ks.chunked_info(rtxn, key)? {
+            return Ok(info);
+        }
+        match ks.db.get(rtxn, key).map_err(heed_err)? {
+            Some(sealed) => {
+                let buf = checksum::unseal(sealed)?;
+                Ok(ValueInfo {
+                    len: buf.len() as u64,
+                    chunks: 1,
+                    checksum: checksum::checksum(buf),
+                })
+            }
+            None => Err(Error::new(ErrorKind::NotFound, "no key")),
+        }
+    }
+
+    fn get_chunk_in(
+        &self,
+        rtxn: &RoTxn,
+        ns: &str,
+        key: &[u8],
+        info: &ValueInfo,
+        n: u32,
+    ) -> Result<Vec<u8>> {
+        let ks = self.keyspace(ns)?;
+        if info.chunks > 1 {
+            return ks.read_chunk(rtxn, key, info, n);
+        }
+        // small values are stored whole
+        match ks.db.get(rtxn, key).map_err(heed_err)? {
+            Some(sealed) => {
+                let buf = checksum::unseal(sealed)?;
+                if n != 0 || checksum::checksum(buf) != info.checksum {
+                    return Err(Error::new(ErrorKind::NotFound, "value changed"));
+                }
+                Ok(buf.to_owned())
+            }
+            None => Err(Error::new(ErrorKind::NotFound, "value changed")),
+        }
+    }
+
+    // run f in a write transaction of its own, committed if f succeeds
+    fn write<T>(&mut self, f: impl FnOnce(&mut TxnStore) -> Result<T>) -> Result<T> {
+        let env = self.env.clone();
+        let wtxn = env.write_txn().map_err(heed_err)?;
+        let mut txn = TxnStore {
+            store: self,
+            env: &env,
+            wtxn: wtxn,
+            keyspaces_changed: false,
+        };
+        match f(&mut txn) {
+            Ok(res) => txn.commit().map(|()| res),
+            Err(err) => {
+                txn.abort();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl KVStorage for DiskKVStore {
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_in(&rtxn, ns, key)
+    }
+
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        self.write(|txn| txn.set_with_meta(ns, key, buf, meta))
+    }
+
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        self.write(|txn| txn.incr(ns, key, by, modified))
+    }
+
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        self.write(|txn| txn.delete_range(ns, start, end))
+    }
+
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.count_range_in(&rtxn, ns, start, end)
+    }
+
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.range_keys_in(&rtxn, ns, start, end, limit)
+    }
+
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        self.write(|txn| txn.delete(ns, key))
+    }
+
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_meta_in(&rtxn, ns, key)
+    }
+
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        self.write(|txn| txn.put_namespace(name, quota))
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        self.write(|txn| txn.drop_namespace(name))
+    }
+
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.namespaces_in(&rtxn)
+    }
+
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.namespace_info(&rtxn, name)
+    }
+
+    fn applied_index(&self) -> Result<u64> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.applied_index_in(&rtxn)
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        self.write(|txn| txn.set_applied_index(index))
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_session_in(&rtxn, client_id)
+    }
+
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        self.write(|txn| txn.set_session(client_id, session))
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_user_in(&rtxn, name)
+    }
+
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        self.write(|txn| txn.set_user(name, user))
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        self.write(|txn| txn.delete_user(name))
+    }
+
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_acl_in(&rtxn, key)
+    }
+
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        self.write(|txn| txn.set_acl(key, buf))
+    }
+
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        self.write(|txn| txn.delete_acl(key))
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let namespaces = self.namespaces()?;
+        let rtxn = self.env.read_txn().unwrap();
+        let entries = self.db.len(&rtxn).map_err(heed_err)?;
+        let chunked = self.chunked.len(&rtxn).map_err(heed_err)?;
+        let file_size = metadata(self.env.path().join("data.mdb"))?.len();
+        Ok(StoreStats {
+            backend: String::from("lmdb"),
+            keys: entries + chunked + namespaces.iter().map(|ns| ns.keys).sum::<u64>(),
+            lmdb: Some(LmdbStats {
+                map_size: DB_STORE_SIZE as u64,
+                file_size: file_size,
+            }),
+            namespaces: namespaces,
+        })
+    }
+
+    fn scrub(&self) -> Result<Vec<String>> {
+        let rtxn = self.env.read_txn().unwrap();
+        let mut corrupt = Vec::new();
+        // session keys and chunks are reported with a prefix so they can't be
+        // confused with regular keys, keys in namespaces with the namespace
+        let mut dbs = vec![
+            (self.db, String::new()),
+            (self.chunked, String::new()),
+            (self.chunks, String::from("chunk/")),
+            (self.value_meta, String::from("meta/")),
+            (
+                self.sessions.remap_key_type::<ByteSlice>(),
+                String::from("session/"),
+            ),
+            (
+                self.users.remap_key_type::<ByteSlice>(),
+                String::from("user/"),
+            ),
+            (self.acl.remap_key_type::<ByteSlice>(), String::from("acl/")),
+            (
+                self.namespaces.remap_key_type::<ByteSlice>(),
+                String::from("namespace/"),
+            ),
+        ];
+        for (name, ks) in &self.keyspaces {
+            dbs.push((ks.db, format!("{}/", name)));
+            dbs.push((ks.chunked, format!("{}/", name)));
+            dbs.push((ks.chunks, format!("chunk/{}/", name)));
+            dbs.push((ks.value_meta, format!("meta/{}/", name)));
+        }
+        for (db, prefix) in dbs {
+            let iter = db
+                .iter(&rtxn)
+                .map_err(|err| Error::other(err.to_string()))?;
+            for item in iter {
+                let (key, val) = item.map_err(|err| Error::other(err.to_string()))?;
+                if !checksum::verify(val) {
+                    corrupt.push(format!("{}{}", prefix, display_key(key)));
+                }
+            }
+        }
+        Ok(corrupt)
+    }
+
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.value_info_in(&rtxn, ns, key)
+    }
+
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        let rtxn = self.env.read_txn().map_err(heed_err)?;
+        self.get_chunk_in(&rtxn, ns, key, info, n)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.env.force_sync().map_err(heed_err)
+    }
+
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        self.write(|txn| f(txn))
+    }
+}
+
+// A DiskKVStore with a write transaction open, see KVStorage::atomic. Reads
+// see the transaction's writes, and each write runs in a nested transaction
+// so one which fails partway leaves nothing behind
+struct TxnStore<'a> {
+    store: &'a mut DiskKVStore,
+    env: &'a Env,
+    wtxn: RwTxn<'a, 'a>,
+    // namespaces were created or dropped, the store's keyspaces have to be
+    // reopened if the transaction doesn't commit
+    keyspaces_changed: bool,
+}
+
+impl TxnStore<'_> {
+    fn write<T>(&mut self, f: impl FnOnce(&DiskKVStore, &mut RwTxn) -> Result<T>) -> Result<T> {
+        let mut child = self
+            .env
+            .nested_write_txn(&mut self.wtxn)
+            .map_err(heed_err)?;
+        let res = f(self.store, &mut child)?;
+        child.commit().map_err(heed_err)?;
+        Ok(res)
+    }
+
+    fn commit(self) -> Result<()> {
+        let (store, changed) = (self.store, self.keyspaces_changed);
+        match self.wtxn.commit() {
+            Ok(()) => Ok(()),
             Err(err) => {
-                let err = Error::new(ErrorKind::Other, err.to_string());
-                return Err(err);
+                if changed {
+                    store.reopen_keyspaces();
+                }
+                Err(heed_err(err))
             }
         }
     }
 
-    fn delete(&mut self, key: String) -> Result<bool> {
-        let mut wtxn = self.env.write_txn().unwrap();
-        let r = self.db.delete(&mut wtxn, &key);
-        if r.is_err() {
-            let err = r.unwrap_err();
-            return Err(Error::new(ErrorKind::Other, err.to_string()));
+    fn abort(self) {
+        let (store, changed) = (self.store, self.keyspaces_changed);
+        drop(self.wtxn);
+        if changed {
+            store.reopen_keyspaces();
         }
-        let deleted = r.unwrap();
-        let r = wtxn.commit();
-        if r.is_err() {
-            let err = r.unwrap_err();
-            return Err(Error::new(ErrorKind::Other, err.to_string()));
+    }
+}
+
+impl KVStorage for TxnStore<'_> {
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        self.store.get_in(&self.wtxn, ns, key)
+    }
+
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        self.write(|store, wtxn| {
+            let ks = store.keyspace(ns)?;
+            store.write_value(wtxn, ns, &ks, key, &buf, meta)?;
+            Ok(true)
+        })
+    }
+
+    // the read and write share one write transaction, so no other write can
+    // land between them
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        self.write(|store, wtxn| {
+            let ks = store.keyspace(ns)?;
+            let old = ks.read_value(wtxn, key)?;
+            let meta = ks.get_meta(wtxn, key).unwrap_or(None);
+            // an expired counter starts again from 0
+            let (old, meta) = unexpired(old, meta, modified);
+            let n = incremented(old.as_deref(), by)?;
+            let meta = counter_meta(meta, modified);
+            let buf = n.to_string().into_bytes();
+            store.write_value(wtxn, ns, &ks, key, &buf, Some(meta))?;
+            Ok(n)
+        })
+    }
+
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        self.write(|store, wtxn| {
+            let ks = store.keyspace(ns)?;
+            let range = key_range(start, end);
+            let mut info = match ns == namespace::DEFAULT {
+                true => None,
+                false => store.namespace_info(wtxn, ns)?,
+            };
+            let mut deleted = 0;
+
+            // chunked values go with their chunks, which are keyed differently
+            let mut chunked = Vec::new();
+            for item in ks.chunked.range(wtxn, &range).map_err(heed_err)? {
+                let (key, _manifest) = item.map_err(heed_err)?;
+                chunked.push(key.to_vec());
+            }
+            for key in chunked {
+                let len = ks.stored_len(wtxn, &key)?;
+                if let Some(info) = &mut info {
+                    info.charge(&key, len, None)?;
+                }
+                ks.remove_chunks(wtxn, &key)?;
+                deleted += 1;
+            }
+
+            let mut cursor = ks.db.range_mut(wtxn, &range).map_err(heed_err)?;
+            while let Some(item) = cursor.next() {
+                let (key, sealed) = item.map_err(heed_err)?;
+                let len = sealed.len().saturating_sub(checksum::CHECKSUM_LEN) as u64;
+                if let Some(info) = &mut info {
+                    info.charge(key, Some(len), None)?;
+                }
+                cursor.del_current().map_err(heed_err)?;
+                deleted += 1;
+            }
+            drop(cursor);
+            let mut cursor = ks.value_meta.range_mut(wtxn, &range).map_err(heed_err)?;
+            while let Some(item) = cursor.next() {
+                item.map_err(heed_err)?;
+                cursor.del_current().map_err(heed_err)?;
+            }
+            drop(cursor);
+
+            if let Some(info) = info {
+                store.put_namespace_info(wtxn, &info)?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        self.store.count_range_in(&self.wtxn, ns, start, end)
+    }
+
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.store.range_keys_in(&self.wtxn, ns, start, end, limit)
+    }
+
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        self.write(|store, wtxn| {
+            let ks = store.keyspace(ns)?;
+            store.charge(wtxn, ns, &ks, key, None)?;
+            let chunked = ks.remove_chunks(wtxn, key)?;
+            ks.value_meta.delete(wtxn, key).map_err(heed_err)?;
+            Ok(ks.db.delete(wtxn, key).map_err(heed_err)? || chunked)
+        })
+    }
+
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        self.store.get_meta_in(&self.wtxn, ns, key)
+    }
+
+    // the store's keyspaces only change once the nested transaction has
+    // committed
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        let created = self.write(|store, wtxn| {
+            if let Some(mut info) = store.namespace_info(wtxn, name)? {
+                info.quota = quota;
+                store.put_namespace_info(wtxn, &info)?;
+                return Ok(None);
+            }
+            // the lowest slot no namespace holds, the same on every replica
+            let mut taken = Vec::new();
+            for item in store.meta.prefix_iter(wtxn, META_SLOT).map_err(heed_err)? {
+                let (_name, slot) = item.map_err(heed_err)?;
+                taken.push(slot);
+            }
+            namespace::check_create(name, taken.len())?;
+            let slot = (0..).find(|slot| !taken.contains(slot)).unwrap_or(0);
+            let prefix = namespace::key_prefix(name);
+            for db in [store.db, store.chunked] {
+                if db
+                    .prefix_iter(wtxn, &prefix)
+                    .map_err(heed_err)?
+                    .next()
+                    .is_some()
+                {
+                    return Err(namespace::rejected(Rejection::NamespaceInUse(
+                        name.to_string(),
+                    )));
+                }
+            }
+            let ks = Keyspace::create(&store.env, wtxn, slot)?;
+            store
+                .meta
+                .put(wtxn, &slot_key(name), &slot)
+                .map_err(heed_err)?;
+            store.put_namespace_info(wtxn, &Namespace::new(name, quota))?;
+            Ok(Some(ks))
+        })?;
+        match created {
+            Some(ks) => {
+                self.store.keyspaces.insert(name.to_string(), ks);
+                self.keyspaces_changed = true;
+                Ok(true)
+            }
+            None => Ok(false),
         }
-        Ok(deleted)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        let ks = match self.store.keyspaces.get(name) {
+            Some(ks) => *ks,
+            None => return Ok(false),
+        };
+        self.write(|store, wtxn| {
+            for db in [ks.db, ks.chunked, ks.chunks, ks.value_meta] {
+                db.clear(wtxn).map_err(heed_err)?;
+            }
+            store.namespaces.delete(wtxn, name).map_err(heed_err)?;
+            store.meta.delete(wtxn, &slot_key(name)).map_err(heed_err)?;
+            Ok(())
+        })?;
+        self.store.keyspaces.remove(name);
+        self.keyspaces_changed = true;
+        Ok(true)
+    }
+
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        self.store.namespaces_in(&self.wtxn)
+    }
+
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        self.store.namespace_info(&self.wtxn, name)
+    }
+
+    fn applied_index(&self) -> Result<u64> {
+        self.store.applied_index_in(&self.wtxn)
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        self.write(|store, wtxn| {
+            store
+                .meta
+                .put(wtxn, META_APPLIED_INDEX, &index)
+                .map_err(heed_err)?;
+            Ok(true)
+        })
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_session_in(&self.wtxn, client_id)
+    }
+
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        self.write(|store, wtxn| {
+            store
+                .sessions
+                .put(wtxn, client_id, &checksum::seal(&session))
+                .map_err(heed_err)?;
+            Ok(true)
+        })
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_user_in(&self.wtxn, name)
+    }
+
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        self.write(|store, wtxn| {
+            store
+                .users
+                .put(wtxn, name, &checksum::seal(&user))
+                .map_err(heed_err)?;
+            Ok(true)
+        })
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        self.write(|store, wtxn| store.users.delete(wtxn, name).map_err(heed_err))
+    }
+
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_acl_in(&self.wtxn, key)
+    }
+
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        self.write(|store, wtxn| {
+            store
+                .acl
+                .put(wtxn, key, &checksum::seal(&buf))
+                .map_err(heed_err)?;
+            Ok(true)
+        })
+    }
+
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        self.write(|store, wtxn| store.acl.delete(wtxn, key).map_err(heed_err))
+    }
+
+    // these look at what's been committed
+    fn stats(&self) -> Result<StoreStats> {
+        self.store.stats()
+    }
+
+    fn scrub(&self) -> Result<Vec<String>> {
+        self.store.scrub()
+    }
+
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        self.store.value_info_in(&self.wtxn, ns, key)
+    }
+
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        self.store.get_chunk_in(&self.wtxn, ns, key, info, n)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.store.sync()
+    }
+
+    // f's writes go in a transaction nested in this one
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        let env = self.env;
+        let (res, changed) = {
+            let wtxn = env.nested_write_txn(&mut self.wtxn).map_err(heed_err)?;
+            let mut txn = TxnStore {
+                store: &mut *self.store,
+                env: env,
+                wtxn: wtxn,
+                keyspaces_changed: false,
+            };
+            let res = f(&mut txn);
+            let changed = txn.keyspaces_changed;
+            match res {
+                Ok(()) => (txn.wtxn.commit().map_err(heed_err), changed),
+                Err(err) => (Err(err), changed),
+            }
+        };
+        if changed {
+            self.keyspaces_changed = true;
+            if res.is_err() {
+                // back to the namespaces this transaction sees
+                self.store.keyspaces.clear();
+                let _ = self.store.load_keyspaces(&mut self.wtxn);
+            }
+        }
+        res
     }
 }
 
@@ -93,6 +1239,14 @@ mod tests {
     use super::*;
     use std::fs;
 
+    // overwrite the raw stored bytes for key, bypassing the checksum, to
+    // simulate on disk corruption
+    fn put_raw(ms: &mut DiskKVStore, key: &[u8], raw: &[u8]) {
+        let mut wtxn = ms.env.write_txn().unwrap();
+        ms.db.put(&mut wtxn, key, raw).unwrap();
+        wtxn.commit().unwrap();
+    }
+
     #[test]
     fn test_heed() {
         let env_path = Path::new("target").join("heed-tst.mdb");
@@ -123,29 +1277,359 @@ mod tests {
         // a config context through from main server or something which is ignored by MemStore
         //
         // WARNING - for now this test will touch the "prod" db on disk
-        let mut ms = DiskKVStore::new();
+        let mut ms = DiskKVStore::new().unwrap();
 
         // set & get
-        ms.set(String::from("foo"), b"bar".to_vec()).unwrap();
-        ms.set(String::from("bar"), b"baz".to_vec()).unwrap();
-        assert_eq!(ms.get(String::from("foo")).unwrap(), b"bar");
-        assert_eq!(ms.get(String::from("bar")).unwrap(), b"baz");
+        ms.set("", b"foo", b"bar".to_vec()).unwrap();
+        ms.set("", b"bar", b"baz".to_vec()).unwrap();
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+        assert_eq!(ms.get("", b"bar").unwrap(), b"baz");
 
         // get non existant key
-        let e = ms.get(String::from("missing"));
+        let e = ms.get("", b"missing");
         assert!(e.is_err());
 
         // delete
-        ms.set(String::from("delete_me"), b"junk".to_vec()).unwrap();
-        assert_eq!(ms.get(String::from("delete_me")).unwrap(), b"junk");
+        ms.set("", b"delete_me", b"junk".to_vec()).unwrap();
+        assert_eq!(ms.get("", b"delete_me").unwrap(), b"junk");
         // can delete once
-        let res = ms.delete(String::from("delete_me"));
-        assert_eq!(res.unwrap(), true);
+        let res = ms.delete("", b"delete_me");
+        assert!(res.unwrap());
         // second get should throw an error
-        let e = ms.get(String::from("delete_me"));
+        let e = ms.get("", b"delete_me");
         assert!(e.is_err());
         // second delete should return false as key removed
-        let res = ms.delete(String::from("delete_me"));
-        assert_eq!(res.unwrap(), false);
+        let res = ms.delete("", b"delete_me");
+        assert!(!res.unwrap());
+
+        // applied index
+        ms.set_applied_index(42).unwrap();
+        assert_eq!(ms.applied_index().unwrap(), 42);
+        ms.sync().unwrap();
+
+        // sessions
+        ms.set_session("client", b"session".to_vec()).unwrap();
+        assert_eq!(ms.get_session("client").unwrap(), Some(b"session".to_vec()));
+
+        // users
+        ms.set_user("alice", b"hash".to_vec()).unwrap();
+        assert_eq!(ms.get_user("alice").unwrap(), Some(b"hash".to_vec()));
+        assert!(ms.delete_user("alice").unwrap());
+        assert_eq!(ms.get_user("alice").unwrap(), None);
+
+        // acl
+        ms.set_acl("role/reader", b"{}".to_vec()).unwrap();
+        assert_eq!(ms.get_acl("role/reader").unwrap(), Some(b"{}".to_vec()));
+        assert!(ms.delete_acl("role/reader").unwrap());
+        assert_eq!(ms.get_acl("role/reader").unwrap(), None);
+
+        // stats
+        let stats = ms.stats().unwrap();
+        assert_eq!(stats.backend, "lmdb");
+        assert!(stats.keys >= 2);
+        let lmdb = stats.lmdb.unwrap();
+        assert_eq!(lmdb.map_size, DB_STORE_SIZE as u64);
+        assert!(lmdb.file_size > 0);
+
+        // checksums - a damaged value is reported rather than returned
+        ms.set("", b"corrupt_me", b"good".to_vec()).unwrap();
+        let mut raw = checksum::seal(b"good");
+        raw[checksum::CHECKSUM_LEN] = b'b';
+        put_raw(&mut ms, b"corrupt_me", &raw);
+        let e = ms.get("", b"corrupt_me").unwrap_err();
+        assert!(checksum::is_corruption(&e));
+        assert!(ms.scrub().unwrap().contains(&String::from("corrupt_me")));
+        ms.delete("", b"corrupt_me").unwrap();
+        assert!(!ms.scrub().unwrap().contains(&String::from("corrupt_me")));
+
+        // chunked values
+        ms.set_chunk_size(4);
+        let big = b"0123456789".to_vec();
+        ms.set("", b"big", big.clone()).unwrap();
+        assert_eq!(ms.get("", b"big").unwrap(), big);
+        let info = ms.value_info("", b"big").unwrap();
+        assert_eq!(info.len, 10);
+        assert_eq!(info.chunks, 3);
+        assert_eq!(ms.get_chunk("", b"big", &info, 0).unwrap(), b"0123");
+        assert_eq!(ms.get_chunk("", b"big", &info, 2).unwrap(), b"89");
+        // small values are a single chunk
+        let info = ms.value_info("", b"foo").unwrap();
+        assert_eq!(info.chunks, 1);
+        assert_eq!(ms.get_chunk("", b"foo", &info, 0).unwrap(), b"bar");
+        // overwriting a chunked value with a small one drops the chunks
+        let old = ms.value_info("", b"big").unwrap();
+        ms.set("", b"big", b"tiny".to_vec()).unwrap();
+        assert_eq!(ms.get("", b"big").unwrap(), b"tiny");
+        assert!(ms.get_chunk("", b"big", &old, 0).is_err());
+        ms.set("", b"big", big.clone()).unwrap();
+        assert!(ms.delete("", b"big").unwrap());
+        assert!(ms.get("", b"big").is_err());
+        assert!(!ms.delete("", b"big").unwrap());
+
+        // metadata
+        let meta = ValueMeta {
+            content_type: Some(String::from("application/json")),
+            created: 1,
+            modified: 1,
+            ..Default::default()
+        };
+        ms.set_with_meta("", b"meta", b"{}".to_vec(), Some(meta.clone()))
+            .unwrap();
+        let replaced = ValueMeta {
+            created: 2,
+            modified: 2,
+            ..meta
+        };
+        ms.set_with_meta("", b"meta", b"[1]".to_vec(), Some(replaced))
+            .unwrap();
+        let stored = ms.get_meta("", b"meta").unwrap().unwrap();
+        assert_eq!(stored.content_type.as_deref(), Some("application/json"));
+        assert_eq!((stored.created, stored.modified, stored.size), (1, 2, 3));
+        ms.set("", b"meta", b"plain".to_vec()).unwrap();
+        assert_eq!(ms.get_meta("", b"meta").unwrap(), None);
+        ms.delete("", b"meta").unwrap();
+
+        // counters, including one big enough to be chunked
+        ms.delete("", b"count").unwrap();
+        assert_eq!(ms.incr("", b"count", 5, 1).unwrap(), 5);
+        assert_eq!(ms.incr("", b"count", 12345, 2).unwrap(), 12350);
+        assert_eq!(ms.get("", b"count").unwrap(), b"12350");
+        assert_eq!(ms.incr("", b"count", -12350, 3).unwrap(), 0);
+        let stored = ms.get_meta("", b"count").unwrap().unwrap();
+        assert_eq!((stored.created, stored.modified), (1, 3));
+        ms.set("", b"count", b"nope".to_vec()).unwrap();
+        assert!(ms.incr("", b"count", 1, 4).is_err());
+        assert_eq!(ms.get("", b"count").unwrap(), b"nope");
+        ms.delete("", b"count").unwrap();
+
+        // ranges, chunked values and metadata included
+        ms.delete_prefix("", b"range/").unwrap();
+        ms.set("", b"range/a", b"x".to_vec()).unwrap();
+        ms.set("", b"range/big", b"0123456789".to_vec()).unwrap();
+        ms.set_with_meta("", b"range/c", b"x".to_vec(), Some(ValueMeta::default()))
+            .unwrap();
+        ms.set("", b"range0", b"x".to_vec()).unwrap();
+        assert_eq!(ms.count_range("", b"range/", Some(b"range0")).unwrap(), 3);
+        let found = ms.range_keys("", b"range/", None, 2).unwrap();
+        assert_eq!(found, vec![b"range/a".to_vec(), b"range/big".to_vec()]);
+        assert_eq!(ms.delete_prefix("", b"range/").unwrap(), 3);
+        assert!(ms.get("", b"range/big").is_err());
+        assert_eq!(ms.get_meta("", b"range/c").unwrap(), None);
+        assert_eq!(ms.count_range("", b"range/", Some(b"range0")).unwrap(), 0);
+        assert_eq!(ms.get("", b"range0").unwrap(), b"x");
+        assert!(ms.delete("", b"range0").unwrap());
+
+        // binary keys
+        let key = b"bin\x00\xff/key";
+        ms.set("", key, b"raw".to_vec()).unwrap();
+        assert_eq!(ms.get("", key).unwrap(), b"raw");
+        assert!(ms.delete("", key).unwrap());
+    }
+
+    #[test]
+    fn test_migrate_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        ms.set_chunk_size(4);
+        // keys as they were stored when they were request paths
+        ms.set("", b"a%20b", b"small".to_vec()).unwrap();
+        ms.set("", b"big%2Fv", b"0123456789".to_vec()).unwrap();
+        ms.set("", b"dup%41", b"old".to_vec()).unwrap();
+        ms.set("", b"dupA", b"kept".to_vec()).unwrap();
+        ms.set("", b"plain", b"same".to_vec()).unwrap();
+        let mut wtxn = ms.env.write_txn().unwrap();
+        ms.meta.delete(&mut wtxn, META_KEY_FORMAT).unwrap();
+        wtxn.commit().unwrap();
+
+        assert_eq!(ms.migrate_keys().unwrap(), 2);
+        assert_eq!(ms.get("", b"a b").unwrap(), b"small");
+        assert!(ms.get("", b"a%20b").is_err());
+        assert_eq!(ms.get("", b"big/v").unwrap(), b"0123456789");
+        assert_eq!(ms.value_info("", b"big/v").unwrap().chunks, 3);
+        assert!(ms.get("", b"big%2Fv").is_err());
+        assert_eq!(ms.get("", b"dupA").unwrap(), b"kept");
+        assert!(ms.get("", b"dup%41").is_err());
+        assert_eq!(ms.get("", b"plain").unwrap(), b"same");
+        assert!(ms.scrub().unwrap().is_empty());
+
+        // only runs once
+        ms.set("", b"c%20d", b"new".to_vec()).unwrap();
+        assert_eq!(ms.migrate_keys().unwrap(), 0);
+        assert_eq!(ms.get("", b"c%20d").unwrap(), b"new");
+    }
+
+    // legacy keys whose decoded form is already stored are removed, chunked
+    // ones with their manifest and chunks
+    #[test]
+    fn test_migrate_taken_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        ms.set_chunk_size(4);
+        ms.set("", b"a%20b", b"small".to_vec()).unwrap();
+        ms.set("", b"a b", b"kept".to_vec()).unwrap();
+        ms.set("", b"big%2Fv", b"0123456789".to_vec()).unwrap();
+        ms.set("", b"big/v", b"kept".to_vec()).unwrap();
+        let mut wtxn = ms.env.write_txn().unwrap();
+        ms.meta.delete(&mut wtxn, META_KEY_FORMAT).unwrap();
+        wtxn.commit().unwrap();
+
+        assert_eq!(ms.migrate_keys().unwrap(), 0);
+        assert_eq!(ms.get("", b"a b").unwrap(), b"kept");
+        assert_eq!(ms.get("", b"big/v").unwrap(), b"kept");
+        let rtxn = ms.env.read_txn().unwrap();
+        for old in [&b"a%20b"[..], b"big%2Fv"] {
+            assert!(ms.db.get(&rtxn, old).unwrap().is_none());
+            assert!(ms.chunked.get(&rtxn, old).unwrap().is_none());
+        }
+        assert!(ms.chunks.is_empty(&rtxn).unwrap());
+        drop(rtxn);
+        assert!(ms.scrub().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        ms.set_chunk_size(4);
+        let quota = Quota {
+            max_keys: None,
+            max_bytes: Some(32),
+        };
+        assert!(ms.set("team", b"foo", b"bar".to_vec()).is_err());
+        assert!(ms.put_namespace("team", quota).unwrap());
+
+        // keys are separate from the default namespace
+        ms.set("", b"foo", b"default".to_vec()).unwrap();
+        ms.set("team", b"foo", b"bar".to_vec()).unwrap();
+        ms.set("team", b"big", b"0123456789".to_vec()).unwrap();
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+        assert_eq!(ms.get("", b"foo").unwrap(), b"default");
+        assert_eq!(ms.value_info("team", b"big").unwrap().chunks, 3);
+        let info = ms.namespace("team").unwrap().unwrap();
+        assert_eq!((info.keys, info.bytes), (2, 19));
+
+        // quota
+        let e = ms.set("team", b"huge", vec![0; 32]).unwrap_err();
+        assert_eq!(
+            namespace::rejection(&e),
+            Some(Rejection::QuotaExceeded(String::from("team")))
+        );
+        assert!(ms.get("team", b"huge").is_err());
+        assert!(ms.delete("team", b"big").unwrap());
+        assert_eq!(ms.namespace("team").unwrap().unwrap().bytes, 6);
+
+        // range deletes are charged too
+        ms.set("team", b"tmp/a", b"0123456789".to_vec()).unwrap();
+        ms.set("team", b"tmp/b", b"x".to_vec()).unwrap();
+        assert_eq!(ms.delete_prefix("team", b"tmp/").unwrap(), 2);
+        let info = ms.namespace("team").unwrap().unwrap();
+        assert_eq!((info.keys, info.bytes), (1, 6));
+
+        // can't shadow default namespace keys
+        ms.set("", b"taken/key", b"x".to_vec()).unwrap();
+        let e = ms.put_namespace("taken", quota).unwrap_err();
+        assert!(namespace::rejection(&e).is_some());
+
+        // namespaces survive a restart
+        drop(ms);
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+        let stats = ms.stats().unwrap();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.namespaces.len(), 1);
+        assert!(ms.scrub().unwrap().is_empty());
+
+        assert!(ms.drop_namespace("team").unwrap());
+        assert!(ms.get("team", b"foo").is_err());
+        assert_eq!(ms.namespaces().unwrap(), vec![]);
+        ms.put_namespace("team", quota).unwrap();
+        assert!(ms.get("team", b"foo").is_err());
+
+        // dropped namespaces' slots are reused, however many come and go
+        ms.set("team", b"foo", b"bar".to_vec()).unwrap();
+        for i in 0..2 * MAX_NAMESPACES {
+            let name = format!("ns-{}", i);
+            assert!(ms.put_namespace(&name, quota).unwrap());
+            assert!(ms.get(&name, b"foo").is_err());
+            ms.set(&name, b"foo", b"bar".to_vec()).unwrap();
+            assert!(ms.drop_namespace(&name).unwrap());
+        }
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+        drop(ms);
+        let ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"bar");
+    }
+
+    #[test]
+    fn test_atomic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        ms.set("", b"count", b"abc".to_vec()).unwrap();
+
+        ms.atomic(&mut |st| {
+            st.set("", b"foo", b"bar".to_vec())?;
+            // reads see the transaction's writes
+            assert_eq!(st.get("", b"foo").unwrap(), b"bar");
+            // a failed write leaves nothing behind, the others still commit
+            assert!(st.incr("", b"count", 1, 1).is_err());
+            st.set_session("client", b"1".to_vec())?;
+            st.set_applied_index(7).map(|_| ())
+        })
+        .unwrap();
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+        assert_eq!(ms.get("", b"count").unwrap(), b"abc");
+        assert_eq!(ms.get_session("client").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(ms.applied_index().unwrap(), 7);
+
+        // nothing is committed when f fails, namespaces included
+        let res = ms.atomic(&mut |st| {
+            st.put_namespace("team", Quota::default())?;
+            st.set("team", b"foo", b"bar".to_vec())?;
+            st.delete("", b"foo")?;
+            st.set_applied_index(8)?;
+            Err(Error::other("failed"))
+        });
+        assert!(res.is_err());
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+        assert_eq!(ms.applied_index().unwrap(), 7);
+        assert_eq!(ms.namespaces().unwrap(), vec![]);
+        assert!(ms.set("team", b"foo", b"bar".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_seal_values() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        ms.set("", b"sealed", b"already".to_vec()).unwrap();
+        // values and sessions as they were stored before checksums
+        put_raw(&mut ms, b"bare", b"value");
+        let mut wtxn = ms.env.write_txn().unwrap();
+        ms.sessions.put(&mut wtxn, "client", b"session").unwrap();
+        ms.meta.delete(&mut wtxn, META_VALUE_FORMAT).unwrap();
+        ms.meta.delete(&mut wtxn, META_KEY_FORMAT).unwrap();
+        wtxn.commit().unwrap();
+        assert!(ms.get("", b"bare").is_err());
+
+        drop(ms);
+        let ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        assert_eq!(ms.get("", b"bare").unwrap(), b"value");
+        assert_eq!(ms.get("", b"sealed").unwrap(), b"already");
+        assert_eq!(ms.get_session("client").unwrap(), Some(b"session".to_vec()));
+        assert!(ms.scrub().unwrap().is_empty());
+
+        // only runs once, later damage is still reported
+        drop(ms);
+        let mut ms = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+        put_raw(&mut ms, b"bare", b"value");
+        assert_eq!(ms.seal_values().unwrap(), 0);
+        assert!(ms.get("", b"bare").is_err());
+    }
+
+    #[test]
+    fn test_open_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        // a file where the environment's directory should be
+        fs::write(tmp.path().join(DB_NAME), b"junk").unwrap();
+        assert!(DiskKVStore::new_with_db_path(tmp.path()).is_err());
     }
 }
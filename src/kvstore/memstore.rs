@@ -2,11 +2,35 @@ use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 use std::vec::Vec;
 
-use super::KVStorage;
+use super::{
+    counter_meta, in_range, incremented, unexpired, KVStorage, StoreStats, ValueInfo, ValueMeta,
+};
+use crate::checksum;
+use crate::namespace::{self, Namespace, Quota, Rejection};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+struct Value {
+    buf: Vec<u8>,
+    meta: Option<ValueMeta>,
+}
+
+type Keys = HashMap<Vec<u8>, Value>;
+
+#[derive(Clone, Debug)]
 pub struct MemKVStore {
-    store: HashMap<String, Vec<u8>>,
+    // the default namespace
+    store: Keys,
+    namespaces: HashMap<String, (Namespace, Keys)>,
+    applied_index: u64,
+    sessions: HashMap<String, Vec<u8>>,
+    users: HashMap<String, Vec<u8>>,
+    acl: HashMap<String, Vec<u8>>,
+}
+
+impl Default for MemKVStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemKVStore {
@@ -14,28 +38,273 @@ impl MemKVStore {
     pub fn new() -> MemKVStore {
         MemKVStore {
             store: HashMap::new(),
+            namespaces: HashMap::new(),
+            applied_index: 0,
+            sessions: HashMap::new(),
+            users: HashMap::new(),
+            acl: HashMap::new(),
+        }
+    }
+
+    fn keys(&self, ns: &str) -> Result<&Keys> {
+        if ns == namespace::DEFAULT {
+            return Ok(&self.store);
+        }
+        match self.namespaces.get(ns) {
+            Some((_info, keys)) => Ok(keys),
+            None => Err(namespace::rejected(Rejection::NoSuchNamespace(
+                ns.to_string(),
+            ))),
+        }
+    }
+
+    // keys in ns and its usage, which the default namespace doesn't track
+    fn keys_mut(&mut self, ns: &str) -> Result<(Option<&mut Namespace>, &mut Keys)> {
+        if ns == namespace::DEFAULT {
+            return Ok((None, &mut self.store));
+        }
+        match self.namespaces.get_mut(ns) {
+            Some((info, keys)) => Ok((Some(info), keys)),
+            None => Err(namespace::rejected(Rejection::NoSuchNamespace(
+                ns.to_string(),
+            ))),
         }
     }
 }
 
 impl KVStorage for MemKVStore {
-    fn get(&self, key: String) -> Result<Vec<u8>> {
-        let err = Error::new(ErrorKind::Other, "missing key");
-        self.store.get(&key).ok_or(err).cloned()
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let err = Error::other("missing key");
+        self.keys(ns)?.get(key).ok_or(err).map(|v| v.buf.clone())
     }
 
-    fn set(&mut self, key: String, buf: Vec<u8>) -> Result<bool> {
-        self.store.insert(key, buf);
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        let (info, keys) = self.keys_mut(ns)?;
+        let old = keys.get(key);
+        if let Some(info) = info {
+            info.charge(key, old.map(|v| v.buf.len() as u64), Some(buf.len() as u64))?;
+        }
+        let old_meta = old.and_then(|v| v.meta.clone());
+        let meta = meta.map(|meta| meta.replacing(old_meta, buf.len() as u64));
+        keys.insert(
+            key.to_vec(),
+            Value {
+                buf: buf,
+                meta: meta,
+            },
+        );
         Ok(true)
     }
 
-    fn delete(&mut self, key: String) -> Result<bool> {
-        let res = self.store.remove(&key);
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        Ok(self.keys(ns)?.get(key).and_then(|v| v.meta.clone()))
+    }
+
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        let old = self.keys(ns)?.get(key);
+        let (old, meta) = unexpired(
+            old.map(|v| v.buf.as_slice()),
+            old.and_then(|v| v.meta.clone()),
+            modified,
+        );
+        let n = incremented(old, by)?;
+        let meta = counter_meta(meta, modified);
+        self.set_with_meta(ns, key, n.to_string().into_bytes(), Some(meta))?;
+        Ok(n)
+    }
+
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        let (info, keys) = self.keys_mut(ns)?;
+        let res = keys.remove(key);
         match res {
-            Some(_res) => return Ok(true),
-            None => return Ok(false),
+            Some(res) => {
+                if let Some(info) = info {
+                    info.charge(key, Some(res.buf.len() as u64), None)?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        let (mut info, keys) = self.keys_mut(ns)?;
+        let doomed: Vec<Vec<u8>> = keys
+            .keys()
+            .filter(|key| in_range(key, start, end))
+            .cloned()
+            .collect();
+        for key in &doomed {
+            let old = keys.remove(key).map(|v| v.buf.len() as u64);
+            if let Some(info) = &mut info {
+                info.charge(key, old, None)?;
+            }
+        }
+        Ok(doomed.len() as u64)
+    }
+
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        let keys = self.keys(ns)?;
+        Ok(keys.keys().filter(|key| in_range(key, start, end)).count() as u64)
+    }
+
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let keys = self.keys(ns)?;
+        let mut found: Vec<Vec<u8>> = keys
+            .keys()
+            .filter(|key| in_range(key, start, end))
+            .cloned()
+            .collect();
+        found.sort();
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        if let Some((info, _keys)) = self.namespaces.get_mut(name) {
+            info.quota = quota;
+            return Ok(false);
+        }
+        namespace::check_create(name, self.namespaces.len())?;
+        let prefix = namespace::key_prefix(name);
+        if self.store.keys().any(|k| k.starts_with(&prefix)) {
+            return Err(namespace::rejected(Rejection::NamespaceInUse(
+                name.to_string(),
+            )));
+        }
+        let info = Namespace::new(name, quota);
+        self.namespaces
+            .insert(name.to_string(), (info, HashMap::new()));
+        Ok(true)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        Ok(self.namespaces.remove(name).is_some())
+    }
+
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        let mut namespaces: Vec<Namespace> = self
+            .namespaces
+            .values()
+            .map(|(info, _)| info.clone())
+            .collect();
+        namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(namespaces)
+    }
+
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        Ok(self.namespaces.get(name).map(|(info, _)| info.clone()))
+    }
+
+    fn applied_index(&self) -> Result<u64> {
+        Ok(self.applied_index)
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        self.applied_index = index;
+        Ok(true)
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.sessions.get(client_id).cloned())
+    }
+
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        self.sessions.insert(client_id.to_string(), session);
+        Ok(true)
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.users.get(name).cloned())
+    }
+
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        self.users.insert(name.to_string(), user);
+        Ok(true)
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        Ok(self.users.remove(name).is_some())
+    }
+
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.acl.get(key).cloned())
+    }
+
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        self.acl.insert(key.to_string(), buf);
+        Ok(true)
+    }
+
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        Ok(self.acl.remove(key).is_some())
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            backend: String::from("memory"),
+            keys: self.store.len() as u64
+                + self
+                    .namespaces
+                    .values()
+                    .map(|(info, _)| info.keys)
+                    .sum::<u64>(),
+            lmdb: None,
+            namespaces: self.namespaces()?,
+        })
+    }
+
+    fn scrub(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    // values in memory are never chunked
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        match self.keys(ns)?.get(key) {
+            Some(val) => Ok(ValueInfo {
+                len: val.buf.len() as u64,
+                chunks: 1,
+                checksum: checksum::checksum(&val.buf),
+            }),
+            None => Err(Error::new(ErrorKind::NotFound, "missing key")),
+        }
+    }
+
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        match self.keys(ns)?.get(key) {
+            Some(val) if n == 0 && checksum::checksum(&val.buf) == info.checksum => {
+                Ok(val.buf.clone())
+            }
+            _ => Err(Error::new(ErrorKind::NotFound, "value changed")),
         }
     }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // nothing in memory survives a crash, so there's no commit to tear.
+    // Writes don't fail partway, but those f made before failing are kept
+    // f works on a copy which replaces the store if it succeeds, the memory
+    // backend is for tests so the copy is simpler than an undo log
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        let mut copy = self.clone();
+        f(&mut copy)?;
+        *self = copy;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -47,26 +316,134 @@ mod tests {
         let mut ms = MemKVStore::new();
 
         // set & get
-        ms.set(String::from("foo"), b"bar".to_vec()).unwrap();
-        ms.set(String::from("bar"), b"baz".to_vec()).unwrap();
-        assert_eq!(ms.get(String::from("foo")).unwrap(), b"bar");
-        assert_eq!(ms.get(String::from("bar")).unwrap(), b"baz");
+        ms.set("", b"foo", b"bar".to_vec()).unwrap();
+        ms.set("", b"bar", b"baz".to_vec()).unwrap();
+        assert_eq!(ms.get("", b"foo").unwrap(), b"bar");
+        assert_eq!(ms.get("", b"bar").unwrap(), b"baz");
 
         // get non existant key
-        let e = ms.get(String::from("missing"));
+        let e = ms.get("", b"missing");
         assert!(e.is_err());
 
         // delete
-        ms.set(String::from("delete_me"), b"junk".to_vec()).unwrap();
-        assert_eq!(ms.get(String::from("delete_me")).unwrap(), b"junk");
+        ms.set("", b"delete_me", b"junk".to_vec()).unwrap();
+        assert_eq!(ms.get("", b"delete_me").unwrap(), b"junk");
         // can delete once
-        let res = ms.delete(String::from("delete_me"));
-        assert_eq!(res.unwrap(), true);
+        let res = ms.delete("", b"delete_me");
+        assert!(res.unwrap());
         // second get should throw an error
-        let e = ms.get(String::from("delete_me"));
+        let e = ms.get("", b"delete_me");
         assert!(e.is_err());
         // second delete should return false as key removed
-        let res = ms.delete(String::from("delete_me"));
-        assert_eq!(res.unwrap(), false);
+        let res = ms.delete("", b"delete_me");
+        assert!(!res.unwrap());
+
+        // applied index
+        assert_eq!(ms.applied_index().unwrap(), 0);
+        ms.set_applied_index(42).unwrap();
+        assert_eq!(ms.applied_index().unwrap(), 42);
+
+        // sessions
+        assert_eq!(ms.get_session("client").unwrap(), None);
+        ms.set_session("client", b"session".to_vec()).unwrap();
+        assert_eq!(ms.get_session("client").unwrap(), Some(b"session".to_vec()));
+
+        // users
+        assert_eq!(ms.get_user("alice").unwrap(), None);
+        ms.set_user("alice", b"hash".to_vec()).unwrap();
+        assert_eq!(ms.get_user("alice").unwrap(), Some(b"hash".to_vec()));
+        assert!(ms.delete_user("alice").unwrap());
+        assert!(!ms.delete_user("alice").unwrap());
+
+        // stats - foo and bar are left
+        assert_eq!(ms.stats().unwrap().keys, 2);
+
+        // values are a single chunk
+        let info = ms.value_info("", b"foo").unwrap();
+        assert_eq!(info.len, 3);
+        assert_eq!(info.chunks, 1);
+        assert_eq!(ms.get_chunk("", b"foo", &info, 0).unwrap(), b"bar");
+        ms.set("", b"foo", b"changed".to_vec()).unwrap();
+        assert!(ms.get_chunk("", b"foo", &info, 0).is_err());
+
+        // metadata, created is kept when a value is replaced
+        assert_eq!(ms.get_meta("", b"foo").unwrap(), None);
+        let meta = ValueMeta {
+            content_type: Some(String::from("text/plain")),
+            created: 1,
+            modified: 1,
+            ..Default::default()
+        };
+        ms.set_with_meta("", b"foo", b"meta".to_vec(), Some(meta.clone()))
+            .unwrap();
+        let replaced = ValueMeta {
+            created: 2,
+            modified: 2,
+            ..meta
+        };
+        ms.set_with_meta("", b"foo", b"again".to_vec(), Some(replaced))
+            .unwrap();
+        let stored = ms.get_meta("", b"foo").unwrap().unwrap();
+        assert_eq!((stored.created, stored.modified, stored.size), (1, 2, 5));
+        ms.set("", b"foo", b"changed".to_vec()).unwrap();
+        assert_eq!(ms.get_meta("", b"foo").unwrap(), None);
+
+        // counters
+        assert_eq!(ms.incr("", b"count", 5, 1).unwrap(), 5);
+        assert_eq!(ms.incr("", b"count", -2, 2).unwrap(), 3);
+        assert_eq!(ms.get("", b"count").unwrap(), b"3");
+        let stored = ms.get_meta("", b"count").unwrap().unwrap();
+        assert_eq!((stored.created, stored.modified), (1, 2));
+        assert!(ms.incr("", b"foo", 1, 3).is_err());
+        assert_eq!(ms.get("", b"foo").unwrap(), b"changed");
+        assert!(ms.delete("", b"count").unwrap());
+
+        // expired counters start again from 0
+        let expiring = ValueMeta {
+            expires: Some(10),
+            ..Default::default()
+        };
+        ms.set_with_meta("", b"ttl", b"7".to_vec(), Some(expiring))
+            .unwrap();
+        assert_eq!(ms.incr("", b"ttl", 1, 9).unwrap(), 8);
+        assert_eq!(ms.incr("", b"ttl", 1, 10).unwrap(), 1);
+        assert_eq!(ms.get_meta("", b"ttl").unwrap().unwrap().expires, None);
+        ms.delete("", b"ttl").unwrap();
+
+        // ranges
+        for key in [&b"app/a"[..], b"app/b", b"app0"] {
+            ms.set("", key, b"x".to_vec()).unwrap();
+        }
+        assert_eq!(ms.count_range("", b"app/", Some(b"app/b")).unwrap(), 1);
+        let found = ms.range_keys("", b"app/", None, 2).unwrap();
+        assert_eq!(found, vec![b"app/a".to_vec(), b"app/b".to_vec()]);
+        assert_eq!(ms.delete_prefix("", b"app/").unwrap(), 2);
+        assert_eq!(ms.delete_prefix("", b"app/").unwrap(), 0);
+        assert_eq!(ms.get("", b"app0").unwrap(), b"x");
+        assert_eq!(ms.delete_range("", b"app0", Some(b"app1")).unwrap(), 1);
+
+        // namespaces
+        assert!(ms.get("team", b"foo").is_err());
+        let quota = Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        };
+        assert!(ms.put_namespace("team", quota).unwrap());
+        assert!(!ms.put_namespace("team", quota).unwrap());
+        ms.set("team", b"foo", b"other".to_vec()).unwrap();
+        assert_eq!(ms.get("team", b"foo").unwrap(), b"other");
+        assert_eq!(ms.get("", b"foo").unwrap(), b"changed");
+        let e = ms.set("team", b"two", b"x".to_vec()).unwrap_err();
+        assert!(namespace::rejection(&e).is_some());
+        let info = ms.namespace("team").unwrap().unwrap();
+        assert_eq!((info.keys, info.bytes), (1, 8));
+        assert_eq!(ms.stats().unwrap().namespaces, vec![info]);
+        // can't shadow default namespace keys
+        ms.set("", b"used/key", b"x".to_vec()).unwrap();
+        assert!(ms.put_namespace("used", quota).is_err());
+        assert!(ms.put_namespace("a/b", quota).is_err());
+        assert!(ms.drop_namespace("team").unwrap());
+        assert!(ms.get("team", b"foo").is_err());
+        assert!(!ms.drop_namespace("team").unwrap());
     }
 }
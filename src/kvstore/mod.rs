@@ -4,28 +4,569 @@
 // Contains two implementations:
 //   kvstore::diskstore::DiskKVStore - backed by a lmdb db using the heed crate
 //   kvstore::memstore::MemKVStore - backed by a std::vec::Vec
+// open(...) picks one by name at runtime, boxed as a BoxedKVStorage
+// asyncstore::AsyncKVStore wraps a shared store for use from async code
+// watch::WatchedKVStore wraps a store to publish the changes made to it
+//
+// Keys are looked up within a namespace, see crate::namespace, "" being the
+// default one.
 //
 
-use std::io::Result;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
 use std::vec::Vec;
 
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use crate::command::{self, CommandError};
+use crate::namespace::{Namespace, Quota};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub backend: String,
+    // across all namespaces
+    pub keys: u64,
+    pub lmdb: Option<LmdbStats>,
+    pub namespaces: Vec<Namespace>,
+}
+
+// the map size the environment was opened with and how big its data file
+// is, lmdb grows the file as pages are used but never shrinks it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LmdbStats {
+    pub map_size: u64,
+    pub file_size: u64,
+}
+
+// size and layout of a stored value, see KVStorage::get_chunk
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValueInfo {
+    pub len: u64,
+    pub chunks: u32,
+    // crc32c of the whole value
+    pub checksum: u32,
+}
+
+// kept alongside a value, see KVStorage::set_with_meta
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValueMeta {
+    pub content_type: Option<String>,
+    // user defined metadata, header names are lower case without the
+    // X-Fekv-Meta- prefix
+    #[serde(default)]
+    pub user: BTreeMap<String, String>,
+    // unix time in milliseconds, the store keeps created from the value
+    // being replaced if it had metadata
+    pub created: u64,
+    pub modified: u64,
+    // unix time in milliseconds after which the value reads as missing, see
+    // expired(...). Expired values are left in the store until they're
+    // overwritten or deleted, or the leader's expirer sweeps them up
+    #[serde(default)]
+    pub expires: Option<u64>,
+    // filled in by the store
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl ValueMeta {
+    // metadata for a value of size bytes replacing one with old metadata
+    pub fn replacing(mut self, old: Option<ValueMeta>, size: u64) -> ValueMeta {
+        if let Some(old) = old {
+            self.created = old.created;
+        }
+        self.size = size;
+        self
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+// a value and its metadata as of now, neither once it has expired
+pub fn unexpired<T>(
+    value: Option<T>,
+    meta: Option<ValueMeta>,
+    now: u64,
+) -> (Option<T>, Option<ValueMeta>) {
+    match meta.as_ref().is_some_and(|meta| meta.expired(now)) {
+        true => (None, None),
+        false => (value, meta),
+    }
+}
+
+// reads take &self so a store shared behind a RwLock (as the server and raft
+// node do) serves them concurrently, only applying writes is exclusive
 pub trait KVStorage {
-    fn get(&self, key: String) -> Result<Vec<u8>>;
-    fn set(&mut self, key: String, buf: Vec<u8>) -> Result<bool>;
-    fn delete(&mut self, key: String) -> Result<bool>;
+    // keys are arbitrary bytes, using a namespace which doesn't exist fails
+    // with namespace::Rejection::NoSuchNamespace
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>>;
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool>;
+
+    // values are stored with metadata when it's given, replacing a value
+    // replaces (or drops) its metadata too
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool>;
+    fn set(&mut self, ns: &str, key: &[u8], buf: Vec<u8>) -> Result<bool> {
+        self.set_with_meta(ns, key, buf, None)
+    }
+    // None for missing keys and values stored without metadata
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>>;
+
+    // add by to the integer (decimal text) stored at key in one write, a
+    // missing key counts from 0. Returns the new value, see incremented(...)
+    // for the values which are refused
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64>;
+
+    // delete every key from start up to but not including end (None for no
+    // end) in one write, returning how many there were. count_range counts
+    // them without deleting anything
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64>;
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64>;
+    // the first limit keys in the same range, in order
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>>;
+    fn delete_prefix(&mut self, ns: &str, prefix: &[u8]) -> Result<u64> {
+        self.delete_range(ns, prefix, prefix_end(prefix).as_deref())
+    }
+
+    // create a namespace or change its quota, returns true if it was created.
+    // Dropping a namespace deletes its keys
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool>;
+    fn drop_namespace(&mut self, name: &str) -> Result<bool>;
+    fn namespaces(&self) -> Result<Vec<Namespace>>;
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>>;
+
+    // index of the last raft entry applied to this store, used to resume
+    // applying committed entries after a restart
+    fn applied_index(&self) -> Result<u64>;
+    fn set_applied_index(&mut self, index: u64) -> Result<bool>;
+
+    // client session dedup table, sessions are opaque bytes to the store
+    // see command::Command::Session
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>>;
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool>;
+
+    // user table for http basic auth, kept apart from regular keys so it
+    // can't be read or overwritten through the kv api
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool>;
+    fn delete_user(&mut self, name: &str) -> Result<bool>;
+
+    // roles and role bindings, see acl
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool>;
+    fn delete_acl(&mut self, key: &str) -> Result<bool>;
+
+    fn stats(&self) -> Result<StoreStats>;
+
+    // check every stored value against its checksum and return the keys
+    // which don't match, stores without checksums have nothing to report
+    fn scrub(&self) -> Result<Vec<String>>;
+
+    // large values can be read a chunk at a time so they can be streamed
+    // without holding the store for the whole response, get_chunk fails if
+    // the value has changed since value_info was read
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo>;
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>>;
+
+    // flush everything written so far to disk, e.g. before exiting
+    fn sync(&self) -> Result<()>;
+
+    // run f against a view of the store whose writes (the applied index and
+    // sessions included) are committed together once f returns Ok, or not at
+    // all if it fails. A write which fails inside f leaves nothing behind.
+    // Calls nest, an inner one which fails only undoes its own writes
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()>;
+
+    // raft installed a snapshot instead of handing over the entries before
+    // it, so writes were skipped without the store seeing them
+    fn restored(&mut self) {}
+}
+
+// lets a boxed backend, see open(...), be used wherever a KVStorage is
+impl<S: KVStorage + ?Sized> KVStorage for Box<S> {
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        (**self).get(ns, key)
+    }
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        (**self).delete(ns, key)
+    }
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        (**self).set_with_meta(ns, key, buf, meta)
+    }
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        (**self).get_meta(ns, key)
+    }
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        (**self).incr(ns, key, by, modified)
+    }
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        (**self).delete_range(ns, start, end)
+    }
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        (**self).count_range(ns, start, end)
+    }
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        (**self).range_keys(ns, start, end, limit)
+    }
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        (**self).put_namespace(name, quota)
+    }
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        (**self).drop_namespace(name)
+    }
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        (**self).namespaces()
+    }
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        (**self).namespace(name)
+    }
+    fn applied_index(&self) -> Result<u64> {
+        (**self).applied_index()
+    }
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        (**self).set_applied_index(index)
+    }
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_session(client_id)
+    }
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        (**self).set_session(client_id, session)
+    }
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_user(name)
+    }
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        (**self).set_user(name, user)
+    }
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        (**self).delete_user(name)
+    }
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_acl(key)
+    }
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        (**self).set_acl(key, buf)
+    }
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        (**self).delete_acl(key)
+    }
+    fn stats(&self) -> Result<StoreStats> {
+        (**self).stats()
+    }
+    fn scrub(&self) -> Result<Vec<String>> {
+        (**self).scrub()
+    }
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        (**self).value_info(ns, key)
+    }
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        (**self).get_chunk(ns, key, info, n)
+    }
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        (**self).atomic(f)
+    }
+    fn restored(&mut self) {
+        (**self).restored()
+    }
 }
 
+// and a borrowed one, such as the view KVStorage::atomic hands out
+impl<S: KVStorage + ?Sized> KVStorage for &mut S {
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        (**self).get(ns, key)
+    }
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        (**self).delete(ns, key)
+    }
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        (**self).set_with_meta(ns, key, buf, meta)
+    }
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        (**self).get_meta(ns, key)
+    }
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        (**self).incr(ns, key, by, modified)
+    }
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        (**self).delete_range(ns, start, end)
+    }
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        (**self).count_range(ns, start, end)
+    }
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        (**self).range_keys(ns, start, end, limit)
+    }
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        (**self).put_namespace(name, quota)
+    }
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        (**self).drop_namespace(name)
+    }
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        (**self).namespaces()
+    }
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        (**self).namespace(name)
+    }
+    fn applied_index(&self) -> Result<u64> {
+        (**self).applied_index()
+    }
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        (**self).set_applied_index(index)
+    }
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_session(client_id)
+    }
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        (**self).set_session(client_id, session)
+    }
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_user(name)
+    }
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        (**self).set_user(name, user)
+    }
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        (**self).delete_user(name)
+    }
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_acl(key)
+    }
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        (**self).set_acl(key, buf)
+    }
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        (**self).delete_acl(key)
+    }
+    fn stats(&self) -> Result<StoreStats> {
+        (**self).stats()
+    }
+    fn scrub(&self) -> Result<Vec<String>> {
+        (**self).scrub()
+    }
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        (**self).value_info(ns, key)
+    }
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        (**self).get_chunk(ns, key, info, n)
+    }
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        (**self).atomic(f)
+    }
+    fn restored(&mut self) {
+        (**self).restored()
+    }
+}
+
+pub mod asyncstore;
 pub mod diskstore;
 pub mod memstore;
+pub mod watch;
+
+// key as text for logs and reports, non utf-8 bytes are escaped
+pub fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => key.to_string(),
+        Err(_err) => key.escape_ascii().to_string(),
+    }
+}
+
+// the value of a counter after adding by to old (None for a missing key),
+// values which aren't a decimal i64 and results which overflow are refused
+pub fn incremented(old: Option<&[u8]>, by: i64) -> Result<i64> {
+    let old = match old {
+        Some(buf) => std::str::from_utf8(buf)
+            .ok()
+            .and_then(|s| s.trim().parse::<i64>().ok())
+            .ok_or_else(|| command::refused(CommandError::NotInteger))?,
+        None => 0,
+    };
+    old.checked_add(by)
+        .ok_or_else(|| command::refused(CommandError::Overflow))
+}
+
+// metadata for a counter written at modified, new counters are text
+pub fn counter_meta(old: Option<ValueMeta>, modified: u64) -> ValueMeta {
+    match old {
+        Some(meta) => ValueMeta {
+            modified: modified,
+            ..meta
+        },
+        None => ValueMeta {
+            content_type: Some(String::from("text/plain")),
+            created: modified,
+            modified: modified,
+            ..ValueMeta::default()
+        },
+    }
+}
+
+// the first key after every key starting with prefix, None when there's no
+// such key (prefix is empty or all 0xff)
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+pub fn in_range(key: &[u8], start: &[u8], end: Option<&[u8]>) -> bool {
+    key >= start && end.is_none_or(|end| key < end)
+}
+
+// keys used to be strings holding the still percent-encoded request path,
+// this is the key such a string now stands for
+pub fn legacy_key(key: &str) -> Vec<u8> {
+    percent_decode_str(key).collect()
+}
+
+pub type BoxedKVStorage = Box<dyn KVStorage + Send + Sync>;
+
+// settings backends may use, ones which don't apply are ignored
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    pub chunk_size: usize,
+    // where stores kept on disk live
+    pub data_dir: PathBuf,
+}
 
-// pub enum KVStoreKind {
-//     MEMORY,
-//     DISK,
-// }
-
-// pub fn GetKVStore(kind: KVStoreKind) -> Box<dyn KVStorage> {
-//     match kind {
-//         KVStoreKind::MEMORY => Box::new(memstore::MemKVStore::new()),
-//         KVStoreKind::DISK => Box::new(diskstore::DiskKVStore::new()),
-//     }
-// }
+type Constructor = fn(&StoreOptions) -> Result<BoxedKVStorage>;
+
+// backends selectable by name with open(...), add new ones here
+static BACKENDS: &[(&str, Constructor)] = &[("memory", open_memory), ("lmdb", open_lmdb)];
+
+fn open_memory(_opts: &StoreOptions) -> Result<BoxedKVStorage> {
+    Ok(Box::new(memstore::MemKVStore::new()))
+}
+
+fn open_lmdb(opts: &StoreOptions) -> Result<BoxedKVStorage> {
+    let mut store = diskstore::DiskKVStore::new_with_db_path(&opts.data_dir)?;
+    store.set_chunk_size(opts.chunk_size);
+    Ok(Box::new(store))
+}
+
+pub fn backends() -> Vec<&'static str> {
+    BACKENDS.iter().map(|(name, _)| *name).collect()
+}
+
+pub fn open(backend: &str, opts: &StoreOptions) -> Result<BoxedKVStorage> {
+    match BACKENDS.iter().find(|(name, _)| *name == backend) {
+        Some((_, constructor)) => constructor(opts),
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "unknown store backend {}, expected one of {}",
+                backend,
+                backends().join(", ")
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let opts = StoreOptions {
+            chunk_size: 1024,
+            data_dir: PathBuf::from("./data"),
+        };
+        let mut store = open("memory", &opts).unwrap();
+        store.set("", b"foo", b"bar".to_vec()).unwrap();
+        assert_eq!(store.get("", b"foo").unwrap(), b"bar");
+        assert_eq!(store.stats().unwrap().backend, "memory");
+
+        assert!(backends().contains(&"lmdb"));
+        let err = open("nope", &opts).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(display_key(b"foo/bar"), "foo/bar");
+        assert_eq!(display_key(b"a\xff\x00"), "a\\xff\\x00");
+        assert_eq!(legacy_key("foo"), b"foo");
+        assert_eq!(legacy_key("a%20b%2Fc%ff"), b"a b/c\xff");
+        assert_eq!(legacy_key("100%"), b"100%");
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(prefix_end(b"app/"), Some(b"app0".to_vec()));
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+        assert_eq!(prefix_end(b""), None);
+        assert!(in_range(b"app/x", b"app/", Some(b"app0")));
+        assert!(!in_range(b"app0", b"app/", Some(b"app0")));
+        assert!(!in_range(b"ap", b"app/", None));
+        assert!(in_range(b"\xff\xff", b"", None));
+    }
+
+    #[test]
+    fn test_incremented() {
+        assert_eq!(incremented(None, 5).unwrap(), 5);
+        assert_eq!(incremented(Some(b"-3"), 1).unwrap(), -2);
+        assert_eq!(incremented(Some(b"10\n"), -10).unwrap(), 0);
+        let max = i64::MAX.to_string();
+        let err = incremented(Some(max.as_bytes()), 1).unwrap_err();
+        assert_eq!(command::refusal(&err), Some(CommandError::Overflow));
+        for bad in [&b"1.5"[..], b"abc", b"", b"\xff"] {
+            let err = incremented(Some(bad), 1).unwrap_err();
+            assert_eq!(command::refusal(&err), Some(CommandError::NotInteger));
+        }
+        let meta = counter_meta(None, 7);
+        assert_eq!((meta.created, meta.modified), (7, 7));
+        assert_eq!(counter_meta(Some(meta), 9).created, 7);
+    }
+}
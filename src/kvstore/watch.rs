@@ -0,0 +1,348 @@
+//
+// Change notifications for a KVStorage
+//
+// WatchedKVStore wraps a store and publishes an Event for each key a write
+// changes on a tokio broadcast channel, which watchers subscribe to. Every
+// node applies every committed write, so a watcher sees the same changes
+// whichever node it's connected to. Watchers which fall more than
+// EVENT_BUFFER events behind get RecvError::Lagged and have to start over.
+//
+// Dropping a namespace publishes one Dropped event rather than one for each of
+// its keys, and installing a raft snapshot a Restored event as the writes it
+// skipped aren't known. Events for writes made through KVStorage::atomic are
+// held back until they're committed.
+//
+
+use std::io::Result;
+
+use tokio::sync::broadcast;
+
+use super::{KVStorage, StoreStats, ValueInfo, ValueMeta};
+use crate::namespace::{Namespace, Quota};
+
+pub const EVENT_BUFFER: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Put,
+    Delete,
+    // every key in ns is gone, key is empty
+    Dropped,
+    // any key in any namespace may have changed, ns and key are empty
+    Restored,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub ns: String,
+    pub key: Vec<u8>,
+    pub kind: EventKind,
+    // the new value and its metadata, empty for deletes
+    pub value: Vec<u8>,
+    pub meta: Option<ValueMeta>,
+}
+
+pub struct WatchedKVStore<S> {
+    store: S,
+    events: broadcast::Sender<Event>,
+    // events of an atomic write which hasn't been committed yet
+    pending: Option<Vec<Event>>,
+}
+
+impl<S: KVStorage> WatchedKVStore<S> {
+    pub fn new(store: S) -> WatchedKVStore<S> {
+        let (events, _rx) = broadcast::channel(EVENT_BUFFER);
+        WatchedKVStore {
+            store: store,
+            events: events,
+            pending: None,
+        }
+    }
+
+    // subscribe() to the returned sender to start receiving events
+    pub fn events(&self) -> broadcast::Sender<Event> {
+        self.events.clone()
+    }
+
+    // copying values for events is skipped while nobody is watching
+    fn watched(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    fn publish(&mut self, ns: &str, key: &[u8], kind: EventKind, value: Vec<u8>) {
+        let meta = match kind {
+            EventKind::Put => self.store.get_meta(ns, key).unwrap_or(None),
+            _ => None,
+        };
+        let event = Event {
+            ns: ns.to_string(),
+            key: key.to_vec(),
+            kind: kind,
+            value: value,
+            meta: meta,
+        };
+        match &mut self.pending {
+            Some(pending) => pending.push(event),
+            None => {
+                let _ = self.events.send(event);
+            }
+        }
+    }
+}
+
+impl<S: KVStorage> KVStorage for WatchedKVStore<S> {
+    fn get(&self, ns: &str, key: &[u8]) -> Result<Vec<u8>> {
+        self.store.get(ns, key)
+    }
+
+    fn delete(&mut self, ns: &str, key: &[u8]) -> Result<bool> {
+        let deleted = self.store.delete(ns, key)?;
+        if deleted && self.watched() {
+            self.publish(ns, key, EventKind::Delete, Vec::new());
+        }
+        Ok(deleted)
+    }
+
+    fn set_with_meta(
+        &mut self,
+        ns: &str,
+        key: &[u8],
+        buf: Vec<u8>,
+        meta: Option<ValueMeta>,
+    ) -> Result<bool> {
+        let value = match self.watched() {
+            true => Some(buf.clone()),
+            false => None,
+        };
+        let res = self.store.set_with_meta(ns, key, buf, meta)?;
+        if let Some(value) = value {
+            self.publish(ns, key, EventKind::Put, value);
+        }
+        Ok(res)
+    }
+
+    fn get_meta(&self, ns: &str, key: &[u8]) -> Result<Option<ValueMeta>> {
+        self.store.get_meta(ns, key)
+    }
+
+    fn incr(&mut self, ns: &str, key: &[u8], by: i64, modified: u64) -> Result<i64> {
+        let n = self.store.incr(ns, key, by, modified)?;
+        if self.watched() {
+            self.publish(ns, key, EventKind::Put, n.to_string().into_bytes());
+        }
+        Ok(n)
+    }
+
+    fn delete_range(&mut self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        let keys = match self.watched() {
+            true => self.store.range_keys(ns, start, end, usize::MAX)?,
+            false => Vec::new(),
+        };
+        let deleted = self.store.delete_range(ns, start, end)?;
+        for key in keys {
+            self.publish(ns, &key, EventKind::Delete, Vec::new());
+        }
+        Ok(deleted)
+    }
+
+    fn count_range(&self, ns: &str, start: &[u8], end: Option<&[u8]>) -> Result<u64> {
+        self.store.count_range(ns, start, end)
+    }
+
+    fn range_keys(
+        &self,
+        ns: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.store.range_keys(ns, start, end, limit)
+    }
+
+    fn put_namespace(&mut self, name: &str, quota: Quota) -> Result<bool> {
+        self.store.put_namespace(name, quota)
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<bool> {
+        let dropped = self.store.drop_namespace(name)?;
+        if dropped && self.watched() {
+            self.publish(name, b"", EventKind::Dropped, Vec::new());
+        }
+        Ok(dropped)
+    }
+
+    fn namespaces(&self) -> Result<Vec<Namespace>> {
+        self.store.namespaces()
+    }
+
+    fn namespace(&self, name: &str) -> Result<Option<Namespace>> {
+        self.store.namespace(name)
+    }
+
+    fn applied_index(&self) -> Result<u64> {
+        self.store.applied_index()
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<bool> {
+        self.store.set_applied_index(index)
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_session(client_id)
+    }
+
+    fn set_session(&mut self, client_id: &str, session: Vec<u8>) -> Result<bool> {
+        self.store.set_session(client_id, session)
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_user(name)
+    }
+
+    fn set_user(&mut self, name: &str, user: Vec<u8>) -> Result<bool> {
+        self.store.set_user(name, user)
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<bool> {
+        self.store.delete_user(name)
+    }
+
+    fn get_acl(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_acl(key)
+    }
+
+    fn set_acl(&mut self, key: &str, buf: Vec<u8>) -> Result<bool> {
+        self.store.set_acl(key, buf)
+    }
+
+    fn delete_acl(&mut self, key: &str) -> Result<bool> {
+        self.store.delete_acl(key)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.store.stats()
+    }
+
+    fn scrub(&self) -> Result<Vec<String>> {
+        self.store.scrub()
+    }
+
+    fn value_info(&self, ns: &str, key: &[u8]) -> Result<ValueInfo> {
+        self.store.value_info(ns, key)
+    }
+
+    fn get_chunk(&self, ns: &str, key: &[u8], info: &ValueInfo, n: u32) -> Result<Vec<u8>> {
+        self.store.get_chunk(ns, key, info, n)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.store.sync()
+    }
+
+    fn atomic(&mut self, f: &mut dyn FnMut(&mut dyn KVStorage) -> Result<()>) -> Result<()> {
+        let events = self.events.clone();
+        let mut pending = Vec::new();
+        self.store.atomic(&mut |store| {
+            let mut watched = WatchedKVStore {
+                store: store,
+                events: events.clone(),
+                pending: Some(Vec::new()),
+            };
+            let res = f(&mut watched);
+            pending.extend(watched.pending.take().unwrap_or_default());
+            res
+        })?;
+        for event in pending {
+            let _ = self.events.send(event);
+        }
+        Ok(())
+    }
+
+    fn restored(&mut self) {
+        self.store.restored();
+        if self.watched() {
+            self.publish("", b"", EventKind::Restored, Vec::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::memstore::MemKVStore;
+
+    #[test]
+    fn test_watched_kvstore() {
+        let mut store = WatchedKVStore::new(MemKVStore::new());
+        // nobody is watching yet
+        store.set("", b"early", b"x".to_vec()).unwrap();
+
+        let mut events = store.events().subscribe();
+        store.set("", b"foo", b"bar".to_vec()).unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, EventKind::Put);
+        assert_eq!((event.key, event.value), (b"foo".to_vec(), b"bar".to_vec()));
+
+        assert_eq!(store.incr("", b"count", 2, 1).unwrap(), 2);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.value, b"2");
+        assert!(event.meta.is_some());
+
+        // deleting a missing key changes nothing
+        assert!(!store.delete("", b"missing").unwrap());
+        assert!(store.delete("", b"foo").unwrap());
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.key),
+            (EventKind::Delete, b"foo".to_vec())
+        );
+
+        assert_eq!(store.delete_prefix("", b"").unwrap(), 2);
+        let deleted: Vec<Vec<u8>> = (0..2).map(|_| events.try_recv().unwrap().key).collect();
+        assert_eq!(deleted, vec![b"count".to_vec(), b"early".to_vec()]);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_watched_drop_and_restore() {
+        let mut store = WatchedKVStore::new(MemKVStore::new());
+        store.put_namespace("team", Quota::default()).unwrap();
+        store.set("team", b"foo", b"bar".to_vec()).unwrap();
+        let mut events = store.events().subscribe();
+
+        assert!(store.drop_namespace("team").unwrap());
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.ns, event.key),
+            (EventKind::Dropped, String::from("team"), Vec::new())
+        );
+        // nothing to drop the second time
+        assert!(!store.drop_namespace("team").unwrap());
+        assert!(events.try_recv().is_err());
+
+        store.restored();
+        assert_eq!(events.try_recv().unwrap().kind, EventKind::Restored);
+    }
+
+    #[test]
+    fn test_watched_atomic() {
+        let mut store = WatchedKVStore::new(MemKVStore::new());
+        let mut events = store.events().subscribe();
+        store
+            .atomic(&mut |st| {
+                st.set("", b"foo", b"bar".to_vec())?;
+                st.delete("", b"foo").map(|_| ())
+            })
+            .unwrap();
+        assert_eq!(events.try_recv().unwrap().kind, EventKind::Put);
+        assert_eq!(events.try_recv().unwrap().kind, EventKind::Delete);
+
+        // nothing is published for writes which aren't committed
+        let res = store.atomic(&mut |st| {
+            st.set("", b"foo", b"bar".to_vec())?;
+            Err(std::io::Error::other("failed"))
+        });
+        assert!(res.is_err());
+        assert!(events.try_recv().is_err());
+    }
+}
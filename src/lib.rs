@@ -1 +1,15 @@
+pub mod acl;
+pub mod batcher;
+pub mod checksum;
+pub mod command;
+pub mod expirer;
+pub mod jsondoc;
+pub mod kvstore;
+pub mod metrics;
+pub mod namespace;
+pub mod pd;
+pub mod raftnode;
 pub mod raftstore;
+pub mod scrubber;
+pub mod tls;
+pub mod transport;
@@ -0,0 +1,57 @@
+//
+// Server logging via slog
+//
+// Builds the main logger (terminal or json on stdout, filtered by level) and an
+// optional json access log written to a file. Both are async drains so a slow
+// console doesn't hold up request handling.
+//
+
+use std::fs::OpenOptions;
+use std::io::Result;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use slog::{o, Drain, Level, LevelFilter, Logger, Never};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Term,
+    Json,
+}
+
+pub fn parse_level(s: &str) -> std::result::Result<Level, String> {
+    Level::from_str(s).map_err(|_| format!("invalid log level {}", s))
+}
+
+fn async_logger<D>(drain: D, level: Level) -> Logger
+where
+    D: Drain<Ok = (), Err = Never> + Send + 'static,
+{
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = LevelFilter::new(drain, level).fuse();
+    Logger::root(drain, o!())
+}
+
+pub fn build_logger(format: LogFormat, level: Level) -> Logger {
+    match format {
+        LogFormat::Term => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            async_logger(drain, level)
+        }
+        LogFormat::Json => {
+            let drain = slog_json::Json::new(std::io::stdout())
+                .add_default_keys()
+                .build()
+                .fuse();
+            async_logger(drain, level)
+        }
+    }
+}
+
+// json lines access log appended to path
+pub fn build_access_logger(path: &str) -> Result<Logger> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let drain = slog_json::Json::new(file).add_default_keys().build().fuse();
+    Ok(async_logger(drain, Level::Info))
+}
@@ -1,51 +1,442 @@
 //
-// Server entrypoint - creates a store::MemStore instance (shared_store)
-// and has lots of hyper.rs/tokio example copy/paste to set up web server
+// Server entrypoint - opens the --store backend (shared_store, lmdb by default),
+// starts a raft node which applies writes to it and has lots of hyper.rs/tokio
+// example copy/paste to set up web server
 // The web service entrypoint is handlers::router(...)
 //
+// With --tls-cert the listeners serve https, handshakes are done in
+// tls_incoming(...) and SIGHUP reloads the certificates. --peer-listen adds a
+// second listener just for raft traffic, and --grpc-listen one for the gRPC
+// api (see grpc.rs). Every write applied to the store is published for gRPC
+// watches by wrapping it in a WatchedKVStore. --resp-listen adds one for redis
+// clients, which resp.rs serves itself rather than hyper.
+//
+// On SIGINT or SIGTERM the client, gRPC and redis listeners stop accepting and drain
+// in flight requests (for up to --shutdown-timeout-secs), then the peer listener
+// and the raft node are stopped and both lmdb environments are synced.
+//
 
-use hyper::server::conn::AddrStream;
+use clap::Parser;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
+use hyper::{Body, Request, Server};
+use slog::{debug, error, info, o, warn, Logger};
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
 
+mod auth;
+mod config;
+mod grpc;
 mod handlers;
-mod kvstore;
-pub mod raftstore;
+mod logging;
+mod resp;
 
-use crate::handlers::router;
+use crate::auth::{Auth, ClientIdentity};
+use crate::config::ServerConfig;
+use crate::handlers::{router, Listener, ServerState};
+use fekv::batcher::Batcher;
+use fekv::expirer::Expirer;
+use fekv::kvstore;
+use fekv::kvstore::asyncstore::AsyncKVStore;
+use fekv::kvstore::watch::WatchedKVStore;
+use fekv::kvstore::{BoxedKVStorage, StoreOptions};
+use fekv::raftnode::RaftNode;
+use fekv::raftstore::RaftDiskStorage;
+use fekv::scrubber::Scrubber;
+use fekv::tls::{self, TlsReloader};
+use fekv::transport::HttpTransport;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // let shared_store = Arc::new(Mutex::new(store::memstore::MemStore::new()));
-    let shared_store = Arc::new(Mutex::new(kvstore::diskstore::DiskKVStore::new()));
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cfg = ServerConfig::parse();
 
-    let make_svc = make_service_fn(move |conn: &AddrStream| {
-        let addr = conn.remote_addr();
-        let shared_store = shared_store.clone();
-        let service = service_fn(move |req| router(req, addr, shared_store.to_owned()));
-        async move { Ok::<_, Infallible>(service) }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = cfg.workers {
+        runtime.worker_threads(workers);
+    }
+    runtime
+        .max_blocking_threads(cfg.blocking_threads)
+        .enable_all()
+        .build()?
+        .block_on(run(cfg))
+}
+
+async fn run(cfg: ServerConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let logger = logging::build_logger(cfg.log_format, cfg.log_level).new(o!("node" => cfg.id));
+    let access_log = match &cfg.access_log {
+        Some(path) => Some(logging::build_access_logger(path)?.new(o!("node" => cfg.id))),
+        None => None,
+    };
+
+    if cfg.store == "lmdb" && cfg.max_key_size > kvstore::diskstore::MAX_KEY_SIZE {
+        return Err(format!(
+            "--max-key-size can't be more than {}",
+            kvstore::diskstore::MAX_KEY_SIZE
+        )
+        .into());
+    }
+    let tls = match cfg.tls_files() {
+        Some(files) => Some(TlsReloader::new(files)?),
+        None => None,
+    };
+    if tls.is_some() && cfg.peer_listen.is_some() && cfg.tls_ca.is_none() {
+        return Err("--peer-listen with TLS needs --tls-ca to verify peers".into());
+    }
+    if cfg.auth_client_cert && cfg.tls_ca.is_none() {
+        return Err("--auth-client-cert needs --tls-ca to verify client certificates".into());
+    }
+
+    let store = WatchedKVStore::new(kvstore::open(
+        &cfg.store,
+        &StoreOptions {
+            chunk_size: cfg.chunk_size,
+            data_dir: cfg.data_dir.clone(),
+        },
+    )?);
+    let events = store.events();
+    let store: BoxedKVStorage = Box::new(store);
+    let shared_store = Arc::new(RwLock::new(store));
+
+    // peers are the other members, each a voter unless it's a learner
+    let mut members: Vec<u64> = cfg.peers.iter().map(|(id, _)| *id).collect();
+    members.push(cfg.id);
+    members.sort();
+    let (learners, voters): (Vec<u64>, Vec<u64>) = members
+        .into_iter()
+        .partition(|id| cfg.learners.contains(id));
+    if voters.is_empty() {
+        return Err("--learner can't name every member, a cluster needs a voter".into());
+    }
+    let storage = RaftDiskStorage::new_with_db_path(&cfg.data_dir);
+    storage.initialize_with_conf_state((voters, learners));
+    let raft_storage = storage.clone();
+
+    let raft_cfg = raft::Config {
+        id: cfg.id,
+        election_tick: 10,
+        heartbeat_tick: 3,
+        ..Default::default()
+    };
+    let peers: HashMap<u64, String> = cfg.peers.iter().cloned().collect();
+    let transport = match &tls {
+        Some(tls) => HttpTransport::new_with_tls(peers, tls.clone()),
+        None => HttpTransport::new(peers),
+    };
+    let transport = transport.with_token(cfg.peer_token.clone());
+    let scrubber = Arc::new(Scrubber::new(
+        shared_store.clone(),
+        storage.clone(),
+        &logger,
+    ));
+    scrubber.spawn(Duration::from_secs(cfg.scrub_interval_secs));
+    let node = RaftNode::spawn(&raft_cfg, storage, shared_store.clone(), transport, &logger)?;
+    let expirer = Arc::new(Expirer::new(shared_store.clone(), node.handle(), &logger));
+    expirer.spawn(Duration::from_secs(cfg.expire_interval_secs));
+    let batcher = Batcher::spawn(
+        node.handle(),
+        cfg.max_batch_size,
+        Duration::from_millis(cfg.batch_linger_ms),
+    );
+
+    let state = Arc::new(ServerState {
+        store: AsyncKVStore::new(shared_store.clone()),
+        node: node.handle(),
+        batcher: batcher,
+        scrubber: scrubber,
+        max_key_size: cfg.max_key_size,
+        max_value_size: cfg.max_value_size,
+        stale_read_max_lag: cfg.stale_read_max_lag,
+        auth: Auth::from_config(&cfg),
+        peer_listener: cfg.peer_listen.is_some(),
+        peer_verified: cfg.peer_listen.is_some() && cfg.tls_ca.is_some(),
+        logger: logger.clone(),
+        access_log: access_log,
+        next_request_id: AtomicU64::new(1),
+        events: events,
     });
+    if state.auth.enabled()
+        && !state.peer_verified
+        && cfg.peer_token.is_none()
+        && !cfg.peers.is_empty()
+    {
+        warn!(logger, "auth is on without --peer-token or a CA verified --peer-listen, peers' raft messages will be refused");
+    }
 
-    let listen_addr = ([127, 0, 0, 1], 3000).into();
+    if let Some(tls) = tls.clone() {
+        let logger = logger.clone();
+        tokio::spawn(async move { reload_on_hangup(tls, logger).await });
+    }
 
-    let server = Server::bind(&listen_addr).serve(make_svc);
+    // the client listener is stopped first, raft traffic keeps flowing so
+    // in flight writes can commit
+    let (client_stop, client_stopped) = watch::channel(false);
+    let (peer_stop, peer_stopped) = watch::channel(false);
+    let mut draining = client_stopped.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = client_stop.send(true);
+    });
 
-    println!("Listening on http://{}", listen_addr);
+    let listen_addr = SocketAddr::new(cfg.bind, cfg.port);
+    let client = listen(
+        listen_addr,
+        Listener::Client,
+        tls.clone(),
+        state.clone(),
+        client_stopped.clone(),
+    );
+    let peer = async {
+        match cfg.peer_listen {
+            Some(addr) => {
+                listen(
+                    addr,
+                    Listener::Peer,
+                    tls.clone(),
+                    state.clone(),
+                    peer_stopped,
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let grpc = async {
+        match cfg.grpc_listen {
+            Some(addr) => {
+                listen(
+                    addr,
+                    Listener::Grpc,
+                    tls.clone(),
+                    state.clone(),
+                    client_stopped.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let resp = async {
+        match cfg.resp_listen {
+            Some(addr) => {
+                resp::listen(addr, tls.clone(), state.clone(), client_stopped.clone()).await
+            }
+            None => Ok(()),
+        }
+    };
+    let client = async {
+        let (client, grpc, resp) = tokio::join!(client, grpc, resp);
+        client.and(grpc).and(resp)
+    };
+    let drain_timeout = async {
+        let _ = draining.changed().await;
+        info!(logger, "shutting down, draining requests");
+        tokio::time::sleep(Duration::from_secs(cfg.shutdown_timeout_secs)).await;
+    };
+    let client = async {
+        tokio::select! {
+            res = client => res,
+            _ = drain_timeout => {
+                warn!(logger, "requests still in flight after shutdown timeout, dropping them");
+                Ok(())
+            }
+        }
+    };
+    let client = async {
+        let res = client.await;
+        let _ = peer_stop.send(true);
+        res
+    };
+    let (client, peer) = tokio::join!(client, peer);
+    if let Err(e) = client.and(peer) {
+        error!(logger, "server error"; "error" => %e);
+    }
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+    info!(logger, "stopping raft node");
+    let _ = tokio::task::spawn_blocking(move || node.shutdown()).await;
+    if let Err(err) = state.store.sync().await {
+        error!(logger, "failed to sync kv store"; "error" => %err);
+    }
+    if let Err(err) = raft_storage.rl().sync() {
+        error!(logger, "failed to sync raft log"; "error" => %err);
+    }
+    info!(logger, "shutdown complete");
+
+    Ok(())
+}
 
-    if let Err(e) = graceful.await {
-        eprintln!("server error: {}", e);
+// what the router needs to know about a connection, plain or TLS
+trait ConnInfo {
+    fn remote_addr(&self) -> SocketAddr;
+    fn client_identity(&self) -> Option<ClientIdentity>;
+}
+
+impl ConnInfo for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        None
     }
+}
 
+impl ConnInfo for TlsStream<TcpStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.get_ref()
+            .0
+            .peer_addr()
+            .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into())
+    }
+
+    // only verified certificates get this far, the handshake checks them
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        tls::client_identity(self.get_ref().1.peer_certificates()).map(ClientIdentity)
+    }
+}
+
+async fn listen(
+    addr: SocketAddr,
+    listener: Listener,
+    tls: Option<TlsReloader>,
+    state: Arc<ServerState<BoxedKVStorage>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let logger = state.logger.clone();
+    match tls {
+        Some(tls) => {
+            let incoming = tls_incoming(
+                TcpListener::bind(addr).await?,
+                tls,
+                listener,
+                logger.clone(),
+            );
+            info!(logger, "listening"; "addr" => format!("https://{}", addr), "listener" => ?listener);
+            serve(incoming, listener, state, shutdown).await?;
+        }
+        None => {
+            let incoming = AddrIncoming::bind(&addr)?;
+            info!(logger, "listening"; "addr" => format!("http://{}", addr), "listener" => ?listener);
+            serve(incoming, listener, state, shutdown).await?;
+        }
+    }
     Ok(())
 }
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
+async fn serve<I>(
+    incoming: I,
+    listener: Listener,
+    state: Arc<ServerState<BoxedKVStorage>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), hyper::Error>
+where
+    I: Accept,
+    I::Conn: ConnInfo + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let addr = conn.remote_addr();
+        let identity = conn.client_identity();
+        let state = state.clone();
+        let service = service_fn(move |mut req: Request<Body>| {
+            if let Some(identity) = &identity {
+                req.extensions_mut().insert(identity.clone());
+            }
+            router(req, addr, listener, state.to_owned())
+        });
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    // grpc is http/2 only, over TLS the h2 ALPN takes care of it
+    Server::builder(incoming)
+        .http2_only(listener == Listener::Grpc)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
         .await
-        .expect("failed to install CTRL+C signal handler");
+}
+
+// accepts tcp connections and does the TLS handshakes off the accept loop, so
+// a slow client can't hold up others. Each handshake uses the latest config
+fn tls_incoming(
+    tcp: TcpListener,
+    tls: TlsReloader,
+    listener: Listener,
+    logger: Logger,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error> {
+    let (tx, rx) = mpsc::channel::<std::io::Result<TlsStream<TcpStream>>>(64);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, addr) = match tcp.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(logger, "accept failed"; "error" => %err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let config = match listener {
+                Listener::Client => tls.server_config(),
+                Listener::Grpc => tls.grpc_server_config(),
+                // refused at startup without a CA
+                Listener::Peer => match tls.peer_server_config() {
+                    Some(config) => config,
+                    None => continue,
+                },
+            };
+            let acceptor = TlsAcceptor::from(config);
+            let tx = tx.clone();
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Err(err) => {
+                        debug!(logger, "tls handshake failed"; "client" => %addr, "error" => %err)
+                    }
+                }
+            });
+        }
+    });
+    accept::from_stream(ReceiverStream::new(rx))
+}
+
+async fn reload_on_hangup(tls: TlsReloader, logger: Logger) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!(logger, "failed to install SIGHUP handler, tls reload disabled"; "error" => %err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match tls.reload() {
+            Ok(()) => info!(logger, "reloaded tls certificates"),
+            Err(err) => {
+                error!(logger, "failed to reload tls certificates, keeping the old ones"; "error" => %err)
+            }
+        }
+    }
+}
+
+// SIGINT (ctrl-c) or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("failed to install SIGTERM signal handler");
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.expect("failed to install CTRL+C signal handler"),
+        _ = terminate.recv() => {}
+    }
 }
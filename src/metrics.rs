@@ -0,0 +1,107 @@
+//
+// Prometheus metrics, registered in the default prometheus registry and
+// exported by the server's /metrics endpoint
+//
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+
+use crate::batcher::BATCH_SIZE_BUCKETS;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fekv_http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "fekv_http_request_duration_seconds",
+        "HTTP request latency by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref STORE_OP_DURATION: HistogramVec = register_histogram_vec!(
+        "fekv_store_op_duration_seconds",
+        "KVStorage operation latency",
+        &["op"]
+    )
+    .unwrap();
+    pub static ref LMDB_MAP_SIZE: IntGauge =
+        register_int_gauge!("fekv_lmdb_map_size_bytes", "LMDB map size").unwrap();
+    pub static ref LMDB_FILE_SIZE: IntGauge =
+        register_int_gauge!("fekv_lmdb_file_bytes", "Size of the LMDB data file").unwrap();
+    pub static ref STORE_KEYS: IntGauge =
+        register_int_gauge!("fekv_store_keys", "Number of keys in the store").unwrap();
+    pub static ref CHECKSUM_FAILURES: IntCounter = register_int_counter!(
+        "fekv_checksum_failures_total",
+        "Values or raft entries read back with a bad checksum"
+    )
+    .unwrap();
+    pub static ref SCRUB_RUNS: IntCounter =
+        register_int_counter!("fekv_scrub_runs_total", "Completed scrubber passes").unwrap();
+    pub static ref SCRUB_CORRUPT_ITEMS: IntGauge = register_int_gauge!(
+        "fekv_scrub_corrupt_items",
+        "Corrupt keys and raft entries found by the last scrubber pass"
+    )
+    .unwrap();
+    pub static ref EXPIRED_KEYS: IntCounter = register_int_counter!(
+        "fekv_expired_keys_total",
+        "Expired keys deleted by this node's expiry sweeps"
+    )
+    .unwrap();
+    pub static ref RAFT_PROPOSALS: IntCounter =
+        register_int_counter!("fekv_raft_proposals_total", "Raft proposals").unwrap();
+    pub static ref RAFT_PROPOSALS_FAILED: IntCounter = register_int_counter!(
+        "fekv_raft_proposals_failed_total",
+        "Raft proposals which were dropped or failed to apply"
+    )
+    .unwrap();
+    pub static ref RAFT_COMMITTED_ENTRIES: IntCounter = register_int_counter!(
+        "fekv_raft_committed_entries_total",
+        "Raft entries committed"
+    )
+    .unwrap();
+    pub static ref RAFT_APPLIED_ENTRIES: IntCounter = register_int_counter!(
+        "fekv_raft_applied_entries_total",
+        "Raft entries applied to the store"
+    )
+    .unwrap();
+    pub static ref RAFT_ELECTIONS: IntCounter = register_int_counter!(
+        "fekv_raft_elections_total",
+        "Elections started by this node"
+    )
+    .unwrap();
+    pub static ref RAFT_LEADER_CHANGES: IntCounter = register_int_counter!(
+        "fekv_raft_leader_changes_total",
+        "Leader changes seen by this node"
+    )
+    .unwrap();
+    pub static ref RAFT_SNAPSHOT_DURATION: Histogram = register_histogram!(
+        "fekv_raft_snapshot_duration_seconds",
+        "Time taken to apply raft snapshots"
+    )
+    .unwrap();
+    pub static ref RAFT_BATCH_SIZE: Histogram = register_histogram!(
+        "fekv_raft_batch_size",
+        "Client writes per raft proposal",
+        BATCH_SIZE_BUCKETS.iter().map(|b| *b as f64).collect()
+    )
+    .unwrap();
+}
+
+// text exposition format of everything in the default registry
+pub fn encode() -> Vec<u8> {
+    let mut buf = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder.encode(&prometheus::gather(), &mut buf).unwrap();
+    buf
+}
+
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
@@ -0,0 +1,205 @@
+//
+// Namespaces, separate keyspaces sharing a cluster
+//
+// Keys are in the default namespace ("") unless they're in a named one, which
+// is created with Command::PutNamespace and addressed over http as
+// /fekv/{namespace}/{key}. DiskKVStore keeps each namespace in its own lmdb
+// databases. Named namespaces count their keys and bytes (keys plus values)
+// and may have quotas on either, writes which would go over are rejected when
+// applied so every replica agrees on them.
+//
+// Writes the store refuses, rather than fails, are reported as an io::Error
+// wrapping a Rejection, see rejection(...), and sent back to the proposer as
+// CommandResult::Rejected. Patches which don't apply and counters which
+// aren't integers are refused with a command::CommandError instead.
+//
+
+use std::fmt;
+use std::io::Error;
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT: &str = "";
+
+// each namespace takes four lmdb databases, see DiskKVStore
+pub const MAX_NAMESPACES: usize = 64;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub name: String,
+    pub quota: Quota,
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    NoSuchNamespace(String),
+    InvalidNamespace(String),
+    TooManyNamespaces,
+    // keys in the default namespace already start with "{name}/"
+    NamespaceInUse(String),
+    QuotaExceeded(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::NoSuchNamespace(name) => write!(f, "no namespace {}", name),
+            Rejection::InvalidNamespace(name) => write!(f, "invalid namespace name {}", name),
+            Rejection::TooManyNamespaces => {
+                write!(f, "at most {} namespaces can be created", MAX_NAMESPACES)
+            }
+            Rejection::NamespaceInUse(name) => {
+                write!(f, "default namespace has keys starting {}/", name)
+            }
+            Rejection::QuotaExceeded(name) => write!(f, "quota exceeded for namespace {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+pub fn rejected(rejection: Rejection) -> Error {
+    Error::other(rejection)
+}
+
+pub fn rejection(err: &Error) -> Option<Rejection> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Rejection>())
+        .cloned()
+}
+
+// names are used in lmdb database names and url paths, so keep them plain
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// whether another namespace called name can be created, besides not
+// clashing with default namespace keys which the store checks
+pub fn check_create(name: &str, existing: usize) -> Result<(), Error> {
+    if !valid_name(name) {
+        return Err(rejected(Rejection::InvalidNamespace(name.to_string())));
+    }
+    if existing >= MAX_NAMESPACES {
+        return Err(rejected(Rejection::TooManyNamespaces));
+    }
+    Ok(())
+}
+
+// namespace names followed by a / start default namespace keys which would
+// be shadowed by it over http
+pub fn key_prefix(name: &str) -> Vec<u8> {
+    format!("{}/", name).into_bytes()
+}
+
+// the name a "{name}/{rest}" key would be in a namespace by and rest, if it
+// starts with a valid name
+pub fn split_key(key: &[u8]) -> Option<(&str, &[u8])> {
+    let i = key.iter().position(|b| *b == b'/')?;
+    let name = std::str::from_utf8(&key[..i]).ok()?;
+    match valid_name(name) {
+        true => Some((name, &key[i + 1..])),
+        false => None,
+    }
+}
+
+// key as it appears in a /fekv path, "{ns}/{key}" outside the default
+// namespace, which is what acl prefixes are matched against
+pub fn qualified_key(ns: &str, key: &[u8]) -> Vec<u8> {
+    if ns == DEFAULT {
+        return key.to_vec();
+    }
+    let mut qualified = key_prefix(ns);
+    qualified.extend_from_slice(key);
+    qualified
+}
+
+impl Namespace {
+    pub fn new(name: &str, quota: Quota) -> Namespace {
+        Namespace {
+            name: name.to_string(),
+            quota: quota,
+            keys: 0,
+            bytes: 0,
+        }
+    }
+
+    // account for key's value going from old to new bytes long (None when
+    // there's no value), leaves the counts alone if that's over quota
+    pub fn charge(&mut self, key: &[u8], old: Option<u64>, new: Option<u64>) -> Result<(), Error> {
+        let size = |len: Option<u64>| len.map_or(0, |len| key.len() as u64 + len);
+        let keys = (self.keys + new.is_some() as u64).saturating_sub(old.is_some() as u64);
+        let bytes = (self.bytes + size(new)).saturating_sub(size(old));
+        let grew = keys > self.keys || bytes > self.bytes;
+        let over = self.quota.max_keys.is_some_and(|max| keys > max)
+            || self.quota.max_bytes.is_some_and(|max| bytes > max);
+        if grew && over {
+            return Err(rejected(Rejection::QuotaExceeded(self.name.clone())));
+        }
+        self.keys = keys;
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge() {
+        let quota = Quota {
+            max_keys: Some(2),
+            max_bytes: Some(20),
+        };
+        let mut ns = Namespace::new("team", quota);
+        ns.charge(b"a", None, Some(4)).unwrap();
+        ns.charge(b"b", None, Some(4)).unwrap();
+        assert_eq!((ns.keys, ns.bytes), (2, 10));
+
+        // a third key is over max_keys, counts are unchanged
+        let err = ns.charge(b"c", None, Some(1)).unwrap_err();
+        assert_eq!(
+            rejection(&err),
+            Some(Rejection::QuotaExceeded(String::from("team")))
+        );
+        assert_eq!((ns.keys, ns.bytes), (2, 10));
+
+        // overwrites only count the difference
+        ns.charge(b"a", Some(4), Some(14)).unwrap();
+        assert!(ns.charge(b"a", Some(14), Some(15)).is_err());
+        assert_eq!(ns.bytes, 20);
+
+        // shrinking is allowed even when over a lowered quota
+        ns.quota.max_bytes = Some(5);
+        ns.charge(b"a", Some(14), Some(10)).unwrap();
+        ns.charge(b"a", Some(10), None).unwrap();
+        assert_eq!((ns.keys, ns.bytes), (1, 5));
+
+        assert!(rejection(&Error::other("other")).is_none());
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(valid_name("team-a_1"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name("caf\u{e9}"));
+        assert!(!valid_name(&"x".repeat(65)));
+        assert_eq!(qualified_key("", b"k"), b"k");
+        assert_eq!(qualified_key("team", b"k"), b"team/k");
+    }
+}
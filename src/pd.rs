@@ -0,0 +1,488 @@
+//
+// Placement driver (PD) - lightweight region scheduler
+//
+// Stores send a heartbeat for themselves and one for every region they lead.
+// The placement driver keeps the latest view of stores/regions and replies to
+// region heartbeats with an (optional) Operator for the leader to carry out:
+//   - AddPeer / RemovePeer - keep regions at max_replicas and balance region counts
+//   - TransferLeader - balance leader counts across stores
+//
+// Roughly follows the scheduler from tinykv project 3C
+//   https://github.com/talent-plan/tinykv/blob/course/doc/project3-MultiRaftKV.md
+//
+// Can be run in-process (PlacementDriver) or as a separate binary (src/bin/fekv-pd.rs),
+// the helpers at the bottom of this file talk to the binary over http + json.
+//
+
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreHeartbeat {
+    pub store_id: u64,
+    pub address: String,
+    pub capacity: u64,
+    pub available: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegionHeartbeat {
+    pub region_id: u64,
+    pub term: u64,
+    pub leader_store_id: u64,
+    pub peer_store_ids: Vec<u64>,
+    pub approximate_size: u64,
+    pub approximate_keys: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operator {
+    AddPeer {
+        region_id: u64,
+        store_id: u64,
+    },
+    RemovePeer {
+        region_id: u64,
+        store_id: u64,
+    },
+    TransferLeader {
+        region_id: u64,
+        from_store_id: u64,
+        to_store_id: u64,
+    },
+}
+
+impl Operator {
+    // an operator is finished once a heartbeat shows the region in the wanted state
+    fn is_finished(&self, region: &RegionHeartbeat) -> bool {
+        match self {
+            Operator::AddPeer { store_id, .. } => region.peer_store_ids.contains(store_id),
+            Operator::RemovePeer { store_id, .. } => !region.peer_store_ids.contains(store_id),
+            Operator::TransferLeader { to_store_id, .. } => region.leader_store_id == *to_store_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PdConfig {
+    pub max_replicas: usize,
+    // stores which haven't sent a heartbeat within this window are not scheduled onto
+    pub store_down_after: Duration,
+    // only move regions/leaders when the busiest and quietest store differ by more than this
+    pub region_tolerance: usize,
+    pub leader_tolerance: usize,
+}
+
+impl Default for PdConfig {
+    fn default() -> PdConfig {
+        PdConfig {
+            max_replicas: 3,
+            store_down_after: Duration::from_secs(30),
+            region_tolerance: 1,
+            leader_tolerance: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub store: StoreHeartbeat,
+    pub up: bool,
+    pub region_count: usize,
+    pub leader_count: usize,
+    pub region_size: u64,
+}
+
+struct StoreInfo {
+    heartbeat: StoreHeartbeat,
+    last_seen: Instant,
+}
+
+pub struct PlacementDriver {
+    config: PdConfig,
+    stores: HashMap<u64, StoreInfo>,
+    regions: HashMap<u64, RegionHeartbeat>,
+    operators: HashMap<u64, Operator>,
+}
+
+impl PlacementDriver {
+    pub fn new(config: PdConfig) -> PlacementDriver {
+        PlacementDriver {
+            config: config,
+            stores: HashMap::new(),
+            regions: HashMap::new(),
+            operators: HashMap::new(),
+        }
+    }
+
+    pub fn handle_store_heartbeat(&mut self, hb: StoreHeartbeat) {
+        self.handle_store_heartbeat_at(hb, Instant::now());
+    }
+
+    fn handle_store_heartbeat_at(&mut self, hb: StoreHeartbeat, now: Instant) {
+        self.stores.insert(
+            hb.store_id,
+            StoreInfo {
+                heartbeat: hb,
+                last_seen: now,
+            },
+        );
+    }
+
+    // record the region and return the operator (if any) the region leader should run
+    pub fn handle_region_heartbeat(&mut self, hb: RegionHeartbeat) -> Option<Operator> {
+        self.handle_region_heartbeat_at(hb, Instant::now())
+    }
+
+    fn handle_region_heartbeat_at(
+        &mut self,
+        hb: RegionHeartbeat,
+        now: Instant,
+    ) -> Option<Operator> {
+        if let Some(known) = self.regions.get(&hb.region_id) {
+            if hb.term < known.term {
+                // stale heartbeat from an old leader
+                return None;
+            }
+        }
+        let region_id = hb.region_id;
+        self.regions.insert(region_id, hb.clone());
+
+        if let Some(op) = self.operators.get(&region_id) {
+            if !op.is_finished(&hb) {
+                // keep asking until the leader reports the operator as done
+                return Some(op.clone());
+            }
+            self.operators.remove(&region_id);
+        }
+
+        let op = self
+            .check_replicas(&hb, now)
+            .or_else(|| self.balance_leader(&hb, now))
+            .or_else(|| self.balance_region(&hb, now));
+        if let Some(op) = &op {
+            self.operators.insert(region_id, op.clone());
+        }
+        op
+    }
+
+    pub fn operator(&self, region_id: u64) -> Option<&Operator> {
+        self.operators.get(&region_id)
+    }
+
+    pub fn regions(&self) -> Vec<RegionHeartbeat> {
+        let mut regions: Vec<RegionHeartbeat> = self.regions.values().cloned().collect();
+        regions.sort_by_key(|r| r.region_id);
+        regions
+    }
+
+    pub fn store_stats(&self) -> Vec<StoreStats> {
+        self.store_stats_at(Instant::now())
+    }
+
+    fn store_stats_at(&self, now: Instant) -> Vec<StoreStats> {
+        let mut stats: Vec<StoreStats> = self
+            .stores
+            .iter()
+            .map(|(id, s)| StoreStats {
+                store: s.heartbeat.clone(),
+                up: self.is_up(s, now),
+                region_count: self.region_count(*id),
+                leader_count: self.leader_count(*id),
+                region_size: self
+                    .regions
+                    .values()
+                    .filter(|r| r.peer_store_ids.contains(id))
+                    .map(|r| r.approximate_size)
+                    .sum(),
+            })
+            .collect();
+        stats.sort_by_key(|s| s.store.store_id);
+        stats
+    }
+
+    fn is_up(&self, store: &StoreInfo, now: Instant) -> bool {
+        now.saturating_duration_since(store.last_seen) < self.config.store_down_after
+    }
+
+    fn up_stores(&self, now: Instant) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .stores
+            .iter()
+            .filter(|(_, s)| self.is_up(s, now))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn region_count(&self, store_id: u64) -> usize {
+        self.regions
+            .values()
+            .filter(|r| r.peer_store_ids.contains(&store_id))
+            .count()
+    }
+
+    fn leader_count(&self, store_id: u64) -> usize {
+        self.regions
+            .values()
+            .filter(|r| r.leader_store_id == store_id)
+            .count()
+    }
+
+    // up store not already holding the region with the fewest regions
+    fn pick_target_store(&self, region: &RegionHeartbeat, now: Instant) -> Option<u64> {
+        self.up_stores(now)
+            .into_iter()
+            .filter(|id| !region.peer_store_ids.contains(id))
+            .min_by_key(|id| (self.region_count(*id), *id))
+    }
+
+    // non leader peer of the region on the store with the most regions
+    fn pick_source_store(&self, region: &RegionHeartbeat) -> Option<u64> {
+        region
+            .peer_store_ids
+            .iter()
+            .filter(|id| **id != region.leader_store_id)
+            .max_by_key(|id| (self.region_count(**id), **id))
+            .copied()
+    }
+
+    fn check_replicas(&self, region: &RegionHeartbeat, now: Instant) -> Option<Operator> {
+        let replicas = region.peer_store_ids.len();
+        if replicas < self.config.max_replicas {
+            let store_id = self.pick_target_store(region, now)?;
+            return Some(Operator::AddPeer {
+                region_id: region.region_id,
+                store_id: store_id,
+            });
+        }
+        if replicas > self.config.max_replicas {
+            let store_id = self.pick_source_store(region)?;
+            return Some(Operator::RemovePeer {
+                region_id: region.region_id,
+                store_id: store_id,
+            });
+        }
+        None
+    }
+
+    fn balance_leader(&self, region: &RegionHeartbeat, now: Instant) -> Option<Operator> {
+        let up = self.up_stores(now);
+        let leader_count = self.leader_count(region.leader_store_id);
+        let (to_store_id, to_count) = region
+            .peer_store_ids
+            .iter()
+            .filter(|id| **id != region.leader_store_id && up.contains(id))
+            .map(|id| (*id, self.leader_count(*id)))
+            .min_by_key(|(id, count)| (*count, *id))?;
+        if leader_count > to_count + self.config.leader_tolerance {
+            return Some(Operator::TransferLeader {
+                region_id: region.region_id,
+                from_store_id: region.leader_store_id,
+                to_store_id: to_store_id,
+            });
+        }
+        None
+    }
+
+    fn balance_region(&self, region: &RegionHeartbeat, now: Instant) -> Option<Operator> {
+        // move a peer by adding one on a quiet store, check_replicas then removes
+        // the extra replica from the busiest store on a later heartbeat
+        let source = self.pick_source_store(region)?;
+        let target = self.pick_target_store(region, now)?;
+        if self.region_count(source) > self.region_count(target) + self.config.region_tolerance {
+            return Some(Operator::AddPeer {
+                region_id: region.region_id,
+                store_id: target,
+            });
+        }
+        None
+    }
+}
+
+fn to_io_error(err: impl ToString) -> Error {
+    Error::other(err.to_string())
+}
+
+async fn post_json<T: Serialize>(pd_addr: &str, path: &str, body: &T) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(body).map_err(to_io_error)?;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}{}", pd_addr, path))
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(to_io_error)?;
+    let resp = Client::new().request(req).await.map_err(to_io_error)?;
+    if !resp.status().is_success() {
+        return Err(to_io_error(format!("pd returned {}", resp.status())));
+    }
+    let b = hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_err(to_io_error)?;
+    Ok(b.to_vec())
+}
+
+// report a store to a fekv-pd server at pd_addr (host:port)
+pub async fn send_store_heartbeat(pd_addr: &str, hb: &StoreHeartbeat) -> Result<()> {
+    post_json(pd_addr, "/pd/store", hb).await?;
+    Ok(())
+}
+
+// report a region to a fekv-pd server, returns the operator to run (if any)
+pub async fn send_region_heartbeat(
+    pd_addr: &str,
+    hb: &RegionHeartbeat,
+) -> Result<Option<Operator>> {
+    let b = post_json(pd_addr, "/pd/region", hb).await?;
+    serde_json::from_slice(&b).map_err(to_io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(id: u64) -> StoreHeartbeat {
+        StoreHeartbeat {
+            store_id: id,
+            address: format!("127.0.0.1:{}", 3000 + id),
+            capacity: 100,
+            available: 100,
+        }
+    }
+
+    fn region(id: u64, leader: u64, peers: Vec<u64>) -> RegionHeartbeat {
+        RegionHeartbeat {
+            region_id: id,
+            term: 1,
+            leader_store_id: leader,
+            peer_store_ids: peers,
+            approximate_size: 1,
+            approximate_keys: 1,
+        }
+    }
+
+    fn pd_with_stores(n: u64, now: Instant) -> PlacementDriver {
+        let mut pd = PlacementDriver::new(PdConfig::default());
+        for id in 1..=n {
+            pd.handle_store_heartbeat_at(store(id), now);
+        }
+        pd
+    }
+
+    #[test]
+    fn test_pd_add_missing_replicas() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(3, now);
+
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1]), now);
+        assert_eq!(
+            op,
+            Some(Operator::AddPeer {
+                region_id: 1,
+                store_id: 2
+            })
+        );
+        // operator is repeated until the heartbeat shows it finished
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1]), now);
+        assert_eq!(
+            op,
+            Some(Operator::AddPeer {
+                region_id: 1,
+                store_id: 2
+            })
+        );
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1, 2]), now);
+        assert_eq!(
+            op,
+            Some(Operator::AddPeer {
+                region_id: 1,
+                store_id: 3
+            })
+        );
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1, 2, 3]), now);
+        assert_eq!(op, None);
+        assert!(pd.operator(1).is_none());
+    }
+
+    #[test]
+    fn test_pd_remove_extra_replica() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(4, now);
+        pd.handle_region_heartbeat_at(region(2, 2, vec![2, 3, 4]), now);
+
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1, 2, 3, 4]), now);
+        // stores 2/3/4 all hold two regions, ties go to the highest store id
+        assert_eq!(
+            op,
+            Some(Operator::RemovePeer {
+                region_id: 1,
+                store_id: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_pd_balance_leader() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(3, now);
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1, 2, 3]), now);
+        assert_eq!(op, None);
+        // store 1 now leads 2 regions and stores 2 and 3 lead none
+        let op = pd.handle_region_heartbeat_at(region(2, 1, vec![1, 2, 3]), now);
+        assert_eq!(
+            op,
+            Some(Operator::TransferLeader {
+                region_id: 2,
+                from_store_id: 1,
+                to_store_id: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_pd_balance_region() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(4, now);
+        pd.handle_region_heartbeat_at(region(1, 1, vec![1, 2, 3]), now);
+        // stores 1-3 hold two regions each and store 4 is empty so a replica moves onto it
+        let op = pd.handle_region_heartbeat_at(region(2, 2, vec![1, 2, 3]), now);
+        assert_eq!(
+            op,
+            Some(Operator::AddPeer {
+                region_id: 2,
+                store_id: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_pd_skips_down_stores() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(2, now);
+        let later = now + Duration::from_secs(60);
+        pd.handle_store_heartbeat_at(store(1), later);
+
+        // store 2 missed its heartbeats so there's nowhere to add a peer
+        let op = pd.handle_region_heartbeat_at(region(1, 1, vec![1]), later);
+        assert_eq!(op, None);
+        let stats = pd.store_stats_at(later);
+        assert!(stats[0].up);
+        assert!(!stats[1].up);
+    }
+
+    #[test]
+    fn test_pd_ignores_stale_heartbeat() {
+        let now = Instant::now();
+        let mut pd = pd_with_stores(3, now);
+        let mut hb = region(1, 2, vec![1, 2, 3]);
+        hb.term = 5;
+        pd.handle_region_heartbeat_at(hb, now);
+        pd.handle_region_heartbeat_at(region(1, 1, vec![1]), now);
+        assert_eq!(pd.regions()[0].leader_store_id, 2);
+    }
+}
@@ -0,0 +1,850 @@
+//
+// Pipelined raft node
+//
+// Drives a RawNode<RaftDiskStorage> with the work from each Ready split across
+// threads, so slow state machine applies don't hold up heartbeats:
+//   - node loop - ticks, steps messages and proposals, takes readies and
+//     applies conf changes
+//   - persist - appends entries/hardstate/snapshots to the RaftDB, then sends
+//     the persisted messages and reports the ready number back to the node loop
+//   - send - hands raft messages to a Transport, and tells it where members
+//     added by conf changes are
+//   - apply - applies committed commands to a KVStorage, records the applied
+//     index in the store and answers proposal callbacks
+//
+// Uses the async ready api of raft-rs (advance_append_async/on_persist_ready/
+// advance_apply_to), see https://docs.rs/raft/0.7.0/raft/#asynchronous-ready
+//
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use protobuf::Message as PbMessage;
+use raft::prelude::*;
+use raft::StateRole;
+use serde::{Deserialize, Serialize};
+use slog::{error, Logger};
+use tokio::sync::oneshot;
+
+use crate::command::{Command, CommandResult};
+use crate::kvstore::KVStorage;
+use crate::metrics;
+use crate::raftstore::{io_err, promote_learner, within_stale_bound, RaftDiskStorage};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+// how far behind the leader's log a learner may be when it's promoted
+pub const PROMOTE_MAX_LAG: u64 = 100;
+
+pub type ApplyCallback = Box<dyn FnOnce(CommandResult) + Send>;
+
+// Delivers raft messages to other nodes, closures work as a transport too
+pub trait Transport: Send + 'static {
+    fn send(&self, msg: Message);
+
+    // where to reach a member added (Some) or removed (None) by a conf
+    // change, transports with a fixed set of peers can ignore it
+    fn set_peer(&mut self, _id: u64, _addr: Option<String>) {}
+}
+
+impl<F> Transport for F
+where
+    F: Fn(Message) + Send + 'static,
+{
+    fn send(&self, msg: Message) {
+        self(msg)
+    }
+}
+
+enum Msg {
+    Propose { cmd: Command, cb: ApplyCallback },
+    ProposeConfChange { cc: ConfChange, cb: ApplyCallback },
+    PromoteLearner { id: u64, cb: ApplyCallback },
+    Raft(Message),
+    Persisted(u64),
+    Applied(u64),
+    Stop,
+}
+
+struct PersistTask {
+    number: u64,
+    snapshot: Snapshot,
+    entries: Vec<Entry>,
+    hs: Option<HardState>,
+    persisted_messages: Vec<Message>,
+}
+
+enum SendTask {
+    Message(Message),
+    Peer(u64, Option<String>),
+}
+
+enum ApplyTask {
+    Register(u64, ApplyCallback),
+    Fail(u64, String),
+    Done(u64, bool),
+    // leadership changed, pending proposals may or may not commit
+    Abort,
+    // raft installed a snapshot up to this index, see KVStorage::restored
+    Restored(u64),
+    Entries(Vec<Entry>),
+}
+
+// Proposal contexts hold the proposing node id and a per node sequence number,
+// the node which applies an entry with its own id answers the callback
+fn proposal_context(node_id: u64, seq: u64) -> Vec<u8> {
+    let mut ctx = node_id.to_be_bytes().to_vec();
+    ctx.extend_from_slice(&seq.to_be_bytes());
+    ctx
+}
+
+fn parse_proposal_context(ctx: &[u8], node_id: u64) -> Option<u64> {
+    if ctx.len() != 16 || ctx[..8] != node_id.to_be_bytes() {
+        return None;
+    }
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&ctx[8..]);
+    Some(u64::from_be_bytes(seq))
+}
+
+// Snapshot of the node's raft state, refreshed by the node loop
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RaftStatus {
+    pub id: u64,
+    pub term: u64,
+    pub role: String,
+    pub leader_id: u64,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub first_index: u64,
+    pub last_index: u64,
+    pub voters: Vec<u64>,
+    pub learners: Vec<u64>,
+    // the snapshot this node was restored from, 0 if none, see
+    // RaftDB::snapshot_index
+    pub snapshot_index: u64,
+}
+
+impl RaftStatus {
+    fn update(&mut self, raft_group: &RawNode<RaftDiskStorage>) {
+        let raft = &raft_group.raft;
+        self.id = raft.id;
+        self.term = raft.term;
+        self.role = format!("{:?}", raft.state);
+        self.leader_id = raft.leader_id;
+        self.commit_index = raft.raft_log.committed;
+        self.applied_index = raft.raft_log.applied;
+        self.first_index = raft.raft_log.first_index();
+        self.last_index = raft.raft_log.last_index();
+        let store = raft_group.store().rl();
+        self.voters = store.conf_state().voters.clone();
+        self.learners = store.conf_state().learners.clone();
+        self.snapshot_index = store.snapshot_index();
+    }
+
+    // whether a local read may be served, which needs a known leader and the
+    // state machine applied to within max_lag entries of the commit index.
+    // Learners and followers serve reads too, so they may be that stale. A
+    // node restored from a snapshot is missing the writes before it and
+    // never serves reads
+    pub fn can_serve_stale_read(&self, max_lag: u64) -> bool {
+        self.leader_id != raft::INVALID_ID
+            && self.snapshot_index == 0
+            && within_stale_bound(self.commit_index, self.applied_index, max_lag)
+    }
+}
+
+#[derive(Clone)]
+pub struct RaftNodeHandle {
+    id: u64,
+    sender: Sender<Msg>,
+    status: Arc<RwLock<RaftStatus>>,
+}
+
+impl RaftNodeHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // propose a command, cb is called once it has been applied (or failed)
+    pub fn propose(&self, cmd: Command, cb: ApplyCallback) {
+        if let Err(err) = self.sender.send(Msg::Propose { cmd: cmd, cb: cb }) {
+            if let Msg::Propose { cb, .. } = err.0 {
+                cb(CommandResult::Failed(String::from("raft node stopped")));
+            }
+        }
+    }
+
+    pub async fn propose_wait(&self, cmd: Command) -> CommandResult {
+        let (tx, rx) = oneshot::channel();
+        self.propose(
+            cmd,
+            Box::new(move |res| {
+                let _ = tx.send(res);
+            }),
+        );
+        rx.await
+            .unwrap_or(CommandResult::Failed(String::from("proposal dropped")))
+    }
+
+    // add or remove a member, the result is Done once the change has been
+    // applied. An added member's context is the address it's reached at
+    pub async fn change_members(&self, cc: ConfChange) -> CommandResult {
+        let (tx, rx) = oneshot::channel();
+        let cb: ApplyCallback = Box::new(move |res| {
+            let _ = tx.send(res);
+        });
+        if let Err(err) = self.sender.send(Msg::ProposeConfChange { cc: cc, cb: cb }) {
+            if let Msg::ProposeConfChange { cb, .. } = err.0 {
+                cb(CommandResult::Failed(String::from("raft node stopped")));
+            }
+        }
+        rx.await
+            .unwrap_or(CommandResult::Failed(String::from("proposal dropped")))
+    }
+
+    // promote learner id to a voter, the result is Done(true) once the
+    // change has been applied and Done(false) if this node isn't the leader,
+    // id isn't a learner, it's more than PROMOTE_MAX_LAG entries behind or
+    // could have been restored from a snapshot (see raftstore::promote_learner)
+    pub async fn promote_learner(&self, id: u64) -> CommandResult {
+        let (tx, rx) = oneshot::channel();
+        let cb: ApplyCallback = Box::new(move |res| {
+            let _ = tx.send(res);
+        });
+        if let Err(err) = self.sender.send(Msg::PromoteLearner { id: id, cb: cb }) {
+            if let Msg::PromoteLearner { cb, .. } = err.0 {
+                cb(CommandResult::Failed(String::from("raft node stopped")));
+            }
+        }
+        rx.await
+            .unwrap_or(CommandResult::Failed(String::from("proposal dropped")))
+    }
+
+    // step a raft message received from another node
+    pub fn step(&self, msg: Message) {
+        let _ = self.sender.send(Msg::Raft(msg));
+    }
+
+    pub fn stop(&self) {
+        let _ = self.sender.send(Msg::Stop);
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        self.status.read().unwrap().clone()
+    }
+}
+
+pub struct RaftNode {
+    handle: RaftNodeHandle,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl RaftNode {
+    pub fn spawn<S, T>(
+        cfg: &Config,
+        storage: RaftDiskStorage,
+        kv: Arc<tokio::sync::RwLock<S>>,
+        transport: T,
+        logger: &Logger,
+    ) -> raft::Result<RaftNode>
+    where
+        S: KVStorage + Send + Sync + 'static,
+        T: Transport,
+    {
+        // resume applying from the index recorded in the kv store
+        let mut cfg = cfg.clone();
+        let applied = match kv.try_read() {
+            Ok(store) => store.applied_index().unwrap_or(0),
+            Err(_err) => 0,
+        };
+        // the store may have applied entries before the hard state with their
+        // commit index was persisted, they were committed all the same
+        let mut hs = storage.rl().hard_state().clone();
+        if applied > hs.commit && applied <= storage.last_index()? {
+            hs.commit = applied;
+            storage.wl().set_hardstate(hs).map_err(io_err)?;
+        }
+        cfg.applied = cmp::min(applied, storage.rl().hard_state().commit);
+        let mut raft_group = RawNode::new(&cfg, storage.clone(), logger)?;
+        if storage.rl().conf_state().voters == vec![cfg.id] {
+            // lone voter, no need to wait out an election timeout
+            raft_group.campaign()?;
+        }
+
+        let (node_tx, node_rx) = mpsc::channel();
+        let (persist_tx, persist_rx) = mpsc::channel();
+        let (send_tx, send_rx) = mpsc::channel();
+        let (apply_tx, apply_rx) = mpsc::channel();
+
+        let status = Arc::new(RwLock::new(RaftStatus::default()));
+        status.write().unwrap().update(&raft_group);
+
+        let mut threads = Vec::new();
+        let (l, tx, st) = (logger.clone(), send_tx.clone(), status.clone());
+        threads.push(thread::spawn(move || {
+            run_node(raft_group, node_rx, persist_tx, tx, apply_tx, st, l)
+        }));
+        let (l, tx) = (logger.clone(), node_tx.clone());
+        threads.push(thread::spawn(move || {
+            run_persist(storage, persist_rx, send_tx, tx, l)
+        }));
+        threads.push(thread::spawn(move || run_send(transport, send_rx)));
+        let (l, tx, id) = (logger.clone(), node_tx.clone(), cfg.id);
+        threads.push(thread::spawn(move || run_apply(id, kv, apply_rx, tx, l)));
+
+        Ok(RaftNode {
+            handle: RaftNodeHandle {
+                id: cfg.id,
+                sender: node_tx,
+                status: status,
+            },
+            threads: threads,
+        })
+    }
+
+    pub fn handle(&self) -> RaftNodeHandle {
+        self.handle.clone()
+    }
+
+    // stop the node loop and wait for the pipeline to drain
+    pub fn shutdown(self) {
+        self.handle.stop();
+        for t in self.threads {
+            let _ = t.join();
+        }
+    }
+}
+
+fn run_node(
+    mut raft_group: RawNode<RaftDiskStorage>,
+    receiver: Receiver<Msg>,
+    persist_tx: Sender<PersistTask>,
+    send_tx: Sender<SendTask>,
+    apply_tx: Sender<ApplyTask>,
+    status: Arc<RwLock<RaftStatus>>,
+    logger: Logger,
+) {
+    let node_id = raft_group.raft.id;
+    let mut seq: u64 = 0;
+    let mut t = Instant::now();
+    let mut timeout = TICK_INTERVAL;
+
+    loop {
+        match receiver.recv_timeout(timeout) {
+            Ok(Msg::Propose { cmd, cb }) => {
+                seq += 1;
+                metrics::RAFT_PROPOSALS.inc();
+                // register first so the apply thread has the callback before the entry
+                let _ = apply_tx.send(ApplyTask::Register(seq, cb));
+                let ctx = proposal_context(node_id, seq);
+                if let Err(e) = raft_group.propose(ctx, cmd.encode()) {
+                    metrics::RAFT_PROPOSALS_FAILED.inc();
+                    let _ = apply_tx.send(ApplyTask::Fail(seq, e.to_string()));
+                }
+            }
+            Ok(Msg::ProposeConfChange { cc, cb }) => {
+                seq += 1;
+                metrics::RAFT_PROPOSALS.inc();
+                let _ = apply_tx.send(ApplyTask::Register(seq, cb));
+                let ctx = proposal_context(node_id, seq);
+                if let Err(e) = raft_group.propose_conf_change(ctx, cc) {
+                    metrics::RAFT_PROPOSALS_FAILED.inc();
+                    let _ = apply_tx.send(ApplyTask::Fail(seq, e.to_string()));
+                }
+            }
+            Ok(Msg::PromoteLearner { id, cb }) => {
+                seq += 1;
+                let ctx = proposal_context(node_id, seq);
+                let _ = apply_tx.send(ApplyTask::Register(seq, cb));
+                match promote_learner(&mut raft_group, id, PROMOTE_MAX_LAG, ctx) {
+                    Ok(true) => metrics::RAFT_PROPOSALS.inc(),
+                    // answered straight away, nothing was proposed
+                    Ok(false) => {
+                        let _ = apply_tx.send(ApplyTask::Done(seq, false));
+                    }
+                    Err(e) => {
+                        metrics::RAFT_PROPOSALS_FAILED.inc();
+                        let _ = apply_tx.send(ApplyTask::Fail(seq, e.to_string()));
+                    }
+                }
+            }
+            Ok(Msg::Raft(m)) => {
+                if let Err(e) = raft_group.step(m) {
+                    error!(logger, "step raft message fail: {:?}", e);
+                }
+            }
+            Ok(Msg::Persisted(number)) => raft_group.on_persist_ready(number),
+            Ok(Msg::Applied(index)) => raft_group.advance_apply_to(index),
+            Ok(Msg::Stop) => return,
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let d = t.elapsed();
+        t = Instant::now();
+        if d >= timeout {
+            timeout = TICK_INTERVAL;
+            raft_group.tick();
+        } else {
+            timeout -= d;
+        }
+
+        on_ready(&mut raft_group, &persist_tx, &send_tx, &apply_tx, &logger);
+
+        let mut st = status.write().unwrap();
+        let (prev_role, prev_leader) = (st.role.clone(), st.leader_id);
+        st.update(&raft_group);
+        if st.role != prev_role && raft_group.raft.state == StateRole::Candidate {
+            metrics::RAFT_ELECTIONS.inc();
+        }
+        if st.leader_id != prev_leader {
+            metrics::RAFT_LEADER_CHANGES.inc();
+        }
+    }
+}
+
+fn on_ready(
+    raft_group: &mut RawNode<RaftDiskStorage>,
+    persist_tx: &Sender<PersistTask>,
+    send_tx: &Sender<SendTask>,
+    apply_tx: &Sender<ApplyTask>,
+    logger: &Logger,
+) {
+    if !raft_group.has_ready() {
+        return;
+    }
+    let mut ready = raft_group.ready();
+
+    if let Some(ss) = ready.ss() {
+        if ss.raft_state != StateRole::Leader {
+            let _ = apply_tx.send(ApplyTask::Abort);
+        }
+    }
+
+    // Messages which don't depend on persistence go out straight away.
+    for msg in ready.take_messages() {
+        let _ = send_tx.send(SendTask::Message(msg));
+    }
+
+    if !ready.snapshot().is_empty() {
+        let index = ready.snapshot().get_metadata().index;
+        let _ = apply_tx.send(ApplyTask::Restored(index));
+    }
+
+    let committed_entries = ready.take_committed_entries();
+    if !committed_entries.is_empty() {
+        metrics::RAFT_COMMITTED_ENTRIES.inc_by(committed_entries.len() as u64);
+        for entry in committed_entries.iter() {
+            if entry.get_entry_type() != EntryType::EntryConfChange {
+                continue;
+            }
+            // conf changes need the RawNode so are applied here rather than
+            // on the apply thread
+            let mut cc = ConfChange::default();
+            if cc.merge_from_bytes(&entry.data).is_ok() {
+                if let Ok(cs) = raft_group.apply_conf_change(&cc) {
+                    if let Err(e) = raft_group.store().wl().set_conf_state(cs) {
+                        error!(logger, "persist conf state fail: {:?}", e);
+                    }
+                    if let Some(addr) = peer_addr(&cc) {
+                        let _ = send_tx.send(SendTask::Peer(cc.node_id, addr));
+                    }
+                }
+            }
+        }
+        let _ = apply_tx.send(ApplyTask::Entries(committed_entries));
+    }
+
+    let task = PersistTask {
+        number: ready.number(),
+        snapshot: ready.snapshot().clone(),
+        entries: ready.take_entries(),
+        hs: ready.hs().cloned(),
+        persisted_messages: ready.take_persisted_messages(),
+    };
+    let _ = persist_tx.send(task);
+    raft_group.advance_append_async(ready);
+}
+
+// the address change a conf change makes, None if it doesn't make one
+fn peer_addr(cc: &ConfChange) -> Option<Option<String>> {
+    if cc.get_change_type() == ConfChangeType::RemoveNode {
+        return Some(None);
+    }
+    match std::str::from_utf8(&cc.context) {
+        Ok(addr) if !addr.is_empty() => Some(Some(addr.to_string())),
+        _ => None,
+    }
+}
+
+fn run_persist(
+    storage: RaftDiskStorage,
+    receiver: Receiver<PersistTask>,
+    send_tx: Sender<SendTask>,
+    node_tx: Sender<Msg>,
+    logger: Logger,
+) {
+    for task in receiver {
+        let restored = task.snapshot.get_metadata().index;
+        {
+            let mut core = storage.wl();
+            if !task.snapshot.is_empty() {
+                // snapshots only carry raft metadata, the kv store misses
+                // whatever was written before, see RaftDB::snapshot_index
+                let timer = metrics::RAFT_SNAPSHOT_DURATION.start_timer();
+                if let Err(e) = core.apply_snapshot(task.snapshot) {
+                    error!(logger, "apply snapshot fail: {:?}", e);
+                }
+                timer.observe_duration();
+            }
+            if let Err(e) = core.append_with_hardstate(&task.entries, task.hs) {
+                error!(logger, "persist raft log fail: {:?}", e);
+            }
+        }
+        for msg in task.persisted_messages {
+            let _ = send_tx.send(SendTask::Message(msg));
+        }
+        if node_tx.send(Msg::Persisted(task.number)).is_err() {
+            return;
+        }
+        // nothing is applied up to a snapshot, raft may carry on from it
+        // once it's persisted
+        if restored > 0 && node_tx.send(Msg::Applied(restored)).is_err() {
+            return;
+        }
+    }
+}
+
+fn run_send<T: Transport>(mut transport: T, receiver: Receiver<SendTask>) {
+    for task in receiver {
+        match task {
+            SendTask::Message(msg) => transport.send(msg),
+            SendTask::Peer(id, addr) => transport.set_peer(id, addr),
+        }
+    }
+}
+
+fn run_apply<S: KVStorage>(
+    node_id: u64,
+    kv: Arc<tokio::sync::RwLock<S>>,
+    receiver: Receiver<ApplyTask>,
+    node_tx: Sender<Msg>,
+    logger: Logger,
+) {
+    let mut callbacks: HashMap<u64, ApplyCallback> = HashMap::new();
+    for task in receiver {
+        match task {
+            ApplyTask::Register(seq, cb) => {
+                callbacks.insert(seq, cb);
+            }
+            ApplyTask::Fail(seq, reason) => {
+                if let Some(cb) = callbacks.remove(&seq) {
+                    cb(CommandResult::Failed(reason));
+                }
+            }
+            ApplyTask::Done(seq, done) => {
+                if let Some(cb) = callbacks.remove(&seq) {
+                    cb(CommandResult::Done(done));
+                }
+            }
+            ApplyTask::Abort => {
+                for (_, cb) in callbacks.drain() {
+                    cb(CommandResult::Failed(String::from(
+                        "leader changed, proposal outcome unknown",
+                    )));
+                }
+            }
+            ApplyTask::Restored(index) => {
+                // the store carries on from the snapshot
+                let mut store = kv.blocking_write();
+                store.restored();
+                if let Err(e) = store.set_applied_index(index) {
+                    error!(logger, "persist applied index fail: {:?}", e);
+                }
+            }
+            ApplyTask::Entries(entries) => {
+                let last_index = match entries.last() {
+                    Some(e) => e.index,
+                    None => continue,
+                };
+                metrics::RAFT_APPLIED_ENTRIES.inc_by(entries.len() as u64);
+                let mut results = Vec::new();
+                {
+                    let mut store = kv.blocking_write();
+                    // the entries' writes, sessions and the applied index are
+                    // committed together, a crash can't leave some applied
+                    // without the others
+                    let applied = store.atomic(&mut |mut st| {
+                        results.clear();
+                        for entry in entries.iter() {
+                            if entry.get_entry_type() == EntryType::EntryConfChange {
+                                // applied by the node loop, only the proposer is left
+                                if let Some(seq) = parse_proposal_context(&entry.context, node_id) {
+                                    results.push((seq, CommandResult::Done(true)));
+                                }
+                                continue;
+                            }
+                            if entry.data.is_empty()
+                                || entry.get_entry_type() != EntryType::EntryNormal
+                            {
+                                // empty entries from new leaders
+                                continue;
+                            }
+                            let res = match Command::decode(&entry.data) {
+                                Ok(cmd) => {
+                                    let timer = metrics::STORE_OP_DURATION
+                                        .with_label_values(&[cmd.name()])
+                                        .start_timer();
+                                    let res = cmd.apply(&mut st);
+                                    timer.observe_duration();
+                                    res
+                                }
+                                Err(err) => CommandResult::Failed(err.to_string()),
+                            };
+                            if let Some(seq) = parse_proposal_context(&entry.context, node_id) {
+                                results.push((seq, res));
+                            }
+                        }
+                        st.set_applied_index(last_index).map(|_| ())
+                    });
+                    if let Err(e) = applied {
+                        error!(logger, "persist applied entries fail: {:?}", e);
+                        for (_seq, res) in results.iter_mut() {
+                            *res = CommandResult::Failed(e.to_string());
+                        }
+                    }
+                }
+                for (seq, res) in results {
+                    if let CommandResult::Failed(_) = res {
+                        metrics::RAFT_PROPOSALS_FAILED.inc();
+                    }
+                    if let Some(cb) = callbacks.remove(&seq) {
+                        cb(res);
+                    }
+                }
+                if node_tx.send(Msg::Applied(last_index)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::diskstore::DiskKVStore;
+    use crate::kvstore::memstore::MemKVStore;
+    use slog::{o, Discard};
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_proposal_context() {
+        let ctx = proposal_context(3, 42);
+        assert_eq!(parse_proposal_context(&ctx, 3), Some(42));
+        // other nodes ignore the proposal
+        assert_eq!(parse_proposal_context(&ctx, 1), None);
+        assert_eq!(parse_proposal_context(b"", 3), None);
+    }
+
+    #[test]
+    fn test_peer_addr() {
+        let mut cc = ConfChange::default();
+        cc.set_change_type(ConfChangeType::AddNode);
+        assert_eq!(peer_addr(&cc), None);
+        cc.context = b"10.0.0.4:3000".to_vec().into();
+        assert_eq!(peer_addr(&cc), Some(Some(String::from("10.0.0.4:3000"))));
+        cc.set_change_type(ConfChangeType::RemoveNode);
+        assert_eq!(peer_addr(&cc), Some(None));
+    }
+
+    fn wait_for(what: &str, cond: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if cond() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    // a restarted node picks up from its persisted hard state and the kv
+    // store's applied index rather than applying everything again
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restart() {
+        let logger = Logger::root(Discard, o!());
+        let tmp = tempdir().unwrap();
+        let cfg = Config {
+            id: 1,
+            election_tick: 10,
+            heartbeat_tick: 3,
+            ..Default::default()
+        };
+        let incr = Command::Incr {
+            ns: String::new(),
+            key: b"count".to_vec(),
+            by: 1,
+            modified: 1,
+        };
+        let mut term = 0;
+        for run in 1..=2 {
+            let storage = RaftDiskStorage::new_with_db_path(tmp.path());
+            storage.initialize_with_conf_state((vec![1], vec![]));
+            let kv = DiskKVStore::new_with_db_path(tmp.path()).unwrap();
+            let kv = Arc::new(tokio::sync::RwLock::new(kv));
+            let node = RaftNode::spawn(&cfg, storage, kv.clone(), |_| {}, &logger).unwrap();
+            let status = node.handle().status();
+            assert!(status.term > term);
+            term = status.term;
+            for n in 1..=3 {
+                let res = node.handle().propose_wait(incr.clone()).await;
+                assert_eq!(res, CommandResult::Value((run - 1) * 3 + n));
+            }
+            node.shutdown();
+        }
+    }
+
+    // node 2 starts out as a learner of lone voter node 1, serves reads once
+    // it has caught up and is then promoted
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_learner_promotion() {
+        let logger = Logger::root(Discard, o!());
+        let (tmp1, tmp2) = (tempdir().unwrap(), tempdir().unwrap());
+        let nodes: Arc<Mutex<HashMap<u64, RaftNodeHandle>>> = Default::default();
+        let spawn = |id: u64, dir: &std::path::Path| {
+            let storage = RaftDiskStorage::new_with_db_path(dir);
+            storage.initialize_with_conf_state((vec![1], vec![2]));
+            let kv = Arc::new(tokio::sync::RwLock::new(MemKVStore::new()));
+            let peers = nodes.clone();
+            let transport = move |msg: Message| {
+                if let Some(node) = peers.lock().unwrap().get(&msg.to) {
+                    node.step(msg);
+                }
+            };
+            let cfg = Config {
+                id: id,
+                election_tick: 10,
+                heartbeat_tick: 3,
+                ..Default::default()
+            };
+            let node = RaftNode::spawn(&cfg, storage, kv, transport, &logger).unwrap();
+            nodes.lock().unwrap().insert(id, node.handle());
+            node
+        };
+
+        let learner = spawn(2, tmp2.path());
+        let status = learner.handle().status();
+        assert_eq!(status.learners, vec![2]);
+        // no leader yet
+        assert!(!status.can_serve_stale_read(u64::MAX));
+
+        let leader = spawn(1, tmp1.path());
+        let res = leader
+            .handle()
+            .propose_wait(Command::Set {
+                ns: String::new(),
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+                meta: None,
+            })
+            .await;
+        assert_eq!(res, CommandResult::Done(true));
+        let last_index = leader.handle().status().last_index;
+        wait_for("the learner to catch up", || {
+            let status = learner.handle().status();
+            status.applied_index >= last_index && status.can_serve_stale_read(0)
+        });
+
+        // only the leader promotes, and only learners
+        let res = learner.handle().promote_learner(2).await;
+        assert_eq!(res, CommandResult::Done(false));
+        let res = leader.handle().promote_learner(3).await;
+        assert_eq!(res, CommandResult::Done(false));
+
+        let res = leader.handle().promote_learner(2).await;
+        assert_eq!(res, CommandResult::Done(true));
+        // the status catches up with the conf change after the node loop's
+        // next ready
+        for node in [&leader, &learner] {
+            wait_for("the promotion", || {
+                let status = node.handle().status();
+                status.voters == vec![1, 2] && status.learners.is_empty()
+            });
+        }
+
+        nodes.lock().unwrap().clear();
+        leader.shutdown();
+        learner.shutdown();
+    }
+    // a leader whose log was compacted catches learners up with snapshots,
+    // which carry no kv data, so the learner doesn't serve reads and isn't
+    // promoted
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_learner_restored_from_snapshot() {
+        let logger = Logger::root(Discard, o!());
+        let (tmp1, tmp2) = (tempdir().unwrap(), tempdir().unwrap());
+        let nodes: Arc<Mutex<HashMap<u64, RaftNodeHandle>>> = Default::default();
+        let spawn = |id: u64, dir: &std::path::Path| {
+            let storage = RaftDiskStorage::new_with_db_path(dir);
+            storage.initialize_with_conf_state((vec![1], vec![2]));
+            if id == 1 {
+                let mut snapshot = Snapshot::default();
+                let meta = snapshot.mut_metadata();
+                (meta.index, meta.term) = (5, 1);
+                meta.mut_conf_state().voters = vec![1];
+                meta.mut_conf_state().learners = vec![2];
+                storage.wl().apply_snapshot(snapshot).unwrap();
+            }
+            let kv = Arc::new(tokio::sync::RwLock::new(MemKVStore::new()));
+            let peers = nodes.clone();
+            let transport = move |msg: Message| {
+                if let Some(node) = peers.lock().unwrap().get(&msg.to) {
+                    node.step(msg);
+                }
+            };
+            let cfg = Config {
+                id: id,
+                election_tick: 10,
+                heartbeat_tick: 3,
+                ..Default::default()
+            };
+            let node = RaftNode::spawn(&cfg, storage, kv, transport, &logger).unwrap();
+            nodes.lock().unwrap().insert(id, node.handle());
+            node
+        };
+
+        let leader = spawn(1, tmp1.path());
+        let learner = spawn(2, tmp2.path());
+        let res = leader
+            .handle()
+            .propose_wait(Command::Set {
+                ns: String::new(),
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+                meta: None,
+            })
+            .await;
+        assert_eq!(res, CommandResult::Done(true));
+        let last_index = leader.handle().status().last_index;
+        wait_for("the learner to catch up", || {
+            learner.handle().status().applied_index >= last_index
+        });
+        let status = learner.handle().status();
+        assert!(status.snapshot_index > 0);
+        assert!(!status.can_serve_stale_read(u64::MAX));
+
+        let res = leader.handle().promote_learner(2).await;
+        assert_eq!(res, CommandResult::Done(false));
+        assert_eq!(leader.handle().status().learners, vec![2]);
+
+        nodes.lock().unwrap().clear();
+        leader.shutdown();
+        learner.shutdown();
+    }
+}
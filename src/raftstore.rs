@@ -13,23 +13,41 @@
 //  - One DB for raft log entries - key is term/index, value is log entry
 //  - One DB for Config - key is config item, value is state for config
 //  See hashicorp/raft-mdb for this in go
+//
+// The HardState, ConfState and the metadata of the last applied snapshot are
+// kept in the state DB as protobuf bytes and loaded when the DB is opened, a
+// HardState is written in the same transaction as the entries it comes with
+// so a restarted node doesn't forget its term, vote or commit index
+//
+// Every entry is stored with a crc32c of its fields which is checked when it
+// is read back, a mismatch is returned as a StorageError::Other rather than
+// handing raft a damaged entry
 
 // TODO
 #![allow(dead_code)]
 
 use std::cmp;
 use std::fs::create_dir_all;
+use std::io;
 use std::path::Path;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use heed::types::{OwnedType, SerdeJson};
-use heed::{Database, Env, EnvOpenOptions};
+use heed::types::{ByteSlice, OwnedType, SerdeJson, Str};
+use heed::{Database, Env, EnvOpenOptions, RwTxn};
 
+use protobuf::Message as PbMessage;
 use raft::prelude::*;
-use raft::{Error, StorageError};
+use raft::{Error, StateRole, StorageError};
 use serde::{Deserialize, Serialize};
 
+use crate::checksum;
+
 const DB_ENTRIES: &str = "entries";
+const DB_STATE: &str = "state";
+
+const KEY_HARD_STATE: &str = "hard_state";
+const KEY_CONF_STATE: &str = "conf_state";
+const KEY_SNAPSHOT: &str = "snapshot_metadata";
 
 const DB_ENV: &str = "raft.mdb";
 const DB_PATH: &str = "./data";
@@ -44,6 +62,9 @@ struct EntryRef {
     pub data: ::bytes::Bytes,
     pub context: ::bytes::Bytes,
     pub sync_log: bool,
+    // None for entries written before checksums were added
+    #[serde(default)]
+    pub checksum: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -66,7 +87,7 @@ impl EntryTypeRef {
         }
     }
 
-    pub fn from_entry_type(&self, et: EntryType) -> EntryTypeRef {
+    pub fn from_entry_type(et: EntryType) -> EntryTypeRef {
         match et {
             raft::eraftpb::EntryType::EntryNormal => crate::raftstore::EntryTypeRef::EntryNormal,
             raft::eraftpb::EntryType::EntryConfChange => {
@@ -91,15 +112,44 @@ impl EntryRef {
         ent
     }
 
-    pub fn from_entry(&self, e: Entry) -> EntryRef {
-        let tr: EntryTypeRef = EntryTypeRef::EntryNormal;
+    pub fn from_entry(e: Entry) -> EntryRef {
         EntryRef {
-            entry_type: tr.from_entry_type(e.entry_type),
+            entry_type: EntryTypeRef::from_entry_type(e.entry_type),
             term: e.term,
             index: e.index,
             data: e.data.to_owned(),
             context: e.context.to_owned(),
             sync_log: e.sync_log,
+            checksum: None,
+        }
+        .with_checksum()
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        checksum::checksum_parts(&[
+            &[self.entry_type.clone() as u8][..],
+            &self.term.to_le_bytes()[..],
+            &self.index.to_le_bytes()[..],
+            &self.data[..],
+            &self.context[..],
+        ])
+    }
+
+    fn with_checksum(mut self) -> EntryRef {
+        self.checksum = Some(self.compute_checksum());
+        self
+    }
+
+    fn verify(&self) -> std::io::Result<()> {
+        match self.checksum {
+            Some(expected) => {
+                let actual = self.compute_checksum();
+                if expected != actual {
+                    return Err(checksum::corruption_error(expected, actual));
+                }
+                Ok(())
+            }
+            None => Ok(()),
         }
     }
 
@@ -111,6 +161,7 @@ impl EntryRef {
             data: ::bytes::Bytes::new(),
             context: ::bytes::Bytes::new(),
             sync_log: false,
+            checksum: None,
         }
     }
 }
@@ -118,54 +169,67 @@ impl EntryRef {
 pub struct RaftDB {
     env: Env,
     entries: Database<OwnedType<u64>, SerdeJson<EntryRef>>, // SerdeBincode
+    state: Database<Str, ByteSlice>,
     raft_state: RaftState,
     snapshot_metadata: SnapshotMetadata,
     trigger_snap_unavailable: bool,
 }
 
+impl Default for RaftDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RaftDB {
     pub fn new() -> RaftDB {
-        let db_path = Path::join(Path::new(&DB_PATH), &DB_ENV);
-        _ = create_dir_all(&db_path);
-        let env = EnvOpenOptions::new()
-            .map_size(DB_STORE_SIZE)
-            .max_dbs(3)
-            .open(db_path)
-            .unwrap();
-        let entries = env.create_database(Some(&DB_ENTRIES)).unwrap();
-        let raft_state = RaftState::new(HardState::new(), ConfState::new());
-        // TODO write initial raft_state to conf database, for now we use raft::storage::RaftState;
-        RaftDB {
-            env: env,
-            entries: entries,
-            raft_state: raft_state,
-            snapshot_metadata: SnapshotMetadata::new(),
-            trigger_snap_unavailable: false,
-        }
+        RaftDB::new_with_db_path(Path::new(&DB_PATH))
     }
 
     pub fn new_with_db_path(db_path: &std::path::Path) -> RaftDB {
-        let db_path = Path::join(db_path, &DB_ENV);
+        let db_path = Path::join(db_path, DB_ENV);
         _ = create_dir_all(&db_path);
         let env = EnvOpenOptions::new()
             .map_size(DB_STORE_SIZE) // 10MB
             .max_dbs(3)
             .open(db_path)
             .unwrap();
-        let entries = env.create_database(Some(&DB_ENTRIES)).unwrap();
-        let raft_state = RaftState::new(HardState::new(), ConfState::new());
-        // TODO write initial raft_state to conf database, for now we use raft::storage::RaftState;
+        let entries = env.create_database(Some(DB_ENTRIES)).unwrap();
+        let state = env.create_database(Some(DB_STATE)).unwrap();
+        let rtxn = env.read_txn().unwrap();
+        let load = |key| state.get(&rtxn, key).unwrap().unwrap_or_default();
+        let hard_state = HardState::parse_from_bytes(load(KEY_HARD_STATE)).unwrap();
+        let conf_state = ConfState::parse_from_bytes(load(KEY_CONF_STATE)).unwrap();
+        let snapshot_metadata = SnapshotMetadata::parse_from_bytes(load(KEY_SNAPSHOT)).unwrap();
+        rtxn.commit().unwrap();
         RaftDB {
             env: env,
             entries: entries,
-            raft_state: raft_state,
-            snapshot_metadata: SnapshotMetadata::new(),
+            state: state,
+            raft_state: RaftState::new(hard_state, conf_state),
+            snapshot_metadata: snapshot_metadata,
             trigger_snap_unavailable: false,
         }
     }
 
-    pub fn set_hardstate(&mut self, hs: HardState) {
+    fn put_state(
+        &self,
+        wtxn: &mut RwTxn,
+        key: &str,
+        msg: &impl PbMessage,
+    ) -> Result<(), heed::Error> {
+        let buf = msg
+            .write_to_bytes()
+            .map_err(|err| heed::Error::Io(io::Error::other(err)))?;
+        self.state.put(wtxn, key, &buf)
+    }
+
+    pub fn set_hardstate(&mut self, hs: HardState) -> Result<(), heed::Error> {
+        let mut wtxn = self.env.write_txn()?;
+        self.put_state(&mut wtxn, KEY_HARD_STATE, &hs)?;
+        wtxn.commit()?;
         self.raft_state.hard_state = hs;
+        Ok(())
     }
 
     pub fn hard_state(&self) -> &HardState {
@@ -176,8 +240,28 @@ impl RaftDB {
         &mut self.raft_state.hard_state
     }
 
-    pub fn set_conf_state(&mut self, cs: ConfState) {
+    pub fn set_conf_state(&mut self, cs: ConfState) -> Result<(), heed::Error> {
+        let mut wtxn = self.env.write_txn()?;
+        self.put_state(&mut wtxn, KEY_CONF_STATE, &cs)?;
+        wtxn.commit()?;
         self.raft_state.conf_state = cs;
+        Ok(())
+    }
+
+    pub fn conf_state(&self) -> &ConfState {
+        &self.raft_state.conf_state
+    }
+
+    // index of the snapshot this log was restored from, 0 if it never was.
+    // Snapshots only carry raft metadata, not kv data, so the store is
+    // missing every write up to it
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_metadata.index
+    }
+
+    // learners receive the raft log but don't vote or count towards quorum
+    pub fn is_learner(&self, id: u64) -> bool {
+        self.raft_state.conf_state.learners.contains(&id)
     }
 
     fn first_index(&self) -> u64 {
@@ -215,15 +299,18 @@ impl RaftDB {
         let res = self.entries.get(&rtxn, &idx);
         match res {
             Ok(e) => match e {
-                Some(e) => return Ok(e.to_entry()),
-                None => return Err(heed::Error::DatabaseClosing),
+                Some(e) => {
+                    e.verify().map_err(heed::Error::Io)?;
+                    Ok(e.to_entry())
+                }
+                None => Err(heed::Error::DatabaseClosing),
             },
-            Err(err) => return Err(err),
+            Err(err) => Err(err),
         }
     }
 
     fn set_entry(&self, idx: u64, e: Entry) {
-        let er = EntryRef::new().from_entry(e);
+        let er = EntryRef::from_entry(e);
         let mut wtxn = self.env.write_txn().unwrap();
         let _r = self.entries.put(&mut wtxn, &idx, &er);
         let _r = wtxn.commit();
@@ -231,8 +318,21 @@ impl RaftDB {
     }
 
     pub fn append(&mut self, ents: &[Entry]) -> Result<(), heed::Error> {
+        self.append_with_hardstate(ents, None)
+    }
+
+    // append entries and record hs (if any) in one write, as a Ready hands
+    // them over
+    pub fn append_with_hardstate(
+        &mut self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+    ) -> Result<(), heed::Error> {
         if ents.is_empty() {
-            return Ok(());
+            return match hs {
+                Some(hs) => self.set_hardstate(hs),
+                None => Ok(()),
+            };
         }
         if self.first_index() > ents[0].index {
             panic!(
@@ -250,12 +350,18 @@ impl RaftDB {
         }
 
         // Append all entries from `ents`.
-        let mut wtxn = self.env.write_txn().unwrap();
-        for (_, e) in ents.into_iter().enumerate() {
-            let er = EntryRef::new().from_entry(e.clone());
-            let _r = self.entries.put(&mut wtxn, &e.index, &er);
+        let mut wtxn = self.env.write_txn()?;
+        for e in ents.iter() {
+            let er = EntryRef::from_entry(e.clone());
+            self.entries.put(&mut wtxn, &e.index, &er)?;
+        }
+        if let Some(hs) = &hs {
+            self.put_state(&mut wtxn, KEY_HARD_STATE, hs)?;
+        }
+        wtxn.commit()?;
+        if let Some(hs) = hs {
+            self.raft_state.hard_state = hs;
         }
-        let _r = wtxn.commit();
         Ok(())
     }
 
@@ -286,23 +392,29 @@ impl RaftDB {
     }
 
     pub fn apply_snapshot(&mut self, mut snapshot: Snapshot) -> Result<(), heed::Error> {
-        let mut meta = snapshot.take_metadata();
+        let meta = snapshot.take_metadata();
         let index = meta.index;
 
         if self.first_index() > index {
             return Err(heed::Error::DatabaseClosing);
         }
 
-        self.snapshot_metadata = meta.clone();
-
-        self.raft_state.hard_state.term = cmp::max(self.raft_state.hard_state.term, meta.term);
-        self.raft_state.hard_state.commit = index;
+        let mut hs = self.raft_state.hard_state.clone();
+        hs.term = cmp::max(hs.term, meta.term);
+        hs.commit = index;
+        let cs = meta.get_conf_state().clone();
 
-        // clear log entries
-        self.clear();
+        // clear log entries, and record the new state with them
+        let mut wtxn = self.env.write_txn()?;
+        self.entries.clear(&mut wtxn)?;
+        self.put_state(&mut wtxn, KEY_SNAPSHOT, &meta)?;
+        self.put_state(&mut wtxn, KEY_HARD_STATE, &hs)?;
+        self.put_state(&mut wtxn, KEY_CONF_STATE, &cs)?;
+        wtxn.commit()?;
 
-        // Update conf states.
-        self.raft_state.conf_state = meta.take_conf_state();
+        self.snapshot_metadata = meta;
+        self.raft_state.hard_state = hs;
+        self.raft_state.conf_state = cs;
         Ok(())
     }
 
@@ -336,6 +448,33 @@ impl RaftDB {
     pub fn trigger_snap_unavailable(&mut self) {
         self.trigger_snap_unavailable = true;
     }
+
+    // flush the log to disk, lmdb may not have if opened without full syncs
+    pub fn sync(&self) -> Result<(), heed::Error> {
+        self.env.force_sync()
+    }
+
+    // indexes of log entries which fail their checksum or can't be decoded
+    pub fn scrub(&self) -> Result<Vec<u64>, heed::Error> {
+        let rtxn = self.env.read_txn()?;
+        // read raw bytes so an entry too damaged to decode is still reported
+        let raw = self.entries.remap_data_type::<ByteSlice>();
+        let mut corrupt = Vec::new();
+        for item in raw.iter(&rtxn)? {
+            let (idx, buf) = item?;
+            let ok = match serde_json::from_slice::<EntryRef>(buf) {
+                Ok(e) => match e.checksum {
+                    Some(expected) => expected == e.compute_checksum(),
+                    None => true,
+                },
+                Err(_err) => false,
+            };
+            if !ok {
+                corrupt.push(idx);
+            }
+        }
+        Ok(corrupt)
+    }
 }
 
 #[derive(Clone)]
@@ -343,6 +482,12 @@ pub struct RaftDiskStorage {
     raftdb: Arc<RwLock<RaftDB>>, // Do we need to use tokio::sync::RwLock?
 }
 
+impl Default for RaftDiskStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RaftDiskStorage {
     pub fn new() -> RaftDiskStorage {
         RaftDiskStorage {
@@ -369,9 +514,31 @@ impl RaftDiskStorage {
     where
         ConfState: From<T>,
     {
-        assert!(!self.initial_state().unwrap().initialized());
-        let mut core = self.wl();
-        core.raft_state.conf_state = ConfState::from(conf_state);
+        let conf_state = ConfState::from(conf_state);
+        for id in conf_state.learners.iter() {
+            assert!(
+                !conf_state.voters.contains(id),
+                "node {} can't be both a voter and a learner",
+                id
+            );
+        }
+        // a store reopened from disk keeps the members it had
+        if self.initial_state().unwrap().initialized() {
+            return;
+        }
+        self.wl().set_conf_state(conf_state).unwrap();
+    }
+
+    // Create a store for a node which starts out as a non-voting learner, voters
+    // are the current members and learners must include this node's own id
+    pub fn new_learner_with_db_path(
+        db_path: &std::path::Path,
+        voters: Vec<u64>,
+        learners: Vec<u64>,
+    ) -> RaftDiskStorage {
+        let store = RaftDiskStorage::new_with_db_path(db_path);
+        store.initialize_with_conf_state((voters, learners));
+        store
     }
 
     pub fn rl(&self) -> RwLockReadGuard<'_, RaftDB> {
@@ -408,9 +575,63 @@ pub fn limit_size(entries: &mut Vec<Entry>, max: Option<u64>) {
     entries.truncate(limit);
 }
 
+// A learner is ready to be promoted to a voter once the leader has seen it
+// replicate to within max_lag entries of the leader's last index
+pub fn learner_caught_up(matched: u64, leader_last_index: u64, max_lag: u64) -> bool {
+    matched + max_lag >= leader_last_index
+}
+
+// Reads served from learners (or followers) may be stale, only serve them while
+// the local state machine is within max_lag entries of the known commit index
+pub fn within_stale_bound(committed: u64, applied: u64, max_lag: u64) -> bool {
+    applied + max_lag >= committed
+}
+
+// Leader side promotion of a learner to a voter, proposes an AddNode conf change
+// with context for the learner once it has caught up. Returns Ok(false) if this
+// node isn't the leader, id isn't a learner or the learner is still catching up.
+// Also refused when the leader's log doesn't start at the first entry, as it
+// then catches learners up with snapshots, which carry no kv data.
+pub fn promote_learner(
+    raft_group: &mut RawNode<RaftDiskStorage>,
+    id: u64,
+    max_lag: u64,
+    context: Vec<u8>,
+) -> raft::Result<bool> {
+    if raft_group.raft.state != StateRole::Leader || !raft_group.store().rl().is_learner(id) {
+        return Ok(false);
+    }
+    if raft_group.raft.raft_log.first_index() > 1 {
+        return Ok(false);
+    }
+    let matched = match raft_group.raft.prs().get(id) {
+        Some(pr) => pr.matched,
+        None => return Ok(false),
+    };
+    if !learner_caught_up(matched, raft_group.raft.raft_log.last_index(), max_lag) {
+        return Ok(false);
+    }
+    let mut cc = ConfChange {
+        node_id: id,
+        ..Default::default()
+    };
+    cc.set_change_type(ConfChangeType::AddNode);
+    raft_group.propose_conf_change(context, cc)?;
+    Ok(true)
+}
+
 fn compute_size(ent: &Entry) -> u32 {
     // hack
-    ent.data.len() as u32 + ent.context.len() as u32 + 4 as u32
+    ent.data.len() as u32 + ent.context.len() as u32 + 4_u32
+}
+
+// heed::Error isn't Send + Sync as StorageError::Other needs, io errors (e.g.
+// checksum failures) are passed on as they are so they can still be told apart
+pub(crate) fn io_err(err: heed::Error) -> io::Error {
+    match err {
+        heed::Error::Io(err) => err,
+        err => io::Error::other(err.to_string()),
+    }
 }
 
 impl Storage for RaftDiskStorage {
@@ -442,7 +663,10 @@ impl Storage for RaftDiskStorage {
         let mut ents: Vec<Entry> = std::vec::Vec::new();
 
         for k in low..high {
-            ents.push(core.get_entry(k as u64).unwrap());
+            match core.get_entry(k) {
+                Ok(e) => ents.push(e),
+                Err(err) => return Err(Error::Store(StorageError::Other(Box::new(io_err(err))))),
+            }
         }
         limit_size(&mut ents, max_size);
         Ok(ents)
@@ -466,6 +690,9 @@ impl Storage for RaftDiskStorage {
         let res = core.get_entry(idx);
         match res {
             Ok(e) => Ok(e.term),
+            Err(heed::Error::Io(err)) if checksum::is_corruption(&err) => {
+                Err(Error::Store(StorageError::Other(Box::new(err))))
+            }
             Err(_err) => Err(Error::Store(StorageError::Unavailable)),
         }
     }
@@ -508,27 +735,28 @@ mod test {
 
     use std::panic::{self, AssertUnwindSafe};
 
-    use super::{RaftDiskStorage, Storage};
-    use raft::eraftpb::{ConfState, Entry, Snapshot};
+    use super::{learner_caught_up, within_stale_bound, EntryRef, RaftDiskStorage, Storage};
+    use raft::eraftpb::{ConfState, Entry, HardState, Snapshot};
     use raft::GetEntriesContext;
     use tempfile::tempdir;
 
-    fn temp_store_with_entries(ents: &Vec<Entry>) -> RaftDiskStorage {
+    fn temp_store_with_entries(ents: &[Entry]) -> RaftDiskStorage {
         let tmp = tempdir().unwrap();
         let storage = RaftDiskStorage::new_with_db_path(tmp.as_ref());
         storage.wl().clear();
-        for (_, e) in ents.clone().drain(..).enumerate() {
+        for e in ents.iter().cloned() {
             let core = storage.wl();
-            core.set_entry(e.index as u64, e);
+            core.set_entry(e.index, e);
         }
         storage
     }
 
     fn new_entry(index: u64, term: u64) -> Entry {
-        let mut e = Entry::default();
-        e.term = term;
-        e.index = index;
-        e
+        Entry {
+            term: term,
+            index: index,
+            ..Default::default()
+        }
     }
 
     fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
@@ -556,7 +784,7 @@ mod test {
         for (i, (idx, wterm)) in tests.drain(..).enumerate() {
             let t = storage.term(idx);
             // raft errors are crate private so just check if we got any err when we expect an error
-            if wterm.is_err() && !t.is_err() {
+            if wterm.is_err() && t.is_ok() {
                 panic!("#{}: expect res {:?}, got {:?}", i, wterm, t);
             }
             if wterm.is_ok() {
@@ -579,7 +807,7 @@ mod test {
             new_entry(6, 6),
         ];
         let storage = temp_store_with_entries(&ents);
-        let max_u64 = u64::max_value();
+        let max_u64 = u64::MAX;
         let mut tests = vec![
             (2, 6, max_u64, Err("err")),
             (3, 4, max_u64, Ok(vec![new_entry(3, 3)])),
@@ -598,21 +826,21 @@ mod test {
                 4,
                 7,
                 // u64::from(size_of(&ents[1]) + size_of(&ents[2])),
-                8 as u64,
+                8_u64,
                 Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
             ),
             (
                 4,
                 7,
                 // u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3]) / 2),
-                10 as u64,
+                10_u64,
                 Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
             ),
             (
                 4,
                 7,
                 // u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3]) - 1),
-                11 as u64,
+                11_u64,
                 Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
             ),
             // all
@@ -620,13 +848,13 @@ mod test {
                 4,
                 7,
                 // u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3])),
-                12 as u64,
+                12_u64,
                 Ok(vec![new_entry(4, 4), new_entry(5, 5), new_entry(6, 6)]),
             ),
         ];
         for (i, (lo, hi, maxsize, wentries)) in tests.drain(..).enumerate() {
             let e = storage.entries(lo, hi, maxsize, GetEntriesContext::empty(false));
-            if e.is_err() && !wentries.is_err() {
+            if e.is_err() && wentries.is_ok() {
                 panic!("#{}: expect entries {:?}, got {:?}", i, wentries, e);
             }
             if wentries.is_ok() {
@@ -707,8 +935,10 @@ mod test {
     fn test_storage_create_snapshot() {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
         let nodes = vec![1, 2, 3];
-        let mut conf_state = ConfState::default();
-        conf_state.voters = nodes.clone();
+        let conf_state = ConfState {
+            voters: nodes.clone(),
+            ..Default::default()
+        };
 
         let unavailable = Err("err");
         let mut tests = vec![
@@ -728,13 +958,11 @@ mod test {
             }
 
             let result = storage.snapshot(windex, 0);
-            if wresult.is_err() && !result.is_err() {
+            if wresult.is_err() && result.is_ok() {
                 panic!("#{}: want {:?}, got {:?}", i, wresult, result);
             }
-            if wresult.is_ok() {
-                if result.as_ref().unwrap() != wresult.as_ref().unwrap() {
-                    panic!("#{}: want {:?}, got {:?}", i, wresult, result);
-                }
+            if wresult.is_ok() && result.as_ref().unwrap() != wresult.as_ref().unwrap() {
+                panic!("#{}: want {:?}, got {:?}", i, wresult, result);
             }
         }
     }
@@ -820,4 +1048,102 @@ mod test {
         let snap = new_snapshot(3, 3, nodes);
         storage.wl().apply_snapshot(snap).unwrap_err();
     }
+
+    #[test]
+    fn test_storage_reopen() {
+        let tmp = tempdir().unwrap();
+        {
+            let storage = RaftDiskStorage::new_with_db_path(tmp.path());
+            storage.initialize_with_conf_state((vec![1, 2], vec![3]));
+            let hs = HardState {
+                term: 5,
+                vote: 2,
+                commit: 2,
+                ..Default::default()
+            };
+            let ents = [new_entry(1, 4), new_entry(2, 5)];
+            storage.wl().append_with_hardstate(&ents, Some(hs)).unwrap();
+        }
+        let storage = RaftDiskStorage::new_with_db_path(tmp.path());
+        let state = storage.initial_state().unwrap();
+        let hs = &state.hard_state;
+        assert_eq!((hs.term, hs.vote, hs.commit), (5, 2, 2));
+        assert_eq!(state.conf_state.voters, vec![1, 2]);
+        assert_eq!(state.conf_state.learners, vec![3]);
+        assert_eq!(storage.last_index().unwrap(), 2);
+        // the members a new store starts with don't replace the ones it has
+        storage.initialize_with_conf_state((vec![1], vec![]));
+        assert_eq!(storage.rl().conf_state().voters, vec![1, 2]);
+
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(4, 6, vec![1, 2, 3]))
+            .unwrap();
+        drop(storage);
+        let storage = RaftDiskStorage::new_with_db_path(tmp.path());
+        assert_eq!(storage.first_index().unwrap(), 5);
+        assert_eq!(storage.term(4).unwrap(), 6);
+        let state = storage.initial_state().unwrap();
+        assert_eq!((state.hard_state.term, state.hard_state.commit), (6, 4));
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_storage_entry_checksum() {
+        let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        let storage = temp_store_with_entries(&ents);
+        assert!(storage.rl().scrub().unwrap().is_empty());
+
+        // damage entry 4 without updating its checksum
+        {
+            let core = storage.wl();
+            let mut er = EntryRef::from_entry(new_entry(4, 4));
+            er.data = ::bytes::Bytes::from_static(b"bad");
+            let mut wtxn = core.env.write_txn().unwrap();
+            core.entries.put(&mut wtxn, &4, &er).unwrap();
+            wtxn.commit().unwrap();
+        }
+        assert_eq!(storage.rl().scrub().unwrap(), vec![4]);
+
+        let res = storage.entries(3, 6, None, GetEntriesContext::empty(false));
+        assert!(res.is_err());
+        assert!(storage.term(4).is_err());
+        assert_eq!(storage.term(5).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_storage_learner_conf_state() {
+        let tmp = tempdir().unwrap();
+        let storage =
+            RaftDiskStorage::new_learner_with_db_path(tmp.as_ref(), vec![1, 2, 3], vec![4]);
+        assert!(storage.rl().is_learner(4));
+        assert!(!storage.rl().is_learner(1));
+        let cs = storage.initial_state().unwrap().conf_state;
+        assert_eq!(cs.voters, vec![1, 2, 3]);
+        assert_eq!(cs.learners, vec![4]);
+
+        // a node can't be both a voter and a learner
+        let tmp = tempdir().unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            RaftDiskStorage::new_learner_with_db_path(tmp.as_ref(), vec![1, 2], vec![2])
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_learner_promotion_and_stale_reads() {
+        let mut tests = vec![(0, 10, 5, false), (5, 10, 5, true), (10, 10, 0, true)];
+        for (i, (matched, last, lag, want)) in tests.drain(..).enumerate() {
+            if learner_caught_up(matched, last, lag) != want {
+                panic!("#{}: caught up want {}", i, want);
+            }
+        }
+
+        let mut tests = vec![(10, 10, 0, true), (10, 4, 5, false), (10, 5, 5, true)];
+        for (i, (committed, applied, lag, want)) in tests.drain(..).enumerate() {
+            if within_stale_bound(committed, applied, lag) != want {
+                panic!("#{}: stale bound want {}", i, want);
+            }
+        }
+    }
 }
@@ -0,0 +1,958 @@
+//
+// Redis protocol (RESP2) listener, see --resp-listen
+//
+// Lets redis-cli and redis client libraries use fekv: GET, SET (with EX/PX
+// and NX/XX), DEL, EXISTS, INCR, MGET, MSET, SCAN and KEYS, plus the
+// connection commands clients send (PING, ECHO, AUTH, SELECT 0, COMMAND,
+// QUIT). Keys address namespaces as over http, "{namespace}/{key}" when the
+// namespace exists, and are authorized against the same acls. Writes go
+// through the batcher and raft like fekv_handler's, SET NX/XX is a
+// Command::Txn comparing the key, and DEL and MSET are a single Txn each so
+// they're applied together, or not at all if any key is rejected (e.g. MSET
+// going over a namespace's quota).
+//
+// Expiry is lazy (see ValueMeta::expired), an expired key reads as missing
+// but stays in the store until it's overwritten or deleted. SCAN and KEYS
+// cover the namespace their pattern starts with, the default one otherwise.
+// SCAN's cursor is an offset into the sorted keys, so keys deleted during a
+// scan can make it skip others.
+//
+// With auth on, connections AUTH with a token ("AUTH token") or a user's
+// name and password ("AUTH name password"), or are authenticated by their
+// TLS client certificate when they connect.
+//
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use slog::{debug, error, info, o, warn, Logger};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter,
+};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::{ClientIdentity, Principal};
+use crate::handlers::{authorize_principal, now_ms, ServerState};
+use fekv::acl::Access;
+use fekv::checksum;
+use fekv::command::{Command, CommandError, CommandResult, Compare, Expect};
+use fekv::kvstore::{prefix_end, unexpired, KVStorage, ValueMeta};
+use fekv::namespace::{self, Rejection};
+use fekv::tls::{self, TlsReloader};
+
+// room in a request for everything besides values, which are limited by
+// --max-value-size as over http
+const MAX_REQUEST_OVERHEAD: usize = 64 * 1024;
+
+// longest inline command or array/bulk string header
+const MAX_LINE: usize = 64 * 1024;
+
+const MAX_ARGS: usize = 1024 * 1024;
+
+// keys SCAN looks at when there's no COUNT, as redis
+const SCAN_COUNT: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => {
+                buf.push(b'+');
+                buf.extend_from_slice(status.as_bytes());
+            }
+            Reply::Error(err) => {
+                // errors are a single line
+                buf.push(b'-');
+                buf.extend(err.bytes().map(|b| match b {
+                    b'\r' | b'\n' => b' ',
+                    b => b,
+                }));
+            }
+            Reply::Int(n) => buf.extend_from_slice(format!(":{}", n).as_bytes()),
+            Reply::Bulk(value) => {
+                buf.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                buf.extend_from_slice(value);
+            }
+            Reply::Nil => buf.extend_from_slice(b"$-1"),
+            Reply::Array(items) => {
+                buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(buf);
+                }
+                return;
+            }
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+// a command's reply, or the error reply it failed with
+type Outcome = std::result::Result<Reply, Reply>;
+
+fn err(msg: &str) -> Reply {
+    Reply::Error(format!("ERR {}", msg))
+}
+
+fn syntax_error() -> Reply {
+    err("syntax error")
+}
+
+fn not_integer() -> Reply {
+    err("value is not an integer or out of range")
+}
+
+// who a connection is authenticated as, when auth is enabled
+struct Session {
+    principal: Option<Principal>,
+    required: bool,
+}
+
+pub async fn listen<S: KVStorage + Send + Sync + 'static>(
+    addr: SocketAddr,
+    tls: Option<TlsReloader>,
+    state: Arc<ServerState<S>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let tcp = TcpListener::bind(addr).await?;
+    let logger = state.logger.clone();
+    let scheme = match tls {
+        Some(_) => "rediss",
+        None => "redis",
+    };
+    info!(logger, "listening"; "addr" => format!("{}://{}", scheme, addr), "listener" => "Resp");
+    loop {
+        let (stream, client) = tokio::select! {
+            res = tcp.accept() => match res {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(logger, "accept failed"; "error" => %err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let log = logger.new(o!("client" => client.to_string()));
+        let (tls, state, shutdown) = (tls.clone(), state.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            let res = match tls {
+                Some(tls) => match TlsAcceptor::from(tls.server_config()).accept(stream).await {
+                    Ok(stream) => {
+                        let certs = stream.get_ref().1.peer_certificates();
+                        let identity = tls::client_identity(certs).map(ClientIdentity);
+                        serve(stream, identity, state, shutdown, &log).await
+                    }
+                    Err(err) => {
+                        debug!(log, "tls handshake failed"; "error" => %err);
+                        return;
+                    }
+                },
+                None => serve(stream, None, state, shutdown, &log).await,
+            };
+            if let Err(err) = res {
+                debug!(log, "redis connection failed"; "error" => %err);
+            }
+        });
+    }
+}
+
+// runs a connection's commands in order until it hangs up or the listener is
+// stopped, which waits for the command in flight
+async fn serve<T: AsyncRead + AsyncWrite + Unpin, S: KVStorage + Send + Sync + 'static>(
+    stream: T,
+    identity: Option<ClientIdentity>,
+    state: Arc<ServerState<S>>,
+    mut shutdown: watch::Receiver<bool>,
+    log: &Logger,
+) -> Result<()> {
+    let mut conn = BufReader::new(BufWriter::new(stream));
+    let mut session = Session {
+        principal: None,
+        required: state.auth.enabled(),
+    };
+    if session.required && identity.is_some() {
+        let res = state
+            .auth
+            .authenticate_with(None, identity.as_ref(), &state.store);
+        session.principal = res.await.unwrap_or(None);
+    }
+    let limit = state.max_value_size + MAX_REQUEST_OVERHEAD;
+    loop {
+        let args = tokio::select! {
+            args = read_command(&mut conn, limit) => args,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let args = match args {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                // as redis, report protocol errors then hang up
+                send(&mut conn, &err(&e.to_string())).await?;
+                return conn.flush().await;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        if name == "QUIT" {
+            send(&mut conn, &Reply::Status("OK")).await?;
+            return conn.flush().await;
+        }
+        let reply = execute(&name, &args[1..], &mut session, &state, log).await;
+        send(&mut conn, &reply.unwrap_or_else(|e| e)).await?;
+        // replies to pipelined commands go out together
+        if conn.buffer().is_empty() {
+            conn.flush().await?;
+        }
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(conn: &mut W, reply: &Reply) -> Result<()> {
+    let mut buf = Vec::new();
+    reply.encode(&mut buf);
+    conn.write_all(&buf).await
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Protocol error: {}", msg))
+}
+
+// a line without its line ending, None at eof
+async fn read_line<R: AsyncBufRead + Unpin>(conn: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *conn)
+        .take(MAX_LINE as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.strip_suffix(b"\n") {
+        Some(line) => Ok(Some(line.strip_suffix(b"\r").unwrap_or(line).to_vec())),
+        None => Err(protocol_error("line too long or unterminated")),
+    }
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+// the next command's arguments, None at eof. Clients send arrays of bulk
+// strings, anything else is an inline command split on whitespace, as typed
+// into telnet. limit is the most bytes of arguments a command can have
+async fn read_command<R: AsyncBufRead + Unpin>(
+    conn: &mut R,
+    limit: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(conn).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => parse_len(count, MAX_ARGS)?,
+        None => {
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec());
+            return Ok(Some(args.collect()));
+        }
+    };
+    let mut args = Vec::with_capacity(count.min(1024));
+    let mut total = 0;
+    for _ in 0..count {
+        let header = read_line(conn)
+            .await?
+            .ok_or_else(|| protocol_error("unexpected end of command"))?;
+        let len = match header.strip_prefix(b"$") {
+            Some(len) => parse_len(len, limit)?,
+            None => return Err(protocol_error("expected '$'")),
+        };
+        total += len;
+        if total > limit {
+            return Err(protocol_error("command too large"));
+        }
+        let mut arg = vec![0; len + 2];
+        conn.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn execute<S: KVStorage + Send + Sync + 'static>(
+    name: &str,
+    args: &[Vec<u8>],
+    session: &mut Session,
+    state: &ServerState<S>,
+    log: &Logger,
+) -> Outcome {
+    if name == "AUTH" {
+        return auth(args, session, state).await;
+    }
+    if session.required && session.principal.is_none() {
+        return Err(Reply::Error(String::from(
+            "NOAUTH Authentication required.",
+        )));
+    }
+    let cmd = Cmd {
+        session: session,
+        state: state,
+        log: log,
+    };
+    match (name, args.len()) {
+        ("PING", 0) => Ok(Reply::Status("PONG")),
+        ("PING", 1) | ("ECHO", 1) => Ok(Reply::Bulk(args[0].clone())),
+        ("SELECT", 1) => match args[0].as_slice() {
+            b"0" => Ok(Reply::Status("OK")),
+            _ => Err(err("DB index is out of range")),
+        },
+        // redis-cli asks for command docs when it starts
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("GET", 1) => cmd.get(&args[0]).await,
+        ("SET", n) if n >= 2 => cmd.set(args).await,
+        ("DEL", n) if n >= 1 => cmd.del(args).await,
+        ("EXISTS", n) if n >= 1 => cmd.exists(args).await,
+        ("INCR", 1) => cmd.incr(&args[0]).await,
+        ("MGET", n) if n >= 1 => cmd.mget(args).await,
+        ("MSET", n) if n >= 2 && n % 2 == 0 => cmd.mset(args).await,
+        ("SCAN", n) if n >= 1 => cmd.scan(args).await,
+        ("KEYS", 1) => cmd.keys(&args[0]).await,
+        (
+            "PING" | "ECHO" | "SELECT" | "GET" | "SET" | "DEL" | "EXISTS" | "INCR" | "MGET"
+            | "MSET" | "SCAN" | "KEYS",
+            _,
+        ) => Err(err(&format!(
+            "wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))),
+        _ => Err(err(&format!("unknown command '{}'", name))),
+    }
+}
+
+// AUTH token or AUTH name password, the credentials http clients would send
+// as a bearer token or basic auth. A failed AUTH leaves the session as it was
+async fn auth<S: KVStorage + Send + Sync + 'static>(
+    args: &[Vec<u8>],
+    session: &mut Session,
+    state: &ServerState<S>,
+) -> Outcome {
+    let authorization = match args {
+        [token] => format!("Bearer {}", String::from_utf8_lossy(token)),
+        [name, password] => {
+            let creds = [name.as_slice(), b":", password.as_slice()].concat();
+            format!("Basic {}", STANDARD.encode(creds))
+        }
+        _ => return Err(err("wrong number of arguments for 'auth' command")),
+    };
+    if !session.required {
+        return Err(err("AUTH called without auth configured"));
+    }
+    let res = state
+        .auth
+        .authenticate_with(Some(&authorization), None, &state.store);
+    match res.await {
+        Ok(Some(principal)) => {
+            session.principal = Some(principal);
+            Ok(Reply::Status("OK"))
+        }
+        _ => Err(Reply::Error(String::from(
+            "WRONGPASS invalid username-password pair or user is disabled.",
+        ))),
+    }
+}
+
+// what key commands need, for one command
+struct Cmd<'a, S: KVStorage> {
+    session: &'a Session,
+    state: &'a ServerState<S>,
+    log: &'a Logger,
+}
+
+impl<'a, S: KVStorage + Send + Sync + 'static> Cmd<'a, S> {
+    // the namespace and key a redis key addresses, a namespace's name and a
+    // / only start a key in it if the namespace exists, as split_namespace
+    // does for /fekv paths
+    async fn split_key(&self, key: &[u8]) -> std::result::Result<(String, Vec<u8>), Reply> {
+        if let Some((name, rest)) = namespace::split_key(key) {
+            match self.state.store.namespace(name.to_string()).await {
+                Ok(Some(_)) => return Ok((name.to_string(), rest.to_vec())),
+                Ok(None) => {}
+                Err(e) => return Err(self.read_failed(e)),
+            }
+        }
+        Ok((namespace::DEFAULT.to_string(), key.to_vec()))
+    }
+
+    // a key argument split into its namespace, size checked and authorized
+    async fn resolve(
+        &self,
+        arg: &[u8],
+        access: Access,
+    ) -> std::result::Result<(String, Vec<u8>), Reply> {
+        let (ns, key) = self.split_key(arg).await?;
+        if key.is_empty() || key.len() > self.state.max_key_size {
+            return Err(err("invalid key size"));
+        }
+        self.authorize(&namespace::qualified_key(&ns, &key), access)
+            .await?;
+        Ok((ns, key))
+    }
+
+    async fn authorize(&self, qualified: &[u8], access: Access) -> std::result::Result<(), Reply> {
+        let principal = self.session.principal.as_ref();
+        if !authorize_principal(principal, self.state, qualified, access, self.log).await {
+            return Err(Reply::Error(String::from(
+                "NOPERM this user has no permissions to access one of the keys used as arguments",
+            )));
+        }
+        match access != Access::Read || self.state.readable() {
+            true => Ok(()),
+            false => Err(Reply::Error(String::from(
+                "TRYAGAIN store too stale to read from",
+            ))),
+        }
+    }
+
+    fn rejected(&self, rejection: Rejection) -> Reply {
+        debug!(self.log, "proposal rejected"; "reason" => %rejection);
+        match rejection {
+            Rejection::QuotaExceeded(_) => Reply::Error(format!("OOM {}", rejection)),
+            rejection => err(&rejection.to_string()),
+        }
+    }
+
+    fn refused(&self, e: CommandError) -> Reply {
+        debug!(self.log, "proposal refused"; "reason" => %e);
+        match e {
+            CommandError::NotInteger => not_integer(),
+            CommandError::Overflow => err("increment or decrement would overflow"),
+            e => err(&e.to_string()),
+        }
+    }
+
+    fn read_failed(&self, e: Error) -> Reply {
+        if checksum::is_corruption(&e) {
+            error!(self.log, "stored value is corrupt"; "error" => %e);
+            return err("stored value is corrupt");
+        }
+        match namespace::rejection(&e) {
+            Some(rejection) => self.rejected(rejection),
+            None => {
+                warn!(self.log, "read failed"; "error" => %e);
+                err(&e.to_string())
+            }
+        }
+    }
+
+    // a write's result unless it failed or was rejected, a Txn fails as a
+    // whole
+    fn applied(&self, res: CommandResult) -> std::result::Result<CommandResult, Reply> {
+        match res {
+            CommandResult::Failed(e) => {
+                warn!(self.log, "proposal failed"; "error" => &e);
+                Err(err(&e))
+            }
+            CommandResult::Rejected(rejection) => Err(self.rejected(rejection)),
+            CommandResult::Refused(e) => Err(self.refused(e)),
+            res => Ok(res),
+        }
+    }
+
+    async fn propose(&self, cmd: Command) -> std::result::Result<CommandResult, Reply> {
+        self.applied(self.state.batcher.propose(cmd).await)
+    }
+
+    // values of keys, None for missing and expired ones
+    async fn values(&self, args: &[Vec<u8>]) -> std::result::Result<Vec<Option<Vec<u8>>>, Reply> {
+        let mut keys = Vec::with_capacity(args.len());
+        for arg in args {
+            keys.push(self.resolve(arg, Access::Read).await?);
+        }
+        let now = now_ms();
+        let found = self
+            .state
+            .store
+            .read(move |st| {
+                let mut values = Vec::with_capacity(keys.len());
+                for (ns, key) in &keys {
+                    let value = match st.get(ns, key) {
+                        Ok(value) => Some(value),
+                        Err(e) if checksum::is_corruption(&e) => return Err(e),
+                        Err(e) if namespace::rejection(&e).is_some() => return Err(e),
+                        Err(_e) => None,
+                    };
+                    let meta = match value {
+                        Some(_) => st.get_meta(ns, key)?,
+                        None => None,
+                    };
+                    values.push(unexpired(value, meta, now).0);
+                }
+                Ok(values)
+            })
+            .await;
+        found.map_err(|e| self.read_failed(e))
+    }
+
+    async fn get(&self, key: &[u8]) -> Outcome {
+        match self.values(&[key.to_vec()]).await?.pop().flatten() {
+            Some(value) => Ok(Reply::Bulk(value)),
+            None => Ok(Reply::Nil),
+        }
+    }
+
+    async fn mget(&self, args: &[Vec<u8>]) -> Outcome {
+        let values = self
+            .values(args)
+            .await?
+            .into_iter()
+            .map(|value| match value {
+                Some(value) => Reply::Bulk(value),
+                None => Reply::Nil,
+            });
+        Ok(Reply::Array(values.collect()))
+    }
+
+    // keys named more than once are counted each time, as redis
+    async fn exists(&self, args: &[Vec<u8>]) -> Outcome {
+        let values = self.values(args).await?;
+        Ok(Reply::Int(
+            values.iter().filter(|v| v.is_some()).count() as i64
+        ))
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    async fn set(&self, args: &[Vec<u8>]) -> Outcome {
+        let now = now_ms();
+        let mut expires = None;
+        let mut expect = None;
+        let mut opts = args[2..].iter();
+        while let Some(opt) = opts.next() {
+            let opt = opt.to_ascii_uppercase();
+            match opt.as_slice() {
+                b"NX" if expect.is_none() => expect = Some(Expect::Missing),
+                b"XX" if expect.is_none() => expect = Some(Expect::Exists),
+                b"EX" | b"PX" if expires.is_none() => {
+                    let n = opts
+                        .next()
+                        .and_then(|n| parse_int(n))
+                        .ok_or_else(not_integer)?;
+                    let ms = match opt.as_slice() {
+                        b"EX" => n.checked_mul(1000),
+                        _ => Some(n),
+                    };
+                    let at = ms
+                        .filter(|ms| *ms > 0)
+                        .and_then(|ms| now.checked_add(ms as u64))
+                        .ok_or_else(|| err("invalid expire time in 'set' command"))?;
+                    expires = Some(at);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        let (ns, key) = self.resolve(&args[0], Access::Write).await?;
+        let set = self.set_command(ns.clone(), key.clone(), args[1].clone(), expires)?;
+        let cmd = match expect {
+            Some(expect) => Command::Txn {
+                compares: vec![Compare {
+                    ns: ns,
+                    key: key,
+                    expect: expect,
+                }],
+                success: vec![set],
+                failure: Vec::new(),
+                now: now,
+            },
+            None => set,
+        };
+        match self.propose(cmd).await? {
+            CommandResult::Txn {
+                succeeded: false, ..
+            } => Ok(Reply::Nil),
+            _ => Ok(Reply::Status("OK")),
+        }
+    }
+
+    fn set_command(
+        &self,
+        ns: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    ) -> std::result::Result<Command, Reply> {
+        if value.len() > self.state.max_value_size {
+            return Err(err("value too large"));
+        }
+        let now = now_ms();
+        let meta = ValueMeta {
+            created: now,
+            modified: now,
+            expires: expires,
+            ..Default::default()
+        };
+        Ok(Command::Set {
+            ns: ns,
+            key: key,
+            value: value,
+            meta: Some(meta),
+        })
+    }
+
+    async fn mset(&self, args: &[Vec<u8>]) -> Outcome {
+        let mut sets = Vec::with_capacity(args.len() / 2);
+        for pair in args.chunks(2) {
+            let (ns, key) = self.resolve(&pair[0], Access::Write).await?;
+            sets.push(self.set_command(ns, key, pair[1].clone(), None)?);
+        }
+        self.propose(txn(sets)).await?;
+        Ok(Reply::Status("OK"))
+    }
+
+    // expired keys which are still stored are counted as deleted
+    async fn del(&self, args: &[Vec<u8>]) -> Outcome {
+        let mut deletes = Vec::with_capacity(args.len());
+        for arg in args {
+            let (ns, key) = self.resolve(arg, Access::Write).await?;
+            deletes.push(Command::Delete { ns: ns, key: key });
+        }
+        let deleted = match self.propose(txn(deletes)).await? {
+            CommandResult::Txn { results, .. } => results
+                .iter()
+                .filter(|res| **res == CommandResult::Done(true))
+                .count(),
+            _ => 0,
+        };
+        Ok(Reply::Int(deleted as i64))
+    }
+
+    async fn incr(&self, key: &[u8]) -> Outcome {
+        let (ns, key) = self.resolve(key, Access::Write).await?;
+        let cmd = Command::Incr {
+            ns: ns,
+            key: key,
+            by: 1,
+            modified: now_ms(),
+        };
+        match self.propose(cmd).await? {
+            CommandResult::Value(n) => Ok(Reply::Int(n)),
+            res => Err(err(&format!("unexpected result {:?}", res))),
+        }
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    async fn scan(&self, args: &[Vec<u8>]) -> Outcome {
+        let cursor = parse_int(&args[0])
+            .and_then(|cursor| usize::try_from(cursor).ok())
+            .ok_or_else(|| err("invalid cursor"))?;
+        let mut pattern = b"*".to_vec();
+        let mut count = SCAN_COUNT;
+        let mut opts = args[1..].iter();
+        while let Some(opt) = opts.next() {
+            let value = opts.next().ok_or_else(syntax_error)?;
+            match opt.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = value.clone(),
+                b"COUNT" => {
+                    count = parse_int(value)
+                        .and_then(|count| usize::try_from(count).ok())
+                        .filter(|count| *count > 0)
+                        .ok_or_else(syntax_error)?
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        let (next, keys) = self.matching(pattern, cursor, count).await?;
+        Ok(Reply::Array(vec![
+            Reply::Bulk(next.to_string().into_bytes()),
+            Reply::Array(keys.into_iter().map(Reply::Bulk).collect()),
+        ]))
+    }
+
+    async fn keys(&self, pattern: &[u8]) -> Outcome {
+        let (_next, keys) = self.matching(pattern.to_vec(), 0, usize::MAX).await?;
+        Ok(Reply::Array(keys.into_iter().map(Reply::Bulk).collect()))
+    }
+
+    // unexpired keys matching pattern among count keys from the cursor'th
+    // one, and the cursor to carry on from, 0 once there are no more. Keys
+    // come back qualified with their namespace. Only the range the pattern's
+    // literal prefix allows is looked at, and needs read access
+    async fn matching(
+        &self,
+        pattern: Vec<u8>,
+        cursor: usize,
+        count: usize,
+    ) -> std::result::Result<(usize, Vec<Vec<u8>>), Reply> {
+        let (ns, pattern) = self.split_key(&pattern).await?;
+        let prefix = literal_prefix(&pattern).to_vec();
+        self.authorize(&namespace::qualified_key(&ns, &prefix), Access::Read)
+            .await?;
+        let now = now_ms();
+        let found = self
+            .state
+            .store
+            .read(move |st| {
+                let end = prefix_end(&prefix);
+                let limit = cursor.saturating_add(count);
+                let keys = st.range_keys(&ns, &prefix, end.as_deref(), limit)?;
+                let next = match keys.len() == limit && limit != usize::MAX {
+                    true => limit,
+                    false => 0,
+                };
+                let mut matched = Vec::new();
+                for key in keys.into_iter().skip(cursor) {
+                    if !glob_match(&pattern, &key) {
+                        continue;
+                    }
+                    let expired = st.get_meta(&ns, &key)?.is_some_and(|m| m.expired(now));
+                    if !expired {
+                        matched.push(namespace::qualified_key(&ns, &key));
+                    }
+                }
+                Ok((next, matched))
+            })
+            .await;
+        found.map_err(|e| self.read_failed(e))
+    }
+}
+
+// commands applied together, there's nothing to compare
+fn txn(cmds: Vec<Command>) -> Command {
+    Command::Txn {
+        compares: Vec::new(),
+        success: cmds,
+        failure: Vec::new(),
+        now: now_ms(),
+    }
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// the part of a pattern before anything special, which every match starts
+// with
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// redis glob patterns: * and ? wildcards, [abc], [a-z] and [^a] classes and
+// \ to escape. On a mismatch only the last * seen is retried with one more
+// byte of the key, the ones before it can't do any better, so it takes at
+// most pattern * key steps
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // the pattern just after the last * and how much of the key it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        // how much of the pattern matched key[k]
+        let matched = match &pattern[p..] {
+            [b'*', ..] => {
+                p += 1;
+                star = Some((p, k));
+                continue;
+            }
+            [b'?', ..] => Some(1),
+            [b'[', rest @ ..] => match class_match(rest, key[k]) {
+                (true, after) => Some(pattern.len() - p - after.len()),
+                (false, _) => None,
+            },
+            [b'\\', c, ..] if *c == key[k] => Some(2),
+            [b'\\', _, ..] => None,
+            [c, ..] if *c == key[k] => Some(1),
+            _ => None,
+        };
+        match (matched, star) {
+            (Some(n), _) => {
+                p += n;
+                k += 1;
+            }
+            (None, Some((after_star, taken))) => {
+                p = after_star;
+                k = taken + 1;
+                star = Some((after_star, k));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+// whether c is in the class pattern starts with (just after its '['), and
+// the pattern after the class. An unclosed class runs to the end
+fn class_match(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut p) = match pattern {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match p {
+            [] => break,
+            [b']', rest @ ..] => {
+                p = rest;
+                break;
+            }
+            [b'\\', x, rest @ ..] => {
+                matched |= *x == c;
+                p = rest;
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                matched |= (*lo.min(hi)..=*lo.max(hi)).contains(&c);
+                p = rest;
+            }
+            [x, rest @ ..] => {
+                matched |= *x == c;
+                p = rest;
+            }
+        }
+    }
+    (matched != negate, p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::handlers::test_state;
+    use fekv::namespace::Quota;
+    use std::collections::HashSet;
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    // an MSET with a key over its namespace's quota sets none of them
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mset_over_quota() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = test_state(Auth::new(Vec::new(), HashSet::new()), tmp.path());
+        let log = state.logger.clone();
+        let put = Command::PutNamespace {
+            name: String::from("team"),
+            quota: Quota {
+                max_keys: Some(2),
+                max_bytes: None,
+            },
+        };
+        assert_eq!(state.batcher.propose(put).await, CommandResult::Done(true));
+        let mut session = Session {
+            principal: None,
+            required: false,
+        };
+
+        let mset = args(&["team/a", "1", "team/b", "1"]);
+        let res = execute("MSET", &mset, &mut session, &state, &log).await;
+        assert_eq!(res, Ok(Reply::Status("OK")));
+        let mset = args(&["team/a", "2", "team/c", "2"]);
+        let res = execute("MSET", &mset, &mut session, &state, &log).await;
+        assert!(matches!(res, Err(Reply::Error(e)) if e.starts_with("OOM")));
+
+        let mget = args(&["team/a", "team/b", "team/c"]);
+        let res = execute("MGET", &mget, &mut session, &state, &log).await;
+        let values = vec![
+            Reply::Bulk(b"1".to_vec()),
+            Reply::Bulk(b"1".to_vec()),
+            Reply::Nil,
+        ];
+        assert_eq!(res, Ok(Reply::Array(values)));
+    }
+
+    #[tokio::test]
+    async fn test_read_command() {
+        let mut input: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\nPING  hi\n\r\n";
+        let cmd = read_command(&mut input, 16).await.unwrap().unwrap();
+        assert_eq!(
+            cmd,
+            vec![b"SET".to_vec(), b"k".to_vec(), b"a\r\nb".to_vec()]
+        );
+        // inline commands
+        let cmd = read_command(&mut input, 16).await.unwrap().unwrap();
+        assert_eq!(cmd, vec![b"PING".to_vec(), b"hi".to_vec()]);
+        assert_eq!(read_command(&mut input, 16).await.unwrap(), Some(vec![]));
+        assert_eq!(read_command(&mut input, 16).await.unwrap(), None);
+
+        for bad in [
+            &b"*1\r\n$20\r\n"[..],
+            b"*2\r\n$1\r\na\r\n",
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$1\r\nab\r\n",
+            b"*x\r\n",
+        ] {
+            let mut input = bad;
+            assert!(read_command(&mut input, 16).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        let reply = Reply::Array(vec![
+            Reply::Status("OK"),
+            Reply::Int(-2),
+            Reply::Bulk(b"v".to_vec()),
+            Reply::Nil,
+            err("a\r\nb"),
+        ]);
+        reply.encode(&mut buf);
+        assert_eq!(buf, b"*5\r\n+OK\r\n:-2\r\n$1\r\nv\r\n$-1\r\n-ERR a  b\r\n");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(!glob_match(b"user:*", b"users"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"heeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-c]llo", b"hdllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"**a*", b"xxa"));
+        assert!(glob_match(b"a\\", b"a\\"));
+        assert!(!glob_match(b"a*b", b"ab_"));
+        assert!(glob_match(b"*[0-9]x", b"a1x2x"));
+        // backtracking every * would take forever on these
+        let key = vec![b'a'; 10_000];
+        assert!(!glob_match(
+            &[b"*a".repeat(20), b"b".to_vec()].concat(),
+            &key
+        ));
+        assert!(glob_match(&b"*a".repeat(20), &key));
+
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"key"), b"key");
+        assert_eq!(literal_prefix(b"a\\*"), b"a");
+    }
+}
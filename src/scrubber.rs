@@ -0,0 +1,158 @@
+//
+// Background scrubber
+//
+// Periodically walks the kv store and the raft log checking every value and
+// entry against its checksum, so damage to rarely read data is found before a
+// client or a lagging follower trips over it. The report from the last pass
+// is served by the server's /admin/scrub endpoint.
+//
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use slog::{error, info, Logger};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::kvstore::KVStorage;
+use crate::metrics;
+use crate::raftstore::RaftDiskStorage;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScrubReport {
+    // completed passes since startup
+    pub runs: u64,
+    // unix time in seconds the last pass finished
+    pub last_run: Option<u64>,
+    pub duration_ms: u64,
+    pub corrupt_keys: Vec<String>,
+    pub corrupt_entries: Vec<u64>,
+    // set if the last pass couldn't read the store or raft log
+    pub error: Option<String>,
+}
+
+pub struct Scrubber<S: KVStorage> {
+    store: Arc<RwLock<S>>,
+    raft: RaftDiskStorage,
+    report: std::sync::Mutex<ScrubReport>,
+    // only one pass at a time, background or on demand
+    running: Mutex<()>,
+    logger: Logger,
+}
+
+impl<S: KVStorage + Send + Sync + 'static> Scrubber<S> {
+    pub fn new(store: Arc<RwLock<S>>, raft: RaftDiskStorage, logger: &Logger) -> Scrubber<S> {
+        Scrubber {
+            store: store,
+            raft: raft,
+            report: std::sync::Mutex::new(ScrubReport::default()),
+            running: Mutex::new(()),
+            logger: logger.clone(),
+        }
+    }
+
+    // scrub every interval on the current tokio runtime, a zero interval
+    // leaves scrubbing to on demand requests
+    pub fn spawn(self: &Arc<Self>, interval: Duration) {
+        if interval.is_zero() {
+            return;
+        }
+        let scrubber = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // the first tick completes immediately, don't scrub at startup
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                scrubber.scrub().await;
+            }
+        });
+    }
+
+    pub fn report(&self) -> ScrubReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    // run a full pass now and return its report
+    pub async fn scrub(&self) -> ScrubReport {
+        let _running = self.running.lock().await;
+        let start = Instant::now();
+        let store = self.store.clone();
+        let raft = self.raft.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let keys = store
+                .blocking_read()
+                .scrub()
+                .map_err(|err| err.to_string())?;
+            let entries = raft.rl().scrub().map_err(|err| err.to_string())?;
+            Ok::<_, String>((keys, entries))
+        })
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()));
+
+        let mut report = self.report();
+        report.runs += 1;
+        report.last_run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+        report.duration_ms = start.elapsed().as_millis() as u64;
+        match res {
+            Ok((keys, entries)) => {
+                if keys.is_empty() && entries.is_empty() {
+                    info!(self.logger, "scrub found no corruption"; "duration_ms" => report.duration_ms);
+                } else {
+                    error!(self.logger, "scrub found corruption";
+                        "keys" => ?keys, "entries" => ?entries);
+                }
+                metrics::SCRUB_CORRUPT_ITEMS.set((keys.len() + entries.len()) as i64);
+                report.corrupt_keys = keys;
+                report.corrupt_entries = entries;
+                report.error = None;
+            }
+            Err(err) => {
+                error!(self.logger, "scrub failed"; "error" => &err);
+                report.error = Some(err);
+            }
+        }
+        metrics::SCRUB_RUNS.inc();
+        *self.report.lock().unwrap() = report.clone();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvstore::memstore::MemKVStore;
+    use raft::prelude::Entry;
+    use slog::{o, Discard};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_scrub_clean() {
+        let tmp = tempdir().unwrap();
+        let raft = RaftDiskStorage::new_with_db_path(tmp.as_ref());
+        raft.wl().clear();
+        let e = Entry {
+            index: 1,
+            term: 1,
+            ..Default::default()
+        };
+        raft.wl().append(&[e]).unwrap();
+
+        let mut store = MemKVStore::new();
+        store.set("", b"foo", b"bar".to_vec()).unwrap();
+        let logger = Logger::root(Discard, o!());
+        let scrubber = Scrubber::new(Arc::new(RwLock::new(store)), raft, &logger);
+        assert_eq!(scrubber.report().runs, 0);
+
+        let report = scrubber.scrub().await;
+        assert_eq!(report.runs, 1);
+        assert!(report.last_run.is_some());
+        assert!(report.corrupt_keys.is_empty());
+        assert!(report.corrupt_entries.is_empty());
+        assert_eq!(report.error, None);
+        assert_eq!(scrubber.report(), report);
+    }
+}
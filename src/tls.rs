@@ -0,0 +1,269 @@
+//
+// TLS for the server's listeners and the raft transport, using rustls
+//
+// Certificates, keys and the CA are PEM files. With a CA the client listener
+// asks for (but doesn't require) client certificates, for auth, while the peer
+// listener and the transport use them for mutual TLS between nodes. Without
+// one there's no peer listener config, nothing could verify the nodes.
+//
+// TlsReloader::reload re-reads the files, the server calls it on SIGHUP. New
+// connections pick up the reloaded config, established ones keep the one they
+// handshook with.
+//
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // verifies client certificates and, for the transport, other nodes
+    pub ca: Option<PathBuf>,
+}
+
+fn open(path: &PathBuf) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    Ok(BufReader::new(file))
+}
+
+fn tls_err(err: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidInput, err.to_string())
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &PathBuf) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut open(path)?)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("no private key in {}", path.display()),
+    ))
+}
+
+fn load_roots(path: &PathBuf) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(tls_err)?;
+    }
+    Ok(roots)
+}
+
+pub fn server_config(files: &TlsFiles, require_client_cert: bool) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &files.ca {
+        Some(ca) if require_client_cert => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+        Some(ca) => builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca)?).boxed(),
+        ),
+        None if require_client_cert => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "client certificates can't be required without a CA to verify them",
+            ))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(load_certs(&files.cert)?, load_key(&files.key)?)
+        .map_err(tls_err)
+}
+
+// the peer listener's config, requiring certificates signed by the CA
+fn peer_server_config(files: &TlsFiles) -> Result<Option<ServerConfig>> {
+    match files.ca {
+        Some(_) => server_config(files, true).map(Some),
+        None => Ok(None),
+    }
+}
+
+// the client listener's config offering only h2, which grpc clients insist on
+// being negotiated with ALPN
+pub fn grpc_server_config(files: &TlsFiles) -> Result<ServerConfig> {
+    let mut config = server_config(files, false)?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(config)
+}
+
+// for connecting to other nodes, presents our certificate as a client cert
+pub fn client_config(files: &TlsFiles) -> Result<ClientConfig> {
+    let roots = match &files.ca {
+        Some(ca) => load_roots(ca)?,
+        // no CA, expect peers to use our own (e.g. shared self signed) cert
+        None => load_roots(&files.cert)?,
+    };
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(load_certs(&files.cert)?, load_key(&files.key)?)
+        .map_err(tls_err)
+}
+
+// common name of the first (leaf) certificate a client presented
+pub fn client_identity(certs: Option<&[Certificate]>) -> Option<String> {
+    let leaf = certs?.first()?;
+    let (_rem, cert) = X509Certificate::from_der(&leaf.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(String::from)
+}
+
+#[derive(Clone)]
+pub struct TlsReloader {
+    files: TlsFiles,
+    client_listener: Arc<RwLock<Arc<ServerConfig>>>,
+    // None without a CA
+    peer_listener: Arc<RwLock<Option<Arc<ServerConfig>>>>,
+    grpc_listener: Arc<RwLock<Arc<ServerConfig>>>,
+    transport: Arc<RwLock<Arc<ClientConfig>>>,
+}
+
+impl TlsReloader {
+    pub fn new(files: TlsFiles) -> Result<TlsReloader> {
+        Ok(TlsReloader {
+            client_listener: Arc::new(RwLock::new(Arc::new(server_config(&files, false)?))),
+            peer_listener: Arc::new(RwLock::new(peer_server_config(&files)?.map(Arc::new))),
+            grpc_listener: Arc::new(RwLock::new(Arc::new(grpc_server_config(&files)?))),
+            transport: Arc::new(RwLock::new(Arc::new(client_config(&files)?))),
+            files: files,
+        })
+    }
+
+    // all the configs are loaded before any are swapped, so a bad file leaves
+    // the old certificates in use
+    pub fn reload(&self) -> Result<()> {
+        let client_listener = server_config(&self.files, false)?;
+        let peer_listener = peer_server_config(&self.files)?;
+        let grpc_listener = grpc_server_config(&self.files)?;
+        let transport = client_config(&self.files)?;
+        *self.client_listener.write().unwrap() = Arc::new(client_listener);
+        *self.peer_listener.write().unwrap() = peer_listener.map(Arc::new);
+        *self.grpc_listener.write().unwrap() = Arc::new(grpc_listener);
+        *self.transport.write().unwrap() = Arc::new(transport);
+        Ok(())
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.client_listener.read().unwrap().clone()
+    }
+
+    pub fn peer_server_config(&self) -> Option<Arc<ServerConfig>> {
+        self.peer_listener.read().unwrap().clone()
+    }
+
+    pub fn grpc_server_config(&self) -> Arc<ServerConfig> {
+        self.grpc_listener.read().unwrap().clone()
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.transport.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn self_signed(dir: &std::path::Path, name: &str) -> TlsFiles {
+        let mut params = rcgen::CertificateParams::new(vec![String::from("localhost")]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let files = TlsFiles {
+            cert: dir.join(format!("{}.pem", name)),
+            key: dir.join(format!("{}-key.pem", name)),
+            ca: Some(dir.join(format!("{}.pem", name))),
+        };
+        fs::write(&files.cert, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&files.key, cert.serialize_private_key_pem()).unwrap();
+        files
+    }
+
+    #[test]
+    fn test_load_and_reload() {
+        let tmp = tempdir().unwrap();
+        let files = self_signed(tmp.path(), "node-1");
+        let reloader = TlsReloader::new(files.clone()).unwrap();
+        let before = reloader.server_config();
+
+        // a reload swaps in new configs
+        self_signed(tmp.path(), "node-1");
+        reloader.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &reloader.server_config()));
+        assert_eq!(
+            reloader.grpc_server_config().alpn_protocols,
+            vec![b"h2".to_vec()]
+        );
+
+        // a bad reload keeps the old ones
+        let current = reloader.client_config();
+        fs::write(&files.key, "junk").unwrap();
+        assert!(reloader.reload().is_err());
+        assert!(Arc::ptr_eq(&current, &reloader.client_config()));
+
+        let missing = TlsFiles {
+            cert: tmp.path().join("missing.pem"),
+            key: files.key.clone(),
+            ca: None,
+        };
+        assert!(TlsReloader::new(missing).is_err());
+    }
+
+    #[test]
+    fn test_peer_needs_ca() {
+        let tmp = tempdir().unwrap();
+        let files = self_signed(tmp.path(), "node-1");
+        assert!(server_config(&files, true).is_ok());
+        assert!(TlsReloader::new(files.clone())
+            .unwrap()
+            .peer_server_config()
+            .is_some());
+
+        let files = TlsFiles { ca: None, ..files };
+        assert!(server_config(&files, true).is_err());
+        assert!(server_config(&files, false).is_ok());
+        assert!(TlsReloader::new(files)
+            .unwrap()
+            .peer_server_config()
+            .is_none());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let tmp = tempdir().unwrap();
+        let files = self_signed(tmp.path(), "node-2");
+        let certs = load_certs(&files.cert).unwrap();
+        assert_eq!(
+            client_identity(Some(&certs[..])),
+            Some(String::from("node-2"))
+        );
+        assert_eq!(client_identity(None), None);
+        assert_eq!(client_identity(Some(&[][..])), None);
+    }
+}
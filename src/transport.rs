@@ -0,0 +1,183 @@
+//
+// Raft message transport between fekv servers
+//
+// Messages are protobuf encoded with encode(...) and POSTed to the peer's
+// /raft endpoint, see handlers::raft_handler for the receiving side which
+// uses decode(...). Failed sends are dropped, raft retries on its own.
+//
+// With TLS peers are called over https presenting this node's certificate,
+// the client is rebuilt when the TlsReloader's config changes. Peers with
+// auth on (and no CA verified peer listener) need an admin's bearer token,
+// see with_token(...).
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hyper::client::HttpConnector;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use protobuf::{Message as PbMessage, ProtobufResult};
+use raft::prelude::Message;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::runtime::Handle;
+
+use crate::raftnode::Transport;
+use crate::tls::TlsReloader;
+
+type PeerClient = Client<HttpsConnector<HttpConnector>>;
+
+// raft's messages are rust-protobuf 2 types, see the protobuf dependency
+pub fn encode(msg: &Message) -> ProtobufResult<Vec<u8>> {
+    msg.write_to_bytes()
+}
+
+pub fn decode(body: &[u8]) -> ProtobufResult<Message> {
+    Message::parse_from_bytes(body)
+}
+
+pub struct HttpTransport {
+    // raft id -> host:port
+    peers: HashMap<u64, String>,
+    tls: Option<TlsReloader>,
+    // client and the tls config it was built with
+    client: Mutex<(Arc<ClientConfig>, PeerClient)>,
+    // sent as Authorization: Bearer
+    token: Option<String>,
+    runtime: Handle,
+}
+
+fn build_client(tls: ClientConfig) -> PeerClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+impl HttpTransport {
+    // must be called from within a tokio runtime, sends are spawned onto it
+    pub fn new(peers: HashMap<u64, String>) -> HttpTransport {
+        // only used for plain http, so no roots to trust
+        let tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        HttpTransport {
+            peers: peers,
+            tls: None,
+            client: Mutex::new((Arc::new(tls.clone()), build_client(tls))),
+            token: None,
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn new_with_tls(peers: HashMap<u64, String>, tls: TlsReloader) -> HttpTransport {
+        let cfg = tls.client_config();
+        HttpTransport {
+            peers: peers,
+            client: Mutex::new((cfg.clone(), build_client((*cfg).clone()))),
+            tls: Some(tls),
+            token: None,
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> HttpTransport {
+        self.token = token;
+        self
+    }
+
+    fn client(&self) -> PeerClient {
+        let mut client = self.client.lock().unwrap();
+        if let Some(tls) = &self.tls {
+            let cfg = tls.client_config();
+            if !Arc::ptr_eq(&cfg, &client.0) {
+                *client = (cfg.clone(), build_client((*cfg).clone()));
+            }
+        }
+        client.1.clone()
+    }
+}
+
+fn raft_request(
+    scheme: &str,
+    addr: &str,
+    token: Option<&str>,
+    body: Vec<u8>,
+) -> hyper::http::Result<Request<Body>> {
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .uri(format!("{}://{}/raft", scheme, addr));
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    builder.body(Body::from(body))
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, msg: Message) {
+        let addr = match self.peers.get(&msg.to) {
+            Some(addr) => addr.to_string(),
+            None => return,
+        };
+        let body = match encode(&msg) {
+            Ok(body) => body,
+            Err(_err) => return,
+        };
+        let scheme = match self.tls {
+            Some(_) => "https",
+            None => "http",
+        };
+        let req = raft_request(scheme, &addr, self.token.as_deref(), body);
+        let client = self.client();
+        self.runtime.spawn(async move {
+            if let Ok(req) = req {
+                let _ = client.request(req).await;
+            }
+        });
+    }
+
+    fn set_peer(&mut self, id: u64, addr: Option<String>) {
+        match addr {
+            Some(addr) => self.peers.insert(id, addr),
+            None => self.peers.remove(&id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raft::prelude::{Entry, MessageType};
+
+    #[test]
+    fn test_encode_decode() {
+        let entry = Entry {
+            index: 7,
+            data: b"data".to_vec().into(),
+            ..Default::default()
+        };
+        let mut msg = Message {
+            to: 2,
+            from: 1,
+            term: 3,
+            entries: vec![entry].into(),
+            ..Default::default()
+        };
+        msg.set_msg_type(MessageType::MsgAppend);
+        assert_eq!(decode(&encode(&msg).unwrap()).unwrap(), msg);
+        assert!(decode(b"\xff\xff").is_err());
+    }
+
+    #[test]
+    fn test_raft_request() {
+        let req = raft_request("https", "127.0.0.1:3001", Some("s3cret"), vec![]).unwrap();
+        assert_eq!(req.uri(), "https://127.0.0.1:3001/raft");
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer s3cret");
+        let req = raft_request("http", "127.0.0.1:3001", None, vec![]).unwrap();
+        assert!(req.headers().get(AUTHORIZATION).is_none());
+    }
+}